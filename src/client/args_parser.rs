@@ -8,6 +8,8 @@ pub async fn parse_args(args: Vec<String>, default_config: FileConfig) -> Result
     let mut config = ClientConfig {
         use_tls: default_config.client_use_tls,
         use_websocket: default_config.client_use_websocket,
+        use_quic: false,
+        quic_streams: false,
         graphs: false,
         raw_output: false,
         log: None,
@@ -19,6 +21,7 @@ pub async fn parse_args(args: Vec<String>, default_config: FileConfig) -> Result
         control_server: default_config.control_server,
         save_results: false,
         client_uuid: default_config.client_uuid,
+        secret_key: default_config.client_secret_key,
         git_hash: None,
     };
 
@@ -52,7 +55,17 @@ pub async fn parse_args(args: Vec<String>, default_config: FileConfig) -> Result
             "-ws" => {
                 config.use_websocket = true;
             }
+            "-quic" | "-quic-streams" => {
+                if !cfg!(feature = "quic") {
+                    return Err(anyhow::anyhow!("QUIC is not available: nettest was built without the quic feature"));
+                }
+                config.use_quic = true;
+                config.quic_streams |= args[i] == "-quic-streams";
+            }
             "-g" => {
+                if !cfg!(feature = "graphs") {
+                    return Err(anyhow::anyhow!("Graphs are not available: nettest was built without the graphs feature"));
+                }
                 config.graphs = true;
             }
             "-raw" => {
@@ -61,6 +74,13 @@ pub async fn parse_args(args: Vec<String>, default_config: FileConfig) -> Result
             "-save" => {
                 config.save_results = true;
             }
+            "-e" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(anyhow::anyhow!("-e requires a key"));
+                }
+                config.secret_key = Some(args[i].clone());
+            }
             "-git-hash" => {
                 i += 1;
                 if i < args.len() {
@@ -96,6 +116,10 @@ pub async fn parse_args(args: Vec<String>, default_config: FileConfig) -> Result
         i += 1;
     }
 
+    if config.use_quic && config.use_websocket {
+        return Err(anyhow::anyhow!("-quic cannot be combined with -ws"));
+    }
+
     if config.log.is_some() {
         logger::init_logger(config.log.unwrap()).unwrap();
     }
@@ -133,6 +157,8 @@ pub fn print_help() {
     println!("Usage: nettest -c 127.0.0.1 -ws -tls -t5");
     println!("-ws - use websocket");
     println!("-tls - use tls");
+    println!("-quic - use QUIC (UDP, always encrypted) on the -p port, one connection per thread");
+    println!("-quic-streams - use QUIC with all -t threads as streams of one connection");
     println!("-log - `RUST_LOG=debug ./nettest 127.0.0.1  -t5 -tls -log`");
     println!("-t<num_threads> - number of threads");
     println!("-raw - output results in parseable format (ping/download/upload)");
@@ -140,7 +166,8 @@ pub fn print_help() {
     println!("-h - print help");
     println!("-g - print graphs");
     println!("-p - port");
-    println!("-e - encryption key");
+    println!("-e <key> - sign the test token with this key, one of the keys in the server's -keys file");
+    println!("--config <path> - config file (default: ~/.config/nettest/nettest.conf, then /etc/nettest.conf)");
     println!("-h - print help");
     println!("-h - print help");
 }
@@ -1,4 +1,4 @@
-use crate::client::{client::Measurement, print::printer::print_test_result};
+use crate::client::client::Measurement;
 
 
 pub fn calculate_speed_from_measurements(measurements: Vec<Vec<(u64, u64)>>) -> (f64, f64, f64) {
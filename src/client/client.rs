@@ -1,4 +1,5 @@
 use crate::client::args_parser::{parse_args, print_help};
+#[cfg(feature = "graphs")]
 use crate::client::print::graph_service::GraphService;
 use crate::client::print::printer::print_test_header;
 use crate::client::runnner::run_threads;
@@ -32,6 +33,10 @@ pub struct SharedStats {
 pub struct ClientConfig {
     pub use_tls: bool,
     pub use_websocket: bool,
+    // QUIC по UDP на порт -p
+    pub use_quic: bool,
+    // Все -t потоков на одном QUIC соединении
+    pub quic_streams: bool,
     pub graphs: bool,
     pub raw_output: bool,
     pub thread_count: usize,
@@ -43,6 +48,8 @@ pub struct ClientConfig {
     pub control_server: String,
     pub save_results: bool,
     pub client_uuid: Option<String>,
+    // Ключ для подписи токена теста (-e)
+    pub secret_key: Option<String>,
     pub git_hash: Option<String>,
 }
 
@@ -66,10 +73,13 @@ pub async fn client_run(args: Vec<String>, dafault_config: FileConfig) -> anyhow
 
     info!("Config: {:?}", config);
 
-    let state_refs = run_threads(config.clone(), stats).await;
+    let state_refs = run_threads(config.clone(), stats).await?;
 
+    #[cfg(feature = "graphs")]
     if config.graphs {
-        GraphService::print_graph(&state_refs.unwrap());
+        GraphService::print_graph(&state_refs);
     }
+    #[cfg(not(feature = "graphs"))]
+    let _ = state_refs;
     Ok(())
 }
@@ -4,18 +4,14 @@ pub const DEFAULT_READ_BUFFER_SIZE: usize = 1024 * 1024;
 /// Default buffer size for write operations
 pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 1024;
 
-/// HTTP upgrade request template for RMBT protocol
-pub const RMBT_UPGRADE_REQUEST: &str = "GET /rmbt HTTP/1.1 \r\n\
-    Connection: Upgrade \r\n\
-    Upgrade: RMBT\r\n\
-    RMBT-Version: 1.2.0\r\n\
-    \r\n";
-
 /// String that indicates token acceptance from server
 pub const ACCEPT_TOKEN_STRING: &str = "ACCEPT TOKEN";
 
-/// String that indicates acceptance of GETCHUNKS command and available commands
-pub const ACCEPT_GETCHUNKS_STRING: &str = "ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING QUIT\n";
+/// Prefix of the line that lists the commands the server accepts
+pub const ACCEPT_COMMANDS_PREFIX: &str = "ACCEPT GETCHUNKS";
+
+/// Advertised by servers that can sign results
+pub const SIGNEDRESULT_COMMAND: &str = "SIGNEDRESULT";
 
 /// GETCHUNKS command
 pub const GETCHUNKS_COMMAND: &[u8] = b"GETCHUNKS\n";
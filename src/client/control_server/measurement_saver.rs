@@ -1,12 +1,15 @@
-use crate::client::client::{SharedStats, ClientConfig};
+use crate::client::client::ClientConfig;
 use log::{warn, info};
 use serde_json::json;
-use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 use std::fs;
-use std::path::PathBuf;
-use std::env;
+use std::io;
+use std::path::Path;
+
+use crate::config::paths;
+
+const CLIENT_UUID_FILE: &str = "client_uuid";
 
 #[derive(Debug, Clone, Copy)]
 pub enum ConnectionType {
@@ -60,51 +63,28 @@ impl MeasurementSaver {
     }
 
     fn ensure_client_uuid(&mut self) -> Result<String, Box<dyn std::error::Error>> {
-        // Если client_uuid уже есть, возвращаем его
+        // Если client_uuid уже есть (из конфига или NETTEST_CLIENT_UUID), возвращаем его
         if let Some(uuid) = &self.client_uuid {
             return Ok(uuid.clone());
         }
-        
-        // Генерируем новый UUID
-        let new_uuid = Uuid::new_v4().to_string();
-        
-        // Определяем путь к конфигурационному файлу
-        let config_path = if cfg!(target_os = "macos") {
-            let home_dir = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-            PathBuf::from(format!("{}/.config/nettest.conf", home_dir))
-        } else {
-            PathBuf::from("/etc/nettest.conf")
-        };
-        
-        // Читаем текущий контент файла
-        let mut content = if config_path.exists() {
-            fs::read_to_string(&config_path)?
-        } else {
-            String::new()
-        };
-        
-        // Проверяем, есть ли уже client_uuid (независимо от того, закомментирован он или нет)
-        let has_uuid = content.lines().any(|line| {
-            let line = line.trim();
-            line.starts_with("client_uuid") && !line.starts_with("#")
-        });
-        
-        // Если client_uuid нет или он закомментирован, добавляем его
-        if !has_uuid {
-            if !content.is_empty() && !content.ends_with('\n') {
-                content.push('\n');
+
+        // UUID живёт в state dir, конфиг клиент не трогает
+        let path = paths::state_dir().join(CLIENT_UUID_FILE);
+        let uuid = match fs::read_to_string(&path) {
+            Ok(content) if !content.trim().is_empty() => content.trim().to_string(),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {
+                let new_uuid = Uuid::new_v4().to_string();
+                match save_client_uuid(&path, &new_uuid) {
+                    Ok(()) => println!("Generated new client UUID {}, saved to {}", new_uuid, path.display()),
+                    Err(e) => warn!("Could not save client UUID to {}: {}", path.display(), e),
+                }
+                new_uuid
             }
-            content.push_str(&format!("client_uuid=\"{}\"\n", new_uuid));
-            
-            // Записываем обновленный контент
-            fs::write(&config_path, content)?;
-            println!("Generated and saved new client UUID: {}", new_uuid);
-        }
-        
-        // Обновляем внутреннее состояние
-        self.client_uuid = Some(new_uuid.clone());
-        
-        Ok(new_uuid)
+        };
+
+        self.client_uuid = Some(uuid.clone());
+        Ok(uuid)
     }
 
     pub async fn save_measurement_with_speeds(
@@ -112,6 +92,7 @@ impl MeasurementSaver {
         ping_median: Option<u64>,
         download_speed_gbps: Option<f64>,
         upload_speed_gbps: Option<f64>,
+        signed_results: &[String],
     ) -> Result<(), Box<dyn std::error::Error>> {
         // Обеспечиваем наличие client_uuid
         let client_uuid = self.ensure_client_uuid()?;
@@ -145,6 +126,11 @@ impl MeasurementSaver {
             "threadsNumber": self.threads_number,
         });
 
+        // Подписанные сервером результаты, по одному на соединение
+        if !signed_results.is_empty() {
+            measurement_data["signedResults"] = json!(signed_results);
+        }
+
         // Добавляем commitHash только если есть git_hash в конфигурации
         if let Some(git_hash) = &self.git_hash {
             measurement_data["commitHash"] = json!(git_hash);
@@ -178,3 +164,10 @@ impl MeasurementSaver {
         Ok(())
     }
 }
+
+fn save_client_uuid(path: &Path, uuid: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", uuid))
+}
@@ -48,3 +48,35 @@ pub fn get_chunk(size: u64, terminal: bool) -> BytesMut {
         return buffer;
     }
 }
+
+/// Prebuilt chunk of `size`; sizes that are not a power of two are cut from the
+/// largest chunk once and kept in `slot` until the size changes.
+pub fn chunk_of_size(size: u64, terminal: bool, slot: &mut Option<BytesMut>) -> &BytesMut {
+    let storage: &HashMap<u64, BytesMut> = if terminal { &CHUNK_TERMINATION_STORAGE } else { &CHUNK_STORAGE };
+    if let Some(chunk) = storage.get(&size) {
+        return chunk;
+    }
+    if slot.as_ref().map(|chunk| chunk.len() as u64) != Some(size) {
+        *slot = Some(get_chunk(size, terminal));
+    }
+    slot.as_ref().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_of_size_without_prebuilt_chunk() {
+        let mut slot = None;
+        let chunk = chunk_of_size(5000, false, &mut slot);
+        assert_eq!(chunk.len(), 5000);
+        assert_eq!(chunk[4999], 0x00);
+
+        let chunk = chunk_of_size(6000, true, &mut slot);
+        assert_eq!(chunk.len(), 6000);
+        assert_eq!(chunk[5999], 0xFF);
+
+        assert_eq!(chunk_of_size(MAX_CHUNK_SIZE, false, &mut None).len() as u64, MAX_CHUNK_SIZE);
+    }
+}
@@ -7,8 +7,20 @@ use crate::client::handlers::greeting::{handle_greeting_receive_greeting, handle
 use crate::client::handlers::get_time::{handle_get_time_receive_chunk, handle_get_time_receive_time, handle_get_time_send_command, handle_get_time_send_ok};
 use crate::client::handlers::ping::{handle_ping_receive_pong, handle_ping_receive_time, handle_ping_send_ok, handle_ping_send_ping};
 use crate::client::handlers::puttimeresult::{handle_put_time_result_send_command, handle_put_time_result_send_chunks, handle_put_time_result_send_last_chunk, handle_put_time_result_receive_ok, handle_put_time_result_receive_time};
+use crate::client::handlers::signed_result::{handle_signed_result_receive, handle_signed_result_send_command};
+use crate::client::constants::ACCEPT_COMMANDS_PREFIX;
 use crate::client::state::{MeasurementState, TestPhase};
 
+/// True when the buffer ends with the server's ACCEPT command list
+pub fn ends_with_accept_line(buffer: &[u8]) -> bool {
+    if !buffer.ends_with(b"\n") {
+        return false;
+    }
+    let lines = &buffer[..buffer.len() - 1];
+    let line_start = lines.iter().rposition(|b| *b == b'\n').map_or(0, |p| p + 1);
+    lines[line_start..].starts_with(ACCEPT_COMMANDS_PREFIX.as_bytes())
+}
+
 
 pub fn handle_client_readable_data(state: &mut MeasurementState, poll: &Poll) -> io::Result<usize> {
 
@@ -28,6 +40,8 @@ pub fn handle_client_readable_data(state: &mut MeasurementState, poll: &Poll) ->
         TestPhase::PerfReceiveOk => handle_put_time_result_receive_ok(poll, state),
         TestPhase::PerfReceiveTime => handle_put_time_result_receive_time(poll, state),
 
+        TestPhase::SignedResultReceive => handle_signed_result_receive(poll, state),
+
         // TestPhase::PerfReceiveOk => handle_perf_receive_ok(poll, state),
         // TestPhase::PerfReceiveTime => handle_perf_receive_time(poll, state),
         TestPhase::GreetingSendConnectionType => handle_greeting_send_connection_type(poll, state),
@@ -59,6 +73,8 @@ pub fn handle_client_writable_data(state: &mut MeasurementState, poll: &Poll) ->
         TestPhase::PerfSendChunks => handle_put_time_result_send_chunks(poll, state),
         TestPhase::PerfSendLastChunk => handle_put_time_result_send_last_chunk(poll, state),
 
+        TestPhase::SignedResultSendCommand => handle_signed_result_send_command(poll, state),
+
         // TestPhase::PerfSendCommand => handle_perf_send_command(poll, state),
         // TestPhase::PerfSendChunks => handle_perf_send_chunks(poll, state),
         // TestPhase::PerfSendLastChunk => handle_perf_send_last_chunk(poll, state),
@@ -1,6 +1,7 @@
 use crate::client::state::TestPhase;
+use crate::client::handlers::basic_handler::ends_with_accept_line;
 use crate::client::constants::{
-    ACCEPT_GETCHUNKS_STRING, MAX_CHUNKS_BEFORE_SIZE_INCREASE, MAX_CHUNK_SIZE, OK_COMMAND,
+    MAX_CHUNKS_BEFORE_SIZE_INCREASE, MAX_CHUNK_SIZE, OK_COMMAND,
     PRE_DOWNLOAD_DURATION_NS,
 };
 use crate::client::state::MeasurementState;
@@ -20,7 +21,7 @@ pub fn handle_get_chunks_receive_time(
         state.read_pos += n;
         let buffer_str = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]);
 
-        if buffer_str.contains(ACCEPT_GETCHUNKS_STRING) {
+        if ends_with_accept_line(&state.read_buffer[..state.read_pos]) {
             if let Some(time_ns) = parse_time_response(&buffer_str) {
                 if time_ns < PRE_DOWNLOAD_DURATION_NS && state.chunk_size < MAX_CHUNK_SIZE as usize
                 {
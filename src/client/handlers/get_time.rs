@@ -4,7 +4,7 @@ use mio::{Interest, Poll};
 use std::time::Instant;
 
 use crate::client::state::{MeasurementState, TestPhase};
-use crate::client::constants::ACCEPT_GETCHUNKS_STRING;
+use crate::client::handlers::basic_handler::ends_with_accept_line;
 
 const TEST_DURATION_NS: u64 = 10_000_000_000; // 7 seconds
 
@@ -108,7 +108,7 @@ pub fn handle_get_time_receive_time(
         state.read_pos += n;
         let buffer_str = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]);
 
-        if buffer_str.contains(ACCEPT_GETCHUNKS_STRING) {
+        if ends_with_accept_line(&state.read_buffer[..state.read_pos]) {
             if let Some(time_ns) = buffer_str
                 .split_whitespace()
                 .nth(1)
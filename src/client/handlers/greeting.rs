@@ -1,9 +1,24 @@
+use crate::client::constants::SIGNEDRESULT_COMMAND;
+use crate::client::handlers::basic_handler::ends_with_accept_line;
 use crate::client::{state::{MeasurementState, TestPhase}};
 use crate::stream::stream::Stream;
+use crate::tokio_server::utils::token_validator::TokenValidator;
 use anyhow::Result;
 use log::{debug};
 use mio::{Interest, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
+/// `uuid_starttime_hmac` token shared by all connections of one test. The
+/// HMAC is made with `secret_key`, one of the keys in the server's -keys
+/// file; servers without -keys accept any well-formed token.
+pub fn test_token(secret_key: Option<&str>) -> Result<String> {
+    let uuid = Uuid::new_v4().to_string();
+    let start_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().to_string();
+    let hmac = TokenValidator::generate_hmac(&uuid, &start_time, secret_key.unwrap_or_default())
+        .map_err(|e| anyhow::anyhow!("Failed to sign test token: {}", e))?;
+    Ok(format!("{}_{}_{}", uuid, start_time, hmac))
+}
 
 pub fn handle_greeting_send_connection_type(
     poll: &Poll,
@@ -17,6 +32,7 @@ pub fn handle_greeting_send_connection_type(
     }
 
     match &mut state.stream {
+        #[cfg(feature = "openssl")]
         Stream::WebSocketTls(stream) => {
             state.phase = TestPhase::GreetingSendToken;
             debug!("TO Greeting send token token WS TLS {:?}", state.token);
@@ -58,11 +74,10 @@ pub fn handle_greeting_send_token(
     state: &mut MeasurementState,
 ) -> Result<usize, std::io::Error> {
     debug!("handle_greeting_send_token token {:?}", state.token);
-    let s = format!("TOKEN {}\n", state.token.0);
+    let s = format!("TOKEN {}\n", state.test_token);
 
     if state.write_pos == 0 {
         debug!("[handle_greeting_send_token] Writing token command");
-        //TODO send uuid
         state.write_buffer[state.write_pos..state.write_pos + s.len()]
             .copy_from_slice(s.as_bytes());
     }
@@ -95,6 +110,9 @@ pub fn handle_greeting_receive_greeting(
         let n = state
             .stream
             .read(&mut state.read_buffer[state.read_pos..])?;
+        if n == 0 {
+            return Err(closed_during_greeting());
+        }
         state.read_pos += n;
         debug!("handle_greeting_receive_greeting read {} bytes", String::from_utf8_lossy(&state.read_buffer));
         let end = b"ACCEPT TOKEN QUIT\n";
@@ -118,12 +136,22 @@ pub fn handle_greeting_receive_response(
         let n = state
             .stream
             .read(&mut state.read_buffer[state.read_pos..])?;
+        if state.read_buffer[..state.read_pos + n].starts_with(b"ERR") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Token was rejected by server (wrong or missing -e key?)",
+            ));
+        }
+        if n == 0 {
+            return Err(closed_during_greeting());
+        }
         state.read_pos += n;
-        let end = b"ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING QUIT\n";
-        if n > 0
-            && state.read_pos >= end.len()
-            && state.read_buffer[state.read_pos - end.len()..state.read_pos] == *end
-        {
+        if n > 0 && ends_with_accept_line(&state.read_buffer[..state.read_pos]) {
+            let response = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]);
+            state.signed_result_supported = response
+                .lines()
+                .last()
+                .is_some_and(|line| line.split_whitespace().any(|c| c == SIGNEDRESULT_COMMAND));
             state.phase = TestPhase::GreetingCompleted;
             state
                 .stream
@@ -133,3 +161,7 @@ pub fn handle_greeting_receive_response(
         }
     }
 }
+
+fn closed_during_greeting() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Server closed the connection during the greeting")
+}
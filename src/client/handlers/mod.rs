@@ -5,3 +5,4 @@ pub mod basic_handler;
 pub mod get_time;
 pub mod perf;
 pub mod puttimeresult;
+pub mod signed_result;
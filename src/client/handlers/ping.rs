@@ -1,5 +1,5 @@
 use crate::client::state::TestPhase;
-use crate::client::constants::ACCEPT_GETCHUNKS_STRING;
+use crate::client::handlers::basic_handler::ends_with_accept_line;
 use crate::client::state::MeasurementState;
 use anyhow::Result;
 use log::debug;
@@ -89,10 +89,7 @@ pub fn handle_ping_receive_time(
             .stream
             .read(&mut state.read_buffer[state.read_pos..])?;
         state.read_pos += n;
-        if state.read_pos >= ACCEPT_GETCHUNKS_STRING.len()
-            && state.read_buffer[state.read_pos - ACCEPT_GETCHUNKS_STRING.len()..state.read_pos]
-                == *ACCEPT_GETCHUNKS_STRING.as_bytes()
-        {
+        if ends_with_accept_line(&state.read_buffer[..state.read_pos]) {
             let elapsed = state.phase_start_time.unwrap().elapsed();
             let buffer_str = String::from_utf8_lossy(&state.read_buffer);
             if let Some(time_start) = buffer_str.find("TIME ") {
@@ -1,25 +1,29 @@
-use log::debug;
+use log::{debug, info};
 use mio::{Interest, Poll};
 
+use crate::client::handlers::basic_handler::ends_with_accept_line;
 use crate::client::state::{MeasurementState, TestPhase};
 
-pub fn handle_signed_result_command(
+pub fn handle_signed_result_send_command(
     poll: &Poll,
     state: &mut MeasurementState,
 ) -> Result<usize, std::io::Error> {
-    debug!("handle_signed_result_command");
-    let command = b"SIGNEDRESULT";
+    debug!("handle_signed_result_send_command token {:?}", state.token);
+    let command = b"SIGNEDRESULT\n";
     if state.write_pos == 0 {
         state.write_buffer[0..command.len()].copy_from_slice(command);
-
     }
     loop {
-        let n = state.stream.write(&mut state.read_buffer)?;
+        let n = state
+            .stream
+            .write(&state.write_buffer[state.write_pos..command.len()])?;
         state.write_pos += n;
-        if state.write_pos >= state.write_buffer.len() {
+        if state.write_pos == command.len() {
             state.write_pos = 0;
             state.read_pos = 0;
-            state.stream.reregister(poll, state.token, Interest::READABLE)?;
+            state
+                .stream
+                .reregister(poll, state.token, Interest::READABLE)?;
             state.phase = TestPhase::SignedResultReceive;
             return Ok(n);
         }
@@ -30,36 +34,74 @@ pub fn handle_signed_result_receive(
     poll: &Poll,
     state: &mut MeasurementState,
 ) -> Result<usize, std::io::Error> {
-    debug!("handle_signed_result_receive");
+    debug!("handle_signed_result_receive token {:?}", state.token);
     loop {
-        let n = state.stream.read(&mut state.read_buffer[state.read_pos..])?;
+        let n = state
+            .stream
+            .read(&mut state.read_buffer[state.read_pos..])?;
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "EOF"));
+        }
         state.read_pos += n;
-        let line = String::from_utf8_lossy(&state.read_buffer[0..state.read_pos]);
-        if line.ends_with("\n") {
-            state.envelope = Some(line.to_string());
+        if ends_with_accept_line(&state.read_buffer[..state.read_pos]) {
+            let response = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]).to_string();
+            // An ACCEPT line left over from the previous command may come first
+            let answer = match response
+                .lines()
+                .find(|line| line.starts_with("SIGNEDRESULT ") || line.starts_with("ERR"))
+            {
+                Some(answer) => answer,
+                None => continue,
+            };
+
+            match answer.strip_prefix("SIGNEDRESULT ") {
+                Some(envelope) if verify_envelope(state, envelope) => {
+                    state.envelope = Some(envelope.to_string());
+                }
+                Some(envelope) => {
+                    info!("Signed result does not match local measurement: {}", envelope);
+                }
+                None => {
+                    info!("Server did not return a signed result");
+                }
+            }
+
             state.read_pos = 0;
-            state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
-            state.phase = TestPhase::SignedResultSendOk;
+            state
+                .stream
+                .reregister(poll, state.token, Interest::WRITABLE)?;
+            state.phase = TestPhase::SignedResultCompleted;
             return Ok(n);
         }
     }
 }
 
-pub fn handle_signed_result_send_ok(
-    poll: &Poll,
-    state: &mut MeasurementState,
-) -> Result<usize, std::io::Error> {
-    debug!("handle_signed_result_send_ok");
-    let ok = b"OK\n";
-    loop {
-        let n = state.stream.write(ok)?;
-        state.write_pos += n;
-        if state.write_pos >= ok.len() {
-            state.write_pos = 0;
-            state.read_pos = 0;
-            state.stream.reregister(poll, state.token, Interest::READABLE)?;
-            state.phase = TestPhase::SignedResultCompleted;
-            return Ok(n);
+/// Checks that the byte counts the server signed are the ones this
+/// connection measured. The signature itself can only be checked by
+/// whoever holds the server key.
+fn verify_envelope(state: &MeasurementState, envelope: &str) -> bool {
+    let (message, signature) = match envelope.rsplit_once(' ') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    if signature.is_empty() {
+        return false;
+    }
+
+    let download = parse_envelope_pair(message, "GETTIME:(");
+    let upload = parse_envelope_pair(message, "PUTTIMERESULT:(");
+
+    match (download, upload) {
+        (Some((download_bytes, _)), Some((upload_bytes, _))) => {
+            download_bytes == state.bytes_received && upload_bytes == state.bytes_sent
         }
+        _ => false,
     }
 }
+
+fn parse_envelope_pair(message: &str, prefix: &str) -> Option<(u64, u128)> {
+    let start = message.find(prefix)? + prefix.len();
+    let end = start + message[start..].find(')')?;
+    let (bytes, time) = message[start..end].split_once(' ')?;
+    Some((bytes.parse().ok()?, time.parse().ok()?))
+}
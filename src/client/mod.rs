@@ -1,10 +1,20 @@
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "client")]
 pub mod constants;
+// Буферы чанков общие с mio сервером
 pub mod globals;
+#[cfg(feature = "client")]
 pub mod handlers;
+#[cfg(feature = "client")]
 pub mod print;
+#[cfg(feature = "client")]
 mod runnner;
+#[cfg(feature = "client")]
 pub mod state;
+#[cfg(feature = "client")]
 pub mod calculator;
+#[cfg(feature = "client")]
 pub mod args_parser;
+#[cfg(feature = "client")]
 pub mod control_server;
@@ -1,2 +1,3 @@
+#[cfg(feature = "graphs")]
 pub mod graph_service;
 pub mod printer;
\ No newline at end of file
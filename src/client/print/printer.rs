@@ -115,5 +115,9 @@ pub fn print_float_result(phase: &str, status: &str, speed: Option<f64>) {
     println!("{}", table);
 }
 
-
-
+pub fn print_signed_results(envelopes: &[String]) {
+    print_result("Signed Result", "Verified", Some(envelopes.len()));
+    for envelope in envelopes {
+        println!("{}", envelope);
+    }
+}
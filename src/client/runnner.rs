@@ -8,8 +8,11 @@ use std::sync::Mutex;
 
 use log::debug;
 
+use crate::stream::stream::Stream;
+
+use crate::client::handlers::greeting::test_token;
 use crate::client::{
-    calculator::{ calculate_download_speed_from_stats_silent, calculate_upload_speed_from_stats_silent}, client::{ClientConfig, Measurement, SharedStats}, print::printer::{print_float_result, print_test_result}, state::TestState, control_server::MeasurementSaver
+    calculator::{ calculate_download_speed_from_stats_silent, calculate_upload_speed_from_stats_silent}, client::{ClientConfig, Measurement, SharedStats}, print::printer::{print_float_result, print_signed_results, print_test_result}, state::TestState, control_server::MeasurementSaver
 };
 
 pub async fn run_threads(
@@ -22,6 +25,10 @@ pub async fn run_threads(
     let ping_median = Arc::new(Mutex::new(None::<u64>));
     let download_speed = Arc::new(Mutex::new(None::<f64>));
     let upload_speed = Arc::new(Mutex::new(None::<f64>));
+    let signed_results = Arc::new(Mutex::new(Vec::<String>::new()));
+    // Первая ошибка приветствия; тест прерывается во всех потоках
+    let greeting_error = Arc::new(Mutex::new(None::<String>));
+    let test_token = test_token(config.secret_key.as_deref())?;
 
     // Get server address (IP or hostname)
     let server_addr = config.server.unwrap();
@@ -38,22 +45,41 @@ pub async fn run_threads(
     
     debug!("Resolved IP: {}", ip);
     
-    let addr = if !config.use_tls {
+    let addr = if !config.use_tls || config.use_quic {
         format!("{}:{}", ip, config.port).parse::<SocketAddr>()?
     } else {
         format!("{}:{}", ip, config.tls_port).parse::<SocketAddr>()?
     };
 
 
+    // Потоки одного QUIC соединения открываются заранее и раздаются нитям
+    let mut quic_streams: Vec<Option<Stream>> = if config.quic_streams {
+        Stream::new_quic_streams(addr, config.thread_count)?
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
     for i in 0..config.thread_count {
+        let quic_stream = quic_streams.get_mut(i).and_then(Option::take);
         let barrier = Arc::clone(&barrier);
         let stats = Arc::clone(&stats);
         let ping_median_clone = Arc::clone(&ping_median);
         let download_speed_clone = Arc::clone(&download_speed);
         let upload_speed_clone = Arc::clone(&upload_speed);
+        let signed_results_clone = Arc::clone(&signed_results);
+        let greeting_error = Arc::clone(&greeting_error);
+        let test_token = test_token.clone();
         thread_handles.push(thread::spawn(move || {
 
-            let mut state = match TestState::new(addr, config.use_tls, config.use_websocket, i, None, None) {
+            let state = match quic_stream {
+                Some(stream) => TestState::with_stream(stream, i),
+                None if config.use_quic => Stream::new_quic(addr).and_then(|stream| TestState::with_stream(stream, i)),
+                None => TestState::new(addr, config.use_tls, config.use_websocket, i, None, None),
+            };
+            let mut state = match state {
                 Ok(state) => state,
                 Err(e) => {
                     debug!("TestState error: {:?} token: {}", e, i);
@@ -61,14 +87,14 @@ pub async fn run_threads(
                 }
             };
 
-            let greeting = state.process_greeting();
-            match greeting {
-                Ok(_) => {}
-                Err(e) => {
-                    debug!("Greeting error: {:?} token: {}", e, i);
-                }
+            if let Err(e) = state.process_greeting(&test_token) {
+                debug!("Greeting error: {:?} token: {}", e, i);
+                greeting_error.lock().unwrap().get_or_insert(e.to_string());
             }
             barrier.wait();
+            if let Some(e) = greeting_error.lock().unwrap().clone() {
+                return Err(anyhow::anyhow!(e));
+            }
             state.run_get_chunks().unwrap();
             // if i == 0 {
             //     print_result(
@@ -140,6 +166,11 @@ pub async fn run_threads(
                 );
             }
 
+            state.run_signed_result().unwrap();
+            if let Some(envelope) = &state.measurement_state().envelope {
+                signed_results_clone.lock().unwrap().push(envelope.clone());
+            }
+
             barrier.wait();
 
             if i == 0 {
@@ -182,6 +213,9 @@ pub async fn run_threads(
         .filter(|s| s.is_ok())
         .map(|s| s.unwrap())
         .collect();
+    if let Some(e) = greeting_error.lock().unwrap().take() {
+        return Err(anyhow::anyhow!(e));
+    }
 
     let state_refs: Vec<Measurement> = states
         .iter()
@@ -194,6 +228,11 @@ pub async fn run_threads(
         println!("Failed threads: {}", config.thread_count - state_refs.len());
     }
 
+    let signed_results = signed_results.lock().unwrap().clone();
+    if !config.raw_output && !signed_results.is_empty() {
+        print_signed_results(&signed_results);
+    }
+
     // Сохраняем результаты если включена опция -save
     if config.save_results {
         let mut measurement_saver = MeasurementSaver::new(
@@ -209,7 +248,8 @@ pub async fn run_threads(
         if let Err(e) = measurement_saver.save_measurement_with_speeds(
             ping_median_value, 
             download_speed_value, 
-            upload_speed_value
+            upload_speed_value,
+            &signed_results,
         ).await {
             eprintln!("Failed to save measurement: {}", e);
         }
@@ -46,6 +46,10 @@ pub enum TestPhase {
     PerfSendLastChunk,
     PerfReceiveTime,
     PerfCompleted,
+
+    SignedResultSendCommand,
+    SignedResultReceive,
+    SignedResultCompleted,
 }
 
 pub struct TestState {
@@ -57,6 +61,8 @@ pub struct TestState {
 #[derive(Debug)]
 pub struct MeasurementState {
     pub token: Token,
+    // Токен теста для команды TOKEN, общий для всех потоков
+    pub test_token: String,
     pub phase: TestPhase,
     pub upload_bytes: Option<u64>,
     pub upload_time: Option<u64>,
@@ -72,6 +78,8 @@ pub struct MeasurementState {
     pub download_measurements: VecDeque<(u64, u64)>, // Хранит (t_k^(j), b_k^(j)) для каждого чанка\
     pub upload_measurements: VecDeque<(u64, u64)>, // Хранит (t_k^(j), b_k^(j)) для каждого чанка\
     pub failed: bool,
+    // Почему failed выставлен
+    pub error: Option<String>,
     pub stream: Stream,
     pub total_chunks: u32,
     pub chunk_buffer: Vec<u8>,
@@ -81,6 +89,8 @@ pub struct MeasurementState {
     pub bytes_received: u64,
     pub bytes_sent: u64,
     pub time_result_buffer: Vec<u8>,
+    pub signed_result_supported: bool,
+    pub envelope: Option<String>,
 }
 
 impl TestState {
@@ -92,11 +102,7 @@ impl TestState {
         cert_path: Option<&Path>,
         key_path: Option<&Path>,
     ) -> Result<Self> {
-        let mut poll = Poll::new()?;
-        let events = Events::with_capacity(2048);
-        let token = Token(tok);
-
-        let mut stream = if use_tls && use_websocket {
+        let stream = if use_tls && use_websocket {
             debug!("Creating WebSocket TLS stream");
             let stream = Stream::new_websocket_tls(addr)?;
             debug!("WebSocket TLS stream created");
@@ -112,6 +118,15 @@ impl TestState {
                 Stream::new_tcp(addr)?
             }
         };
+        Self::with_stream(stream, tok)
+    }
+
+    /// Test over a stream that is already connected, e.g. one of several QUIC
+    /// streams opened on a single connection.
+    pub fn with_stream(mut stream: Stream, tok: usize) -> Result<Self> {
+        let mut poll = Poll::new()?;
+        let events = Events::with_capacity(2048);
+        let token = Token(tok);
 
         debug!("Registering stream");
         stream.register(&mut poll, token, Interest::READABLE | Interest::WRITABLE)?;
@@ -130,7 +145,9 @@ impl TestState {
             upload_measurements: VecDeque::new(),
             phase_start_time: None,
             failed: false,
+            error: None,
             token,
+            test_token: String::new(),
             write_buffer: [0u8; 1024 * 8],
             read_pos: 0,
             write_pos: 0,
@@ -143,6 +160,8 @@ impl TestState {
             bytes_received: 0,
             bytes_sent: 0,
             time_result_buffer: Vec::new(),
+            signed_result_supported: false,
+            envelope: None,
         };
 
 
@@ -153,7 +172,10 @@ impl TestState {
         })
     }
 
-    pub fn process_greeting(&mut self) -> Result<&mut TestState> {
+    /// Sends `test_token` (see `test_token()`) and fails if the server
+    /// rejects it or closes the connection.
+    pub fn process_greeting(&mut self, test_token: &str) -> Result<&mut TestState> {
+        self.measurement_state.test_token = test_token.to_string();
         self.measurement_state.stream.reregister(
             &mut self.poll,
             self.measurement_state.token,
@@ -162,6 +184,12 @@ impl TestState {
 
         debug!("Greeting process_greeting");
         self.process_phase(TestPhase::GreetingCompleted, ONE_SECOND_NS * 50)?;
+        if self.measurement_state.failed {
+            return Err(anyhow::anyhow!(
+                "Greeting failed: {}",
+                self.measurement_state.error.as_deref().unwrap_or("unknown error")
+            ));
+        }
 
         debug!("Greeting completed");
 
@@ -214,6 +242,22 @@ impl TestState {
         Ok(())
     }
 
+    pub fn run_signed_result(&mut self) -> Result<()> {
+        if !self.measurement_state.signed_result_supported {
+            return Ok(());
+        }
+        self.measurement_state.phase = TestPhase::SignedResultSendCommand;
+        self.measurement_state.write_pos = 0;
+        self.measurement_state.read_pos = 0;
+        self.measurement_state.stream.reregister(
+            &self.poll,
+            self.measurement_state.token,
+            Interest::WRITABLE,
+        )?;
+        self.process_phase(TestPhase::SignedResultCompleted, ONE_SECOND_NS * 3)?;
+        Ok(())
+    }
+
     fn process_phase(
         &mut self,
         phase: TestPhase,
@@ -225,24 +269,24 @@ impl TestState {
 
         self.measurement_state.phase_start_time = Some(Instant::now());
 
-        while self.measurement_state.phase != phase {
+        while self.measurement_state.phase != phase && !self.measurement_state.failed {
             self.poll
                 .poll(&mut self.events, Some(Duration::from_nanos(test_duration_ns as u64)))?;
 
             if self.events.is_empty() {
-                let time = self
+                let elapsed = self
                     .measurement_state
                     .phase_start_time
                     .unwrap()
                     .elapsed()
                     .as_nanos();
-                let now = Instant::now().elapsed().as_nanos();
-                if now - time > test_duration_ns {
+                if elapsed > test_duration_ns {
                     info!(
                         "Test duration exceeded {:?} for token {:?}",
                         self.measurement_state.phase, self.measurement_state.token
                     );
                     self.measurement_state.failed = true;
+                    self.measurement_state.error = Some(format!("timed out in {:?}", self.measurement_state.phase));
                     break;
                 }
             }
@@ -263,6 +307,7 @@ impl TestState {
                         if n == 0 {
                             trace!("No data to read");
                             self.measurement_state.failed = true;
+                            self.measurement_state.error = Some("connection closed".to_string());
                         }
                         // If n > 0, continue processing
                     }
@@ -273,6 +318,7 @@ impl TestState {
                     Err(e) => {
                         trace!("Error: {:?}", e);
                         self.measurement_state.failed = true;
+                        self.measurement_state.error = Some(e.to_string());
                         break;
                     }
                 }
@@ -287,3 +333,50 @@ impl TestState {
     }
 
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::client::handlers::greeting::test_token;
+    use crate::config::FileConfig;
+    use crate::mioserver::server::MioServer;
+
+    const KEY: &str = "loopback-test-key";
+
+    /// mio server on a free loopback port that validates tokens with KEY.
+    fn start_server() -> SocketAddr {
+        let keys_path = std::env::temp_dir().join(format!("nettest-keys-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&keys_path, format!("{} loopback\n", KEY)).unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let args = ["-s", "-l", &addr.to_string(), "-keys", keys_path.to_str().unwrap(), "-t", "1"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut server = MioServer::new(args, FileConfig::default()).unwrap();
+        std::fs::remove_file(&keys_path).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let _guard = runtime.enter();
+            server.run().unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_greeting_with_secret_key() {
+        let addr = start_server();
+
+        let token = test_token(Some(KEY)).unwrap();
+        let mut state = TestState::new(addr, false, false, 0, None, None).unwrap();
+        state.process_greeting(&token).unwrap();
+        assert_eq!(state.measurement_state().phase, TestPhase::GreetingCompleted);
+
+        // Неверный ключ: ERR сразу, без ожидания таймаута
+        let started = Instant::now();
+        let token = test_token(Some("wrong-key")).unwrap();
+        let mut state = TestState::new(addr, false, false, 1, None, None).unwrap();
+        let err = state.process_greeting(&token).err().unwrap();
+        assert!(err.to_string().contains("Token was rejected"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
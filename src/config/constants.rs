@@ -2,10 +2,18 @@ pub const DEFAULT_NUM_THREADS: usize = 200;
 pub const CHUNK_SIZE: usize = 4096; // 4 KiB
 pub const MIN_CHUNK_SIZE: usize = 4096; // 4 KiB
 pub const MAX_CHUNK_SIZE: usize = 4194304; // 4 MiB
+/// HTTP upgrade request template for RMBT protocol
+pub const RMBT_UPGRADE_REQUEST: &str = "GET /rmbt HTTP/1.1 \r\n\
+    Connection: Upgrade \r\n\
+    Upgrade: RMBT\r\n\
+    RMBT-Version: 1.2.0\r\n\
+    \r\n";
+pub const RMBT_UPGRADE: &str = "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: RMBT\r\n\r\n";
 pub const GREETING: &str = "RMBTv2\n";
 pub const GREETING_V3: &str = "RMBTv3\n";
 pub const ACCEPT_TOKEN: &str = "ACCEPT TOKEN QUIT\n";
-pub const ACCEPT_COMMANDS: &str = "ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING QUIT\n";
+pub const ACCEPT_COMMANDS: &str = "ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING SIGNEDRESULT QUIT\n";
+pub const ACCEPT_COMMANDS_UNSIGNED: &str = "ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING QUIT\n";
 pub const TOKEN_PATTERN: &str = r"TOKEN ([a-zA-Z0-9-]+)_(\d+)_([a-zA-Z0-9+/=]+)";
 
 // Command constants
@@ -15,6 +23,7 @@ pub const CMD_PUT: &str = "PUT";
 pub const CMD_PUTNORESULT: &str = "PUTNORESULT";
 pub const CMD_PING: &str = "PING";
 pub const CMD_QUIT: &str = "QUIT";
+pub const CMD_SIGNEDRESULT: &str = "SIGNEDRESULT";
 
 // Response constants
 pub const RESP_OK: &str = "OK\n";
@@ -29,4 +38,5 @@ pub const MAX_PUT_SIZE: usize = 1024 * 1024; // 1MB
 pub const MAX_LINE_LENGTH: usize = 1024;
 pub const MAX_ACCEPT_EARLY: u32 = 20;  // 20 секунд
 pub const MAX_ACCEPT_LATE: u32 = 90;   // 90 секунд
+pub const MAX_TOKEN_REUSE: u32 = 10;   // 10 секунд
 
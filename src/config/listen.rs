@@ -0,0 +1,65 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+pub fn parse_listen_address(addr: &str) -> Result<SocketAddr, Box<dyn Error + Send + Sync>> {
+    // Try IPv6 format: [::1]:8080
+    if addr.starts_with('[') {
+        if let Some(end_bracket) = addr.rfind(']') {
+            let ip_str = &addr[1..end_bracket];
+            if let Some(port_str) = addr[end_bracket + 1..].strip_prefix(':') {
+                let ip: Ipv6Addr = ip_str.parse()?;
+                let port: u16 = port_str.parse()?;
+                return Ok(SocketAddr::new(IpAddr::V6(ip), port));
+            }
+        }
+        return Err(format!("Invalid IPv6 address format: {}", addr).into());
+    }
+
+    // Try IPv4 format: 127.0.0.1:8080
+    if let Some((ip, port)) = addr.split_once(':') {
+        let ip: std::net::Ipv4Addr = ip.parse()?;
+        let port: u16 = port.parse()?;
+        return Ok(SocketAddr::new(IpAddr::V4(ip), port));
+    }
+
+    // Try port only: 8080
+    if let Ok(port) = addr.parse::<u16>() {
+        return Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port));
+    }
+
+    Err(format!("Invalid listen address format: {}", addr).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_listen_address_ipv6() {
+        let addr = parse_listen_address("[::1]:8080").unwrap();
+        assert_eq!(addr.ip(), IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)));
+        assert_eq!(addr.port(), 8080);
+    }
+
+    #[test]
+    fn test_parse_listen_address_ipv4() {
+        let addr = parse_listen_address("127.0.0.1:8080").unwrap();
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(addr.port(), 8080);
+    }
+
+    #[test]
+    fn test_parse_listen_address_port_only() {
+        let addr = parse_listen_address("8080").unwrap();
+        assert_eq!(addr.ip(), IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(addr.port(), 8080);
+    }
+
+    #[test]
+    fn test_parse_listen_address_invalid() {
+        assert!(parse_listen_address("invalid").is_err());
+        assert!(parse_listen_address("127.0.0.1:invalid").is_err());
+        assert!(parse_listen_address("[::1]:invalid").is_err());
+    }
+}
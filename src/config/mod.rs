@@ -1,7 +1,14 @@
 use log::LevelFilter;
 
+use crate::logger::LogOptions;
+use crate::mioserver::limits::TestLimits;
+
 pub mod constants;
+pub mod listen;
 pub mod parser;
+pub mod paths;
+
+pub use listen::parse_listen_address;
 
 #[derive(Debug, Clone)]
 pub enum App {
@@ -14,9 +21,14 @@ pub struct FileConfig {
     pub app: App,
     pub server_tcp_port: String,
     pub server_tls_port: Option<String>,
+    pub server_listen: Vec<String>,
+    pub server_tls_listen: Vec<String>,
+    pub proxy_protocol_listen: Vec<String>,
+    pub server_quic_listen: Vec<String>,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
     pub server_workers: Option<usize>,
+    pub tests_per_worker: usize,
     pub user: Option<String>,
     pub daemonize: bool,
     pub use_websocket: bool,
@@ -24,9 +36,28 @@ pub struct FileConfig {
     pub client_use_tls: bool,
     pub client_use_websocket: bool,
     pub client_thread_count: usize,
+    pub client_secret_key: Option<String>,
     pub protocol_version: Option<u32>, //TODO None for latest, Some(3) for v0.3
     pub logger: LevelFilter,
+    pub log_options: LogOptions,
     pub secret_key: Option<String>,
+    pub secret_keys_file: Option<String>,
+    pub key_grace_period: u64,
+    pub metrics_listen: Option<String>,
+    pub ip_connection_limit: usize,
+    pub ip_connection_rate: u32,
+    pub cidr_connection_limits: Vec<String>,
+    pub ip_allow: Vec<String>,
+    pub ip_deny: Vec<String>,
+    pub ip_allow_file: Option<String>,
+    pub ip_deny_file: Option<String>,
+    pub drain_timeout: u64,
+    pub test_limits: TestLimits,
+    pub max_bandwidth: Option<String>,
+    pub label_bandwidth_limits: Vec<String>,
+    pub reuse_port: bool,
+    pub pid_file: Option<String>,
+    pub session_log: Option<String>,
     pub x_nettest_client: String,
     pub control_server: String,
     pub server_registration: bool,
@@ -41,19 +72,43 @@ impl Default for FileConfig {
             app: App::Server,
             server_tcp_port: "5005".to_string(),
             server_tls_port: None,
+            server_listen: Vec::new(),
+            server_tls_listen: Vec::new(),
+            proxy_protocol_listen: Vec::new(),
+            server_quic_listen: Vec::new(),
             cert_path: None,
             key_path: None,
             server_workers: None,
+            tests_per_worker: 4,
             user: None,
             daemonize: false,
             use_websocket: false,
             use_tls: false,
             protocol_version: None, 
             logger: LevelFilter::Off,
+            log_options: LogOptions::default(),
             client_use_tls: false,
             client_use_websocket: false,
             client_thread_count: 3,
+            client_secret_key: None,
             secret_key: None,
+            secret_keys_file: None,
+            key_grace_period: 300,
+            metrics_listen: None,
+            ip_connection_limit: 0,
+            ip_connection_rate: 0,
+            cidr_connection_limits: Vec::new(),
+            ip_allow: Vec::new(),
+            ip_deny: Vec::new(),
+            ip_allow_file: None,
+            ip_deny_file: None,
+            drain_timeout: 60,
+            test_limits: TestLimits::default(),
+            max_bandwidth: None,
+            label_bandwidth_limits: Vec::new(),
+            reuse_port: false,
+            pid_file: None,
+            session_log: None,
             x_nettest_client: "nt".to_string(),
             control_server: "https://api.nettest.org".to_string(),
             server_registration: false,
@@ -1,162 +1,313 @@
 
-use log::LevelFilter;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use crate::config::{App, FileConfig};
+use crate::config::{paths, App, FileConfig};
 
+const ENV_PREFIX: &str = "NETTEST_";
+// Путь к конфигу, как --config
+const CONFIG_ENV: &str = "NETTEST_CONFIG";
+const DEFAULT_CONFIG: &str = include_str!("../../nettest.conf");
 
-pub fn read_config_file() -> FileConfig {
-    use std::fs;
-    use std::path::PathBuf;
-    use std::env;
-
-    // Определяем путь к конфигурационному файлу в зависимости от ОС
-    let config_path = if cfg!(target_os = "macos") {
-        // На macOS используем ~/.config/ как основную директорию
-        let home_dir = env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-        PathBuf::from(format!("{}/.config/nettest.conf", home_dir))
-    } else {
-        PathBuf::from("/etc/nettest.conf")
+/// Reads the config from `path`, or from the first existing file of
+/// `paths::config_candidates()`, then applies NETTEST_* overrides. Without any
+/// file the built-in defaults are used; nothing is written.
+pub fn read_config_file(path: Option<PathBuf>) -> Result<FileConfig, anyhow::Error> {
+    let path = path.or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let found = match path {
+        // Явно указанный файл обязан существовать
+        Some(path) => Some(path),
+        None => paths::config_candidates().into_iter().find(|path| path.exists()),
     };
 
-    // Проверяем права доступа и читаем файл
-    let config_content = if config_path.exists() {
-        match fs::read_to_string(&config_path) {
-            Ok(content) => {
-                println!("Reading config from: {:?}", config_path);
-                content
-            }
-            Err(e) => {
-                println!(
-                    "Warning: Could not read config file {:?}: {}",
-                    config_path, e
-                );
-                String::new()
-            }
-        }
-    } else {
-        if let Some(parent) = config_path.parent() {
-            if !parent.exists() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    println!("Warning: Could not create config directory: {}", e);
-                }
-            }
+    let mut config = match found {
+        Some(path) => {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Could not read config file {}: {}", path.display(), e))?;
+            println!("Reading config from: {:?}", path);
+            parse_config_content(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
         }
-        let default_config = include_str!("../../nettest.conf");
-
-        if let Err(e) = fs::write(&config_path, default_config) {
-            println!(
-                "Warning: Could not create config file {:?}: {}",
-                config_path, e
-            );
-        } else {
-            println!("Created default config file at: {:?}", config_path);
+        None => {
+            println!("No config file found, using built-in defaults (see --config)");
+            parse_config_content(DEFAULT_CONFIG)?
         }
-
-        default_config.to_string()
     };
+    apply_env_overrides(&mut config, env::vars())?;
+    Ok(config)
+}
 
-    parse_config_content(&config_content)
+/// Removes `--config <path>` from the arguments; it is accepted in every mode.
+pub fn take_config_flag(args: &mut Vec<String>) -> Result<Option<PathBuf>, anyhow::Error> {
+    let Some(index) = args.iter().position(|arg| arg == "--config") else {
+        return Ok(None);
+    };
+    if index + 1 >= args.len() {
+        return Err(anyhow::anyhow!("--config requires a path"));
+    }
+    let path = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(PathBuf::from(path)))
 }
 
-fn parse_config_content(content: &str) -> FileConfig {
+fn parse_config_content(content: &str) -> Result<FileConfig, anyhow::Error> {
     let mut config = FileConfig::default();
 
-    for line in content.lines() {
+    for (number, line) in content.lines().enumerate() {
         let line = line.trim();
 
         if line.is_empty() || line.starts_with('#') {
             continue;
         }
 
-        if let Some((key, value)) = line.split_once('=') {
-            let key = key.trim();
-            let value = value.trim().trim_matches('"');
-
-            match key {
-                "default_mode" => {
-                    if value == "server" {
-                        config.app = App::Server;
-                    } else {
-                        config.app = App::Client;
-                    }
-                }
-                "server_tcp_port" => {
-                    if let Ok(port) = value.parse::<u16>() {
-                        config.server_tcp_port = port.to_string();
-                    } else {
-                        config.server_tcp_port = "5005".to_string();
-                    }
-                }
-                "server_tls_port" => {
-                    if let Ok(port) = value.parse::<u16>() {
-                        config.server_tls_port = Some(port.to_string());
-                    } else {
-                        config.server_tls_port = Some("443".to_string());
-                    }
-                }
-                "cert_path" => config.cert_path = Some(value.to_string()),
-                "key_path" => config.key_path = Some(value.to_string()),
-                "server_workers" => {
-                    if let Ok(threads) = value.parse::<usize>() {
-                        config.server_workers = Some(threads);
-                    }
-                }
-                "user" => config.user = Some(value.to_string()),
-                "daemonize" => config.daemonize = value.parse().unwrap_or(false),
-                "use_websocket" => config.use_websocket = value.parse().unwrap_or(false),
-                "protocol_version" => {
-                    if let Ok(version) = value.parse::<u32>() {
-                        config.protocol_version = Some(version);
-                    }
-                }
-                // Client-specific settings
-                "client_use_tls" => {
-                    if value == "true" {
-                        config.client_use_tls = true;
-                    } else {
-                        config.client_use_tls = false;
-                    }
-                }
-                "client_use_websocket" => {
-                    if value == "true" {
-                        config.client_use_websocket = true;
-                    } else {
-                        config.client_use_websocket = false;
-                    }
-                }
-                // Logging settings
-                "logger" => {
-                    if value == "info" {
-                        config.logger = LevelFilter::Info;
-                    } else if value == "debug" {
-                        config.logger = LevelFilter::Debug;
-                    } else if value == "trace" {
-                        config.logger = LevelFilter::Trace;
-                    }
-                }
-                "server_registration" => {
-                    if value == "true" {
-                        config.server_registration = true;
-                    } else {
-                        config.server_registration = false;
-                    }
-                }
-                "hostname" => config.hostname = Some(value.to_string()),
-                "x_nettest_client" => config.x_nettest_client = value.to_string(),
-                "control_server" => config.control_server = value.to_string(),
-                "client_uuid" => {
-                    // Убираем кавычки если они есть
-                    let clean_value = value.trim_matches('"').trim();
-                    if !clean_value.is_empty() {
-                        config.client_uuid = Some(clean_value.to_string());
-                    }
-                }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(anyhow::anyhow!("line {}: expected key = value", number + 1));
+        };
+        apply_key(&mut config, key.trim(), value.trim().trim_matches('"'))
+            .map_err(|e| anyhow::anyhow!("line {}: {}", number + 1, e))?;
+    }
+
+    Ok(config)
+}
+
+/// NETTEST_CONTROL_SERVER=... overrides control_server and so on. Keys that may
+/// be repeated take a whitespace-separated list that replaces the file's list.
+/// Names that are not config keys are skipped with a warning, since the
+/// environment is shared with other programs; bad values are still errors.
+fn apply_env_overrides(
+    config: &mut FileConfig,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), anyhow::Error> {
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if name == CONFIG_ENV {
+            continue;
+        }
+        let key = key.to_ascii_lowercase();
+        let applied = if let Some(list) = list_for_key(config, &key) {
+            list.clear();
+            value
+                .split_whitespace()
+                .try_for_each(|item| apply_key(config, &key, item))
+        } else {
+            apply_key(config, &key, value.trim().trim_matches('"'))
+        };
+        match applied {
+            Ok(()) => {}
+            // Логгер ещё не запущен
+            Err(e) if e.is::<UnknownKey>() => eprintln!("Warning: ignoring {}: {}", name, e),
+            Err(e) => return Err(anyhow::anyhow!("{}: {}", name, e)),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct UnknownKey(String);
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown config key '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownKey {}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, anyhow::Error>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid value '{}' for {}: {}", value, key, e))
+}
+
+fn parse_seconds(key: &str, value: &str) -> Result<Duration, anyhow::Error> {
+    parse_value(key, value).map(Duration::from_secs)
+}
+
+// Ключи, которые можно повторять в файле
+fn list_for_key<'a>(config: &'a mut FileConfig, key: &str) -> Option<&'a mut Vec<String>> {
+    match key {
+        "server_listen" => Some(&mut config.server_listen),
+        "server_tls_listen" => Some(&mut config.server_tls_listen),
+        "proxy_protocol_listen" => Some(&mut config.proxy_protocol_listen),
+        "server_quic_listen" => Some(&mut config.server_quic_listen),
+        "cidr_connection_limit" => Some(&mut config.cidr_connection_limits),
+        "ip_allow" => Some(&mut config.ip_allow),
+        "ip_deny" => Some(&mut config.ip_deny),
+        "label_bandwidth_limit" => Some(&mut config.label_bandwidth_limits),
+        _ => None,
+    }
+}
+
+fn apply_key(config: &mut FileConfig, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    match key {
+        "default_mode" => {
+            config.app = match value {
+                "server" => App::Server,
+                "client" => App::Client,
                 _ => {
-                    println!("Warning: Unknown config key: {}", key);
+                    return Err(anyhow::anyhow!(
+                        "invalid value '{}' for default_mode, expected server or client",
+                        value
+                    ))
                 }
             }
         }
+        "server_tcp_port" => config.server_tcp_port = parse_value::<u16>(key, value)?.to_string(),
+        "server_tls_port" => config.server_tls_port = Some(parse_value::<u16>(key, value)?.to_string()),
+        "server_listen" => config.server_listen.push(value.to_string()),
+        "server_tls_listen" => config.server_tls_listen.push(value.to_string()),
+        "proxy_protocol_listen" => config.proxy_protocol_listen.push(value.to_string()),
+        "server_quic_listen" => config.server_quic_listen.push(value.to_string()),
+        "cert_path" => config.cert_path = Some(value.to_string()),
+        "key_path" => config.key_path = Some(value.to_string()),
+        "server_workers" => config.server_workers = Some(parse_value(key, value)?),
+        "tests_per_worker" => config.tests_per_worker = parse_value::<usize>(key, value)?.max(1),
+        "user" => config.user = Some(value.to_string()),
+        "daemonize" => config.daemonize = parse_value(key, value)?,
+        "use_websocket" => config.use_websocket = parse_value(key, value)?,
+        "protocol_version" => config.protocol_version = Some(parse_value(key, value)?),
+        // Client-specific settings
+        "client_use_tls" => config.client_use_tls = parse_value(key, value)?,
+        "client_use_websocket" => config.client_use_websocket = parse_value(key, value)?,
+        "client_secret_key" => config.client_secret_key = Some(value.to_string()),
+        // Logging settings: off, error, warn, info, debug or trace
+        "logger" => config.logger = parse_value(key, value)?,
+        "log_format" => config.log_options.format = value.parse()?,
+        "log_output" => config.log_options.output = value.parse()?,
+        "log_stdout" => config.log_options.stdout = Some(value.parse()?),
+        "server_registration" => config.server_registration = parse_value(key, value)?,
+        "secret_keys_file" => config.secret_keys_file = Some(value.to_string()),
+        "key_grace_period" => config.key_grace_period = parse_value(key, value)?,
+        "metrics_listen" => config.metrics_listen = Some(value.to_string()),
+        "ip_connection_limit" => config.ip_connection_limit = parse_value(key, value)?,
+        "ip_connection_rate" => config.ip_connection_rate = parse_value(key, value)?,
+        // Может повторяться, по одной подсети на строку
+        "cidr_connection_limit" => config.cidr_connection_limits.push(value.to_string()),
+        // Может повторяться, по одной сети на строку
+        "ip_allow" => config.ip_allow.push(value.to_string()),
+        "ip_deny" => config.ip_deny.push(value.to_string()),
+        "ip_allow_file" => config.ip_allow_file = Some(value.to_string()),
+        "ip_deny_file" => config.ip_deny_file = Some(value.to_string()),
+        "pid_file" => config.pid_file = Some(value.to_string()),
+        "session_log" => config.session_log = Some(value.to_string()),
+        "reuse_port" => config.reuse_port = parse_value(key, value)?,
+        "drain_timeout" => config.drain_timeout = parse_value(key, value)?,
+        "max_gettime_seconds" => config.test_limits.max_gettime = parse_seconds(key, value)?,
+        "max_put_bytes" => config.test_limits.max_put_bytes = parse_value(key, value)?,
+        "max_chunks" => config.test_limits.max_chunks = parse_value(key, value)?,
+        "min_chunk_size" => config.test_limits.min_chunk_size = parse_value(key, value)?,
+        "max_chunk_size" => config.test_limits.max_chunk_size = parse_value(key, value)?,
+        "idle_timeout" => config.test_limits.idle_timeout = parse_seconds(key, value)?,
+        "handshake_timeout" => config.test_limits.handshake_timeout = parse_seconds(key, value)?,
+        "max_session_seconds" => config.test_limits.max_session = parse_seconds(key, value)?,
+        "max_bandwidth_mbps" => config.max_bandwidth = Some(value.to_string()),
+        // Может повторяться, по одной метке ключа на строку
+        "label_bandwidth_limit" => config.label_bandwidth_limits.push(value.to_string()),
+        "hostname" => config.hostname = Some(value.to_string()),
+        "x_nettest_client" => config.x_nettest_client = value.to_string(),
+        "control_server" => config.control_server = value.to_string(),
+        "client_uuid" => {
+            // Убираем кавычки если они есть
+            let clean_value = value.trim_matches('"').trim();
+            if !clean_value.is_empty() {
+                config.client_uuid = Some(clean_value.to_string());
+            }
+        }
+        _ => return Err(UnknownKey(key.to_string()).into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_default_config_parses() {
+        let config = parse_config_content(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.server_tcp_port, "5005");
+    }
+
+    #[test]
+    fn test_unknown_key_is_an_error() {
+        let err = parse_config_content("server_tcp_port = 5005\nserver_tpc_port = 5006\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown config key 'server_tpc_port'");
+        assert!(parse_config_content("server_listen\n").is_err());
     }
 
-    config
+    #[test]
+    fn test_env_overrides() {
+        let mut config = parse_config_content("control_server = \"https://a.example\"\nserver_listen = 5005\n").unwrap();
+        apply_env_overrides(
+            &mut config,
+            vars(&[
+                ("NETTEST_CONTROL_SERVER", "http://127.0.0.1:8080"),
+                ("NETTEST_SERVER_LISTEN", "127.0.0.1:5005 [::1]:5005"),
+                ("NETTEST_CONFIG", "/ignored.conf"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.control_server, "http://127.0.0.1:8080");
+        assert_eq!(config.server_listen, vec!["127.0.0.1:5005", "[::1]:5005"]);
+
+        // Чужие NETTEST_* переменные не мешают запуску
+        apply_env_overrides(&mut config, vars(&[("NETTEST_NO_SUCH_KEY", "1")])).unwrap();
+        let err = apply_env_overrides(&mut config, vars(&[("NETTEST_IDLE_TIMEOUT", "15s")])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "NETTEST_IDLE_TIMEOUT: invalid value '15s' for idle_timeout: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_bad_values_are_errors() {
+        for line in [
+            "idle_timeout = 15s",
+            "max_chunk_size = 4M",
+            "server_tcp_port = 70000",
+            "daemonize = yes",
+            "client_use_tls = 1",
+            "default_mode = srever",
+            "logger = loud",
+        ] {
+            let err = parse_config_content(line).unwrap_err().to_string();
+            assert!(err.starts_with("line 1: invalid value"), "{}: {}", line, err);
+        }
+
+        let config = parse_config_content("logger = warn
+default_mode = client
+daemonize = true
+").unwrap();
+        assert_eq!(config.logger, log::LevelFilter::Warn);
+        assert!(matches!(config.app, App::Client));
+        assert!(config.daemonize);
+    }
+
+    #[test]
+    fn test_take_config_flag() {
+        let mut args: Vec<String> = ["nettest", "-c", "--config", "/tmp/n.conf", "-t", "2"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(take_config_flag(&mut args).unwrap(), Some(PathBuf::from("/tmp/n.conf")));
+        assert_eq!(args, vec!["nettest", "-c", "-t", "2"]);
+        assert!(take_config_flag(&mut vec!["nettest".to_string(), "--config".to_string()]).is_err());
+    }
 }
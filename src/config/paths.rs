@@ -0,0 +1,51 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+pub const SYSTEM_CONFIG: &str = "/etc/nettest.conf";
+pub const SYSTEM_STATE_DIR: &str = "/var/lib/nettest";
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+// По спецификации XDG относительные пути в переменных игнорируются
+fn xdg_dir(var: &str, default_in_home: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+        Some(dir) if Path::new(&dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => home_dir().map(|home| home.join(default_in_home)),
+    }
+}
+
+/// Config files tried in order when no path is given: the user's XDG config
+/// for normal users, then the system-wide file.
+pub fn config_candidates() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if !is_root() {
+        if let Some(dir) = xdg_dir("XDG_CONFIG_HOME", ".config") {
+            paths.push(dir.join("nettest").join("nettest.conf"));
+            // Прежнее место конфига на macOS
+            if cfg!(target_os = "macos") {
+                paths.push(dir.join("nettest.conf"));
+            }
+        }
+    }
+    paths.push(PathBuf::from(SYSTEM_CONFIG));
+    paths
+}
+
+/// Directory for files nettest keeps between runs, such as the client UUID:
+/// `$XDG_STATE_HOME/nettest` for normal users, /var/lib/nettest for root.
+pub fn state_dir() -> PathBuf {
+    if !is_root() {
+        if let Some(dir) = xdg_dir("XDG_STATE_HOME", ".local/state") {
+            return dir.join("nettest");
+        }
+    }
+    PathBuf::from(SYSTEM_STATE_DIR)
+}
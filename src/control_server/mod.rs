@@ -0,0 +1,318 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, LevelFilter};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::logger;
+use crate::config::{parse_listen_address, paths};
+
+pub mod store;
+
+use store::{Deregistration, Heartbeat, Store};
+
+const DEFAULT_LISTEN: &str = "8080";
+const STORE_FILE: &str = "control.json";
+const MAX_HEAD_SIZE: usize = 8192;
+// Измерение с подписанными результатами всех потоков укладывается с запасом
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct ControlConfig {
+    pub listen: SocketAddr,
+    pub store_path: PathBuf,
+    pub log_level: LevelFilter,
+}
+
+pub fn parse_args(args: &[String]) -> Result<ControlConfig, anyhow::Error> {
+    let mut config = ControlConfig {
+        listen: parse_listen_address(DEFAULT_LISTEN).map_err(|e| anyhow::anyhow!("{}", e))?,
+        store_path: paths::state_dir().join(STORE_FILE),
+        log_level: LevelFilter::Info,
+    };
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-l" => {
+                i += 1;
+                if i < args.len() {
+                    config.listen = parse_listen_address(&args[i])
+                        .map_err(|e| anyhow::anyhow!("Invalid listen address {}: {}", args[i], e))?;
+                }
+            }
+            "-db" => {
+                i += 1;
+                if i < args.len() {
+                    config.store_path = PathBuf::from(&args[i]);
+                }
+            }
+            "-log" => {
+                i += 1;
+                if i < args.len() {
+                    config.log_level = args[i].parse()?;
+                }
+            }
+            "--help" | "-h" => {
+                print_help();
+                return Err(anyhow::anyhow!("Help printed"));
+            }
+            _ => return Err(anyhow::anyhow!("Unknown option: {}", args[i])),
+        }
+        i += 1;
+    }
+    Ok(config)
+}
+
+pub fn print_help() {
+    println!("==== Nettest Control Server ====");
+    println!("Local stand-in for the control server API, for labs and tests without internet access.");
+    println!("Point servers and clients at it with control_server = \"http://<host>:8080\".");
+    println!("Usage: nettest --control [-l <listen_address>] [-db <file>] [-log <level>]\n");
+    println!(" -l     listen on (IP and) port (default: 8080 on IPv4 and IPv6)\n");
+    println!(" -db    file with registered servers (default: {} in the state directory);", STORE_FILE);
+    println!("        measurements are appended to <name>.measurements.jsonl next to it\n");
+    println!(" -log   log level: info, debug, trace (default: info)\n");
+    println!(" --config  config file, as for the client and server\n");
+}
+
+/// Serves the control server endpoints until the process is stopped.
+pub async fn run_control_server(args: &[String]) -> Result<(), anyhow::Error> {
+    let config = parse_args(args)?;
+    // Лаборатория без root: /var/log/nettest может быть недоступен
+    logger::init_logger_or_stdout(config.log_level).map_err(|e| anyhow::anyhow!("{}", e))?;
+    if let Some(parent) = config.store_path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let store = Store::open(&config.store_path)?;
+    info!(
+        "Control server store {}: {} servers, {} measurements in {}",
+        config.store_path.display(),
+        store.servers().len(),
+        store.measurement_count(),
+        store.measurements_path().display()
+    );
+    let store = Arc::new(Mutex::new(store));
+
+    let listener = TcpListener::bind(config.listen).await?;
+    info!("Control server listening on http://{}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let store = store.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, serve_connection(stream, peer, store)).await {
+                Ok(Err(e)) => debug!("Control request from {} failed: {}", peer, e),
+                Err(_) => debug!("Control request from {} timed out", peer),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+/// One request per connection, like the metrics endpoint.
+async fn serve_connection(mut stream: TcpStream, peer: SocketAddr, store: Arc<Mutex<Store>>) -> io::Result<()> {
+    let (status, body) = match read_request(&mut stream).await? {
+        // Запись на диск и ожидание блокировки не должны занимать потоки tokio
+        Ok(request) => tokio::task::spawn_blocking(move || {
+            let mut store = store.lock().unwrap();
+            route(&mut store, peer.ip().to_canonical(), &request, unix_now())
+        })
+        .await
+        .map_err(io::Error::other)?,
+        Err(status) => (status, String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// Reads the request line, headers and a Content-Length body. An `Err` status
+/// is answered without routing.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Result<Request, &'static str>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Ok(Err("431 Request Header Fields Too Large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before request end"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(Err("400 Bad Request"));
+    };
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>());
+    let content_length = match content_length {
+        None => 0,
+        Some(Ok(length)) if length <= MAX_BODY_SIZE => length,
+        Some(Ok(_)) => return Ok(Err("413 Payload Too Large")),
+        Some(Err(_)) => return Ok(Err("400 Bad Request")),
+    };
+
+    let mut body = buf[head_end + 4..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before body end"));
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(Ok(Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        body,
+    }))
+}
+
+/// Handles one API call; returns the status line and JSON body.
+pub fn route(store: &mut Store, peer: IpAddr, request: &Request, now: u64) -> (&'static str, String) {
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/measurementServer") => {
+            let servers = store.available_servers(now);
+            debug!("Offering {} servers to {}", servers.len(), peer);
+            return json_response(&servers);
+        }
+        ("POST", "/measurementServer/auto-register") => {
+            let Ok(registration) = serde_json::from_slice(&request.body) else {
+                return ("400 Bad Request", error_body("invalid registration"));
+            };
+            store.register(peer, registration, now).map(|server| {
+                info!("Registered server {} from {}", server.id, peer);
+                Some(format!("{{\"id\":{},\"uuid\":\"{}\"}}", server.id, server.uuid))
+            })
+        }
+        ("POST", "/measurementServer/ping") => {
+            let heartbeat: Heartbeat = serde_json::from_slice(&request.body).unwrap_or_default();
+            Ok(store.ping(peer, heartbeat, now).then(|| "{}".to_string()))
+        }
+        ("DELETE", "/measurementServer/auto-deregister") => {
+            let deregistration: Deregistration = serde_json::from_slice(&request.body).unwrap_or_default();
+            store.deregister(peer, deregistration.tcp_port).map(|known| {
+                known.then(|| {
+                    info!("Deregistered server {}", peer);
+                    "{}".to_string()
+                })
+            })
+        }
+        ("POST", "/measurement/save") => {
+            let Ok(measurement) = serde_json::from_slice(&request.body) else {
+                return ("400 Bad Request", error_body("invalid measurement"));
+            };
+            store.save_measurement(measurement).map(|()| {
+                info!("Saved measurement from {}", peer);
+                Some("{}".to_string())
+            })
+        }
+        (_, "/measurementServer" | "/measurementServer/auto-register" | "/measurementServer/ping")
+        | (_, "/measurementServer/auto-deregister" | "/measurement/save") => {
+            return ("405 Method Not Allowed", error_body("method not allowed"));
+        }
+        _ => return ("404 Not Found", error_body("not found")),
+    };
+    match result {
+        Ok(Some(body)) => ("200 OK", body),
+        // Незнакомый сервер: 404 заставляет его зарегистрироваться заново
+        Ok(None) => ("404 Not Found", error_body("server not registered")),
+        Err(e) => {
+            info!("Failed to write control server store: {}", e);
+            ("500 Internal Server Error", error_body("store write failed"))
+        }
+    }
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> (&'static str, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => ("200 OK", body),
+        Err(_) => ("500 Internal Server Error", error_body("serialization failed")),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_route_registration_flow() {
+        let path = std::env::temp_dir().join(format!("nettest-control-{}.json", uuid::Uuid::new_v4()));
+        let mut store = Store::open(&path).unwrap();
+        let peer: IpAddr = "192.0.2.20".parse().unwrap();
+
+        let (status, _) = route(&mut store, peer, &request("POST", "/measurementServer/ping", r#"{"message":"PING"}"#), 100);
+        assert_eq!(status, "404 Not Found");
+
+        let registration = r#"{"token":"lab-server-key","tlsPort":443,"tcpPort":5005,"version":"2.0.0","hostname":"lab.example","ipV4Support":true,"ipV6Support":true}"#;
+        let (status, _) = route(&mut store, peer, &request("POST", "/measurementServer/auto-register", registration), 100);
+        assert_eq!(status, "200 OK");
+        let (status, _) = route(&mut store, peer, &request("POST", "/measurementServer/ping", r#"{"message":"PING","tcpPort":5005,"activeTests":1}"#), 110);
+        assert_eq!(status, "200 OK");
+        let (status, _) = route(&mut store, peer, &request("POST", "/measurementServer/ping", r#"{"message":"PING","tcpPort":5006}"#), 110);
+        assert_eq!(status, "404 Not Found");
+
+        let (status, body) = route(&mut store, peer, &request("GET", "/measurementServer", ""), 120);
+        assert_eq!(status, "200 OK");
+        assert!(!body.contains("lab-server-key"), "{}", body);
+        let servers: Vec<crate::client::control_server::MeasurementServer> = serde_json::from_str(&body).unwrap();
+        assert_eq!(servers[0].web_address, "lab.example");
+        assert_eq!(servers[0].server_type_details[0].port_ssl, 443);
+
+        assert_eq!(route(&mut store, peer, &request("POST", "/measurement/save", "{\"time\":1}"), 130).0, "200 OK");
+        assert_eq!(route(&mut store, peer, &request("POST", "/measurement/save", "not json"), 130).0, "400 Bad Request");
+        assert_eq!(route(&mut store, peer, &request("GET", "/measurement/save", ""), 130).0, "405 Method Not Allowed");
+        assert_eq!(route(&mut store, peer, &request("DELETE", "/measurementServer/auto-deregister", r#"{"tcpPort":5005}"#), 140).0, "200 OK");
+        assert!(route(&mut store, peer, &request("GET", "/measurementServer", ""), 150).1 == "[]");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(store.measurements_path()).unwrap();
+    }
+}
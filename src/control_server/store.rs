@@ -0,0 +1,306 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::client::control_server::{Location, MeasurementServer, ServerTypeDetail};
+use crate::mioserver::control_server::auto_registration::AutoMeasurementServerRegistrationRequest;
+
+/// Servers that have not pinged for this long are not offered to clients.
+pub const STALE_AFTER_SECS: u64 = 60;
+
+/// A measurement server known to the control server, keyed by its address and
+/// TCP port, so several servers on one host stay apart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredServer {
+    pub id: i32,
+    pub uuid: String,
+    pub address: IpAddr,
+    pub registration: AutoMeasurementServerRegistrationRequest,
+    pub registered_at: u64,
+    pub last_seen: u64,
+    pub active_tests: Option<usize>,
+    pub capacity: Option<usize>,
+}
+
+impl RegisteredServer {
+    // Серверы до 2.0 не присылают tcpPort в ping и deregister
+    fn matches(&self, address: IpAddr, tcp_port: Option<i32>) -> bool {
+        self.address == address && tcp_port.is_none_or(|port| port == self.registration.tcp_port)
+    }
+
+    fn is_full(&self) -> bool {
+        matches!((self.active_tests, self.capacity), (Some(active), Some(capacity)) if capacity > 0 && active >= capacity)
+    }
+
+    /// The entry `GET /measurementServer` returns to clients.
+    pub fn to_measurement_server(&self) -> MeasurementServer {
+        let registration = &self.registration;
+        MeasurementServer {
+            id: self.id,
+            uuid: Some(self.uuid.clone()),
+            name: registration
+                .hostname
+                .clone()
+                .unwrap_or_else(|| self.address.to_string()),
+            web_address: registration
+                .hostname
+                .clone()
+                .unwrap_or_else(|| self.address.to_string()),
+            provider: None,
+            // token сервера — его ключ -e, которым подписан SIGNEDRESULT; клиентам его не отдаём
+            secret_key: String::new(),
+            city: String::new(),
+            email: None,
+            company: None,
+            expiration: None,
+            ip_address: Some(self.address.to_string()),
+            comment: None,
+            countries: None,
+            location: Location {
+                latitude: 0.0,
+                longitude: 0.0,
+            },
+            distance: 0.0,
+            server_type_details: vec![ServerTypeDetail {
+                server_type: "RMBT".to_string(),
+                port: registration.tcp_port,
+                // 0: TLS не настроен
+                port_ssl: registration.tls_port.unwrap_or(0),
+                encrypted: registration.tls_port.is_some(),
+            }],
+            dedicated: false,
+            ip_v4_support: registration.ip_v4_support,
+            ip_v6_support: registration.ip_v6_support,
+            version: registration.version.clone(),
+            on_net: false,
+        }
+    }
+}
+
+/// Load reported in a server heartbeat; servers before 2.0 only send "message".
+#[derive(Debug, Default, Deserialize)]
+pub struct Heartbeat {
+    #[serde(rename = "tcpPort")]
+    pub tcp_port: Option<i32>,
+    #[serde(rename = "activeTests")]
+    pub active_tests: Option<usize>,
+    pub capacity: Option<usize>,
+}
+
+/// Body of a deregistration; servers before 2.0 send none.
+#[derive(Debug, Default, Deserialize)]
+pub struct Deregistration {
+    #[serde(rename = "tcpPort")]
+    pub tcp_port: Option<i32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreData {
+    next_id: i32,
+    servers: Vec<RegisteredServer>,
+}
+
+/// Registered servers in a JSON file, rewritten when a server registers or
+/// deregisters; heartbeats only update memory. Measurements are appended to
+/// a JSON Lines file next to it.
+pub struct Store {
+    path: PathBuf,
+    measurements_path: PathBuf,
+    data: StoreData,
+    measurement_count: usize,
+}
+
+impl Store {
+    /// Loads the store; missing files start an empty one.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let data = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StoreData::default(),
+            Err(e) => return Err(e),
+        };
+        let measurements_path = measurements_path(path);
+        let measurement_count = match fs::File::open(&measurements_path) {
+            Ok(file) => BufReader::new(file).lines().count(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Store {
+            path: path.to_path_buf(),
+            measurements_path,
+            data,
+            measurement_count,
+        })
+    }
+
+    pub fn servers(&self) -> &[RegisteredServer] {
+        &self.data.servers
+    }
+
+    pub fn measurement_count(&self) -> usize {
+        self.measurement_count
+    }
+
+    pub fn measurements_path(&self) -> &Path {
+        &self.measurements_path
+    }
+
+    /// Registers a server, replacing an earlier registration from the same address and port.
+    pub fn register(
+        &mut self,
+        address: IpAddr,
+        registration: AutoMeasurementServerRegistrationRequest,
+        now: u64,
+    ) -> io::Result<&RegisteredServer> {
+        let tcp_port = Some(registration.tcp_port);
+        let index = match self.data.servers.iter().position(|server| server.matches(address, tcp_port)) {
+            Some(index) => index,
+            None => {
+                self.data.next_id += 1;
+                self.data.servers.push(RegisteredServer {
+                    id: self.data.next_id,
+                    uuid: Uuid::new_v4().to_string(),
+                    address,
+                    registration: registration.clone(),
+                    registered_at: now,
+                    last_seen: now,
+                    active_tests: None,
+                    capacity: None,
+                });
+                self.data.servers.len() - 1
+            }
+        };
+        let server = &mut self.data.servers[index];
+        server.registration = registration;
+        server.registered_at = now;
+        server.last_seen = now;
+        self.persist()?;
+        Ok(&self.data.servers[index])
+    }
+
+    /// Records a heartbeat in memory. Returns false for an unknown server,
+    /// which is told to register again.
+    pub fn ping(&mut self, address: IpAddr, heartbeat: Heartbeat, now: u64) -> bool {
+        let Some(server) = self.data.servers.iter_mut().find(|server| server.matches(address, heartbeat.tcp_port)) else {
+            return false;
+        };
+        server.last_seen = now;
+        server.active_tests = heartbeat.active_tests;
+        server.capacity = heartbeat.capacity;
+        true
+    }
+
+    pub fn deregister(&mut self, address: IpAddr, tcp_port: Option<i32>) -> io::Result<bool> {
+        let before = self.data.servers.len();
+        self.data.servers.retain(|server| !server.matches(address, tcp_port));
+        if self.data.servers.len() == before {
+            return Ok(false);
+        }
+        self.persist()?;
+        Ok(true)
+    }
+
+    /// Servers a client may be sent to: recently seen and not at capacity.
+    pub fn available_servers(&self, now: u64) -> Vec<MeasurementServer> {
+        self.data
+            .servers
+            .iter()
+            .filter(|server| now.saturating_sub(server.last_seen) <= STALE_AFTER_SECS && !server.is_full())
+            .map(RegisteredServer::to_measurement_server)
+            .collect()
+    }
+
+    pub fn save_measurement(&mut self, measurement: serde_json::Value) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&measurement)?;
+        line.push(b'\n');
+        // Одна запись за write, чтобы строки не перемешались
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.measurements_path)?
+            .write_all(&line)?;
+        self.measurement_count += 1;
+        Ok(())
+    }
+
+    // Пишем во временный файл и переименовываем, чтобы не оставить половину JSON
+    fn persist(&self) -> io::Result<()> {
+        let content = serde_json::to_vec_pretty(&self.data)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// control.json -> control.measurements.jsonl
+fn measurements_path(path: &Path) -> PathBuf {
+    path.with_extension("measurements.jsonl")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(tcp_port: i32) -> AutoMeasurementServerRegistrationRequest {
+        AutoMeasurementServerRegistrationRequest {
+            token: Some("secret".to_string()),
+            tls_port: None,
+            tcp_port,
+            version: Some("2.0.0".to_string()),
+            hostname: None,
+            ip_v4_support: true,
+            ip_v6_support: false,
+        }
+    }
+
+    #[test]
+    fn test_store_persists_servers_and_measurements() {
+        let path = std::env::temp_dir().join(format!("nettest-control-{}.json", Uuid::new_v4()));
+        let address: IpAddr = "192.0.2.10".parse().unwrap();
+        {
+            let mut store = Store::open(&path).unwrap();
+            assert_eq!(store.register(address, registration(5005), 100).unwrap().id, 1);
+            // Повторная регистрация с того же адреса и порта обновляет запись
+            assert_eq!(store.register(address, registration(5005), 110).unwrap().id, 1);
+            // Второй сервер на том же хосте
+            assert_eq!(store.register(address, registration(5006), 110).unwrap().id, 2);
+            assert!(store.ping(address, Heartbeat { tcp_port: Some(5006), ..Heartbeat::default() }, 115));
+            store.save_measurement(serde_json::json!({"speedDownload": 100})).unwrap();
+            store.save_measurement(serde_json::json!({"speedDownload": 200})).unwrap();
+        }
+        let measurements_path = measurements_path(&path);
+        assert_eq!(fs::read_to_string(&measurements_path).unwrap().lines().count(), 2);
+
+        let mut store = Store::open(&path).unwrap();
+        assert_eq!(store.servers().len(), 2);
+        // Heartbeat не пишется на диск
+        assert_eq!(store.servers()[1].last_seen, 110);
+        assert_eq!(store.measurement_count(), 2);
+
+        let servers = store.available_servers(120);
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].ip_address.as_deref(), Some("192.0.2.10"));
+        assert_eq!(servers[1].server_type_details[0].port, 5006);
+        assert!(store.available_servers(110 + STALE_AFTER_SECS + 1).is_empty());
+
+        let full = Heartbeat {
+            tcp_port: Some(5005),
+            active_tests: Some(8),
+            capacity: Some(8),
+        };
+        assert!(store.ping(address, full, 130));
+        assert_eq!(store.available_servers(130).len(), 1);
+        assert!(!store.ping(address, Heartbeat { tcp_port: Some(5007), ..Heartbeat::default() }, 130));
+        assert!(!store.ping("192.0.2.11".parse().unwrap(), Heartbeat::default(), 130));
+
+        assert!(store.deregister(address, Some(5005)).unwrap());
+        assert_eq!(store.servers().len(), 1);
+        assert_eq!(store.servers()[0].registration.tcp_port, 5006);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&measurements_path).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use log::Level;
+
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+// SYSLOG_IDENTIFIER: journalctl -t nettest
+pub const IDENTIFIER: &str = "nettest";
+// LOG_DAEMON
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Syslog severity of a log level, also used as the journald PRIORITY field.
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Unix datagram socket of a local log daemon. Each record is sent to the path
+/// again, so a restarted daemon is picked up without reconnecting.
+pub struct LogSocket {
+    socket: UnixDatagram,
+    path: &'static str,
+}
+
+impl LogSocket {
+    /// Fails if nothing listens on `path`, so a missing journald shows up at startup.
+    pub fn connect(path: &'static str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        Ok(LogSocket { socket, path })
+    }
+
+    pub fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        self.socket.send_to(datagram, Path::new(self.path)).map(|_| ())
+    }
+}
+
+/// Datagram in the journald native protocol. Values with a newline use the
+/// binary form: name, newline, little-endian length, value.
+pub fn journald_datagram(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    for (name, value) in fields {
+        datagram.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+    datagram
+}
+
+/// RFC 3164 style line for /dev/log; the daemon adds time and host.
+pub fn syslog_datagram(level: Level, message: &str) -> Vec<u8> {
+    format!(
+        "<{}>{}[{}]: {}",
+        SYSLOG_FACILITY * 8 + severity(level),
+        IDENTIFIER,
+        std::process::id(),
+        message
+    )
+    .into_bytes()
+}
+
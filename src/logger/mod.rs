@@ -1,52 +1,251 @@
-use chrono::Local;
+use chrono::{Local, SecondsFormat};
 use libc::{c_int, signal, SIGHUP};
 use log::{LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::cell::RefCell;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+pub mod journal;
+
+use journal::LogSocket;
+
+/// Line format for the log file, stdout and syslog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // Одна JSON-запись на строку, с модулем, воркером, токеном и сессией
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!("invalid log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+/// Where `init_logger` sends records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogOutput {
+    #[default]
+    File,
+    // Только stdout, без файла
+    Stdout,
+    Journald,
+    Syslog,
+}
+
+impl FromStr for LogOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(LogOutput::File),
+            "stdout" => Ok(LogOutput::Stdout),
+            "journald" => Ok(LogOutput::Journald),
+            "syslog" => Ok(LogOutput::Syslog),
+            _ => Err(anyhow::anyhow!("invalid log output '{}', expected file, stdout, journald or syslog", s)),
+        }
+    }
+}
+
+/// Logging settings from the config file, applied by `init_logger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    pub output: LogOutput,
+    // None: копия в stdout только при записи в файл, journald и syslog и так её соберут
+    pub stdout: Option<bool>,
+}
+
+enum LogSink {
+    File(LogFile),
+    // Записи идут только в копию в stdout
+    Stdout,
+    Journald(LogSocket),
+    Syslog(LogSocket),
+}
+
+// Уровень не хранится в логгере: это log::max_level, его меняет set_level
 pub struct FileLogger {
-    level: LevelFilter,
-    log_file: Arc<Mutex<File>>,
+    sink: LogSink,
+    format: LogFormat,
 }
 
-impl FileLogger {
-    pub fn new(level: LevelFilter, log_path: &Path) -> Result<Self, std::io::Error> {
+// Копия записей в stdout; демон её отключает
+static STDOUT_COPY: AtomicBool = AtomicBool::new(true);
+// Контекст соединения нужен только JSON и journald
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+/// Worker and connection of the records a thread is logging.
+#[derive(Debug, Clone, Default)]
+struct LogContext {
+    worker: Option<usize>,
+    token: Option<usize>,
+    session_id: Option<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Tags every record logged from this thread with a worker ID.
+pub fn set_worker(id: usize) {
+    CONTEXT.with(|context| context.borrow_mut().worker = Some(id));
+}
+
+/// Tags records logged from this thread with a connection until the guard is dropped.
+pub fn enter_connection(token: usize, session_id: Option<&str>) -> ConnectionContext {
+    if STRUCTURED.load(Ordering::Relaxed) {
+        CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            context.token = Some(token);
+            context.session_id = session_id.map(String::from);
+        });
+    }
+    ConnectionContext { _private: () }
+}
+
+#[must_use]
+pub struct ConnectionContext {
+    _private: (),
+}
+
+impl Drop for ConnectionContext {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            context.token = None;
+            context.session_id = None;
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    module: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<&'a str>,
+}
+
+// Увеличивается обработчиком SIGHUP; каждый LogFile переоткрывается при следующей записи
+static REOPEN_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Append-only file that follows logrotate: after SIGHUP the next write
+/// reopens it by path. If reopening fails, writes go to the old file.
+pub struct LogFile {
+    path: PathBuf,
+    file: Mutex<(File, usize)>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
         // Create directory if it doesn't exist
-        if let Some(parent) = log_path.parent() {
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
-
-        let log_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path)?;
-
+        let generation = REOPEN_GENERATION.load(Ordering::Relaxed);
         Ok(Self {
-            level,
-            log_file: Arc::new(Mutex::new(log_file)),
+            path: path.to_path_buf(),
+            file: Mutex::new((Self::open_append(path)?, generation)),
         })
     }
 
-    pub fn reopen_log_file(&self, log_path: &Path) -> Result<(), std::io::Error> {
-        let mut log_file = self.log_file.lock().unwrap();
-        *log_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(log_path)?;
-        Ok(())
+    fn open_append(path: &Path) -> Result<File, std::io::Error> {
+        OpenOptions::new().create(true).append(true).open(path)
     }
 
-    fn format_log(&self, record: &Record) -> String {
-        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
-        format!("{} [{}] - {}\n", timestamp, record.level(), record.args())
+    pub fn path(&self) -> &Path {
+        &self.path
     }
+
+    pub fn write_all(&self, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut file = self.file.lock().unwrap();
+        let generation = REOPEN_GENERATION.load(Ordering::Relaxed);
+        if file.1 != generation {
+            file.1 = generation;
+            if let Ok(reopened) = Self::open_append(&self.path) {
+                file.0 = reopened;
+            }
+        }
+        file.0.write_all(data)?;
+        file.0.flush()
+    }
+}
+
+impl FileLogger {
+    fn format_log(&self, record: &Record, context: &LogContext) -> String {
+        match self.format {
+            LogFormat::Text => {
+                let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+                format!("{} [{}] - {}\n", timestamp, record.level(), record.args())
+            }
+            LogFormat::Json => json_line(record, context),
+        }
+    }
+}
+
+fn json_line(record: &Record, context: &LogContext) -> String {
+    let json = JsonRecord {
+        timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        level: record.level().as_str(),
+        module: record.module_path().unwrap_or(record.target()),
+        message: record.args().to_string(),
+        worker: context.worker,
+        token: context.token,
+        session_id: context.session_id.as_deref(),
+    };
+    let mut line = serde_json::to_string(&json).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// Fields of a record in the journald native protocol; the message stays
+/// plain text there, the structure goes into separate fields.
+fn journald_record(record: &Record, context: &LogContext) -> Vec<u8> {
+    let message = record.args().to_string();
+    let priority = journal::severity(record.level()).to_string();
+    let line = record.line().map(|line| line.to_string());
+    let worker = context.worker.map(|worker| worker.to_string());
+    let token = context.token.map(|token| token.to_string());
+    let mut fields = vec![
+        ("MESSAGE", message.as_str()),
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", journal::IDENTIFIER),
+        ("CODE_MODULE", record.module_path().unwrap_or(record.target())),
+    ];
+    let optional = [
+        ("CODE_FILE", record.file()),
+        ("CODE_LINE", line.as_deref()),
+        ("NETTEST_WORKER", worker.as_deref()),
+        ("NETTEST_TOKEN", token.as_deref()),
+        ("NETTEST_SESSION_ID", context.session_id.as_deref()),
+    ];
+    fields.extend(optional.into_iter().filter_map(|(name, value)| value.map(|value| (name, value))));
+    journal::journald_datagram(&fields)
 }
 
 impl Log for FileLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
@@ -54,60 +253,121 @@ impl Log for FileLogger {
             return;
         }
 
-        let message = self.format_log(record);
+        let context = if STRUCTURED.load(Ordering::Relaxed) {
+            CONTEXT.with(|context| context.borrow().clone())
+        } else {
+            LogContext::default()
+        };
+        let message = self.format_log(record, &context);
 
-        // Write to file
-        if let Ok(mut file) = self.log_file.lock() {
-            let _ = file.write_all(message.as_bytes());
-            let _ = file.flush();
-        }
+        let _ = match &self.sink {
+            LogSink::File(log_file) => log_file.write_all(message.as_bytes()),
+            LogSink::Stdout => Ok(()),
+            LogSink::Journald(socket) => socket.send(&journald_record(record, &context)),
+            LogSink::Syslog(socket) => {
+                // Время и хост добавит syslog
+                let body = match self.format {
+                    LogFormat::Text => record.args().to_string(),
+                    LogFormat::Json => message.trim_end().to_string(),
+                };
+                socket.send(&journal::syslog_datagram(record.level(), &body))
+            }
+        };
 
-        // Write to stdout
-        let _ = io::stdout().write_all(message.as_bytes());
-        let _ = io::stdout().flush();
+        if STDOUT_COPY.load(Ordering::Relaxed) {
+            let _ = io::stdout().write_all(message.as_bytes());
+            let _ = io::stdout().flush();
+        }
     }
 
     fn flush(&self) {
-        if let Ok(mut file) = self.log_file.lock() {
-            let _ = file.flush();
-        }
         let _ = io::stdout().flush();
     }
 }
 
 extern "C" fn handle_sighup(_: c_int) {
-    // This will be called when logrotate sends SIGHUP
-    if let Some(logger) = LOGGER.lock().ok().and_then(|l| l.as_ref().cloned()) {
-        let log_path = if cfg!(target_os = "macos") {
-            let home = std::env::var("HOME").unwrap_or_else(|_| "/Users/root".to_string());
-            Path::new(&home).join("Library/Logs/rmbt/nettest.log")
-        } else {
-            Path::new("/var/log/rmbt/nettest.log").to_path_buf()
-        };
-        let _ = logger.reopen_log_file(&log_path);
+    // This will be called when logrotate sends SIGHUP. Only async-signal-safe
+    // work is allowed here, so the file is reopened by the next log call.
+    REOPEN_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs the SIGHUP handler that makes every `LogFile` reopen itself.
+pub fn install_reopen_handler() {
+    unsafe {
+        signal(SIGHUP, handle_sighup as usize);
     }
 }
 
 lazy_static::lazy_static! {
     static ref LOGGER: Mutex<Option<Arc<FileLogger>>> = Mutex::new(None);
+    static ref OPTIONS: Mutex<LogOptions> = Mutex::new(LogOptions::default());
+}
+
+/// Sets format and output for a later `init_logger`.
+pub fn set_options(options: LogOptions) {
+    *OPTIONS.lock().unwrap() = options;
+}
+
+/// Stops copying records to stdout, e.g. before the server detaches from its terminal.
+pub fn disable_stdout() {
+    STDOUT_COPY.store(false, Ordering::Relaxed);
 }
 
 pub fn init_logger(level: LevelFilter) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let options = *OPTIONS.lock().unwrap();
+    let sink = match options.output {
+        LogOutput::File => LogSink::File(LogFile::open(&default_log_path()?)?),
+        LogOutput::Stdout => LogSink::Stdout,
+        LogOutput::Journald => LogSink::Journald(LogSocket::connect(journal::JOURNALD_SOCKET)?),
+        LogOutput::Syslog => LogSink::Syslog(LogSocket::connect(journal::SYSLOG_SOCKET)?),
+    };
+    STDOUT_COPY.store(
+        options.output == LogOutput::Stdout || options.stdout.unwrap_or(options.output == LogOutput::File),
+        Ordering::Relaxed,
+    );
+    STRUCTURED.store(
+        options.format == LogFormat::Json || options.output == LogOutput::Journald,
+        Ordering::Relaxed,
+    );
+
+    // Create logger
+    let logger = Arc::new(FileLogger {
+        sink,
+        format: options.format,
+    });
+
+    // Store logger in global state
+    *LOGGER.lock().unwrap() = Some(logger.clone());
+
+    // Set up SIGHUP handler for log rotation
+    install_reopen_handler();
+
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+/// `init_logger` for tools that must run without root: if the log file
+/// cannot be opened, records go to stdout only instead of failing.
+pub fn init_logger_or_stdout(level: LevelFilter) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match init_logger(level) {
+        Err(e) if e.is::<std::io::Error>() && OPTIONS.lock().unwrap().output == LogOutput::File => {
+            eprintln!("Cannot open the log file ({}), logging to stdout only", e);
+            OPTIONS.lock().unwrap().output = LogOutput::Stdout;
+            init_logger(level)
+        }
+        result => result,
+    }
+}
+
+fn default_log_path() -> Result<PathBuf, std::io::Error> {
     // Create log directory if it doesn't exist
     let log_dir = if cfg!(target_os = "macos") {
         // On macOS, use ~/Library/Logs/rmbt
         let home = std::env::var("HOME").unwrap_or_else(|_| "/Users/root".to_string());
         Path::new(&home).join("Library/Logs/nettest")
     } else {
-        // Create PID file
-        let pid = std::process::id();
-        let pid_dir = Path::new("/run");
-        if !pid_dir.exists() {
-            std::fs::create_dir_all(pid_dir)?;
-        }
-        let pid_path = pid_dir.join("nettest.pid");
-        std::fs::write(&pid_path, pid.to_string())?;
-        log::info!("PID file location: {}", pid_path.display());
         Path::new("/var/log/nettest").to_path_buf()
     };
 
@@ -116,31 +376,70 @@ pub fn init_logger(level: LevelFilter) -> Result<(), Box<dyn std::error::Error +
         fs::create_dir_all(&log_dir)?;
     }
 
-    // Ensure we have write permissions
-    let log_path = log_dir.join("nettest.log");
-
-    // Try to create the log file with proper permissions
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_path)?;
+    Ok(log_dir.join("nettest.log"))
+}
 
-    // Create logger
-    let logger = Arc::new(FileLogger {
-        level,
-        log_file: Arc::new(Mutex::new(log_file)),
-    });
+/// Changes the level of the logger set up by `init_logger`. Returns false if
+/// logging was never enabled: the logger is only installed at startup.
+pub fn set_level(level: LevelFilter) -> bool {
+    if LOGGER.lock().unwrap().is_none() {
+        return false;
+    }
+    log::set_max_level(level);
+    true
+}
 
-    // Store logger in global state
-    *LOGGER.lock().unwrap() = Some(logger.clone());
+/// Whether `init_logger` has run.
+pub fn is_enabled() -> bool {
+    LOGGER.lock().unwrap().is_some()
+}
 
-    // Set up SIGHUP handler for log rotation
-    unsafe {
-        signal(SIGHUP, handle_sighup as usize);
+/// Path of the log file opened by `init_logger`, if logging goes to a file.
+pub fn log_file_path() -> Option<PathBuf> {
+    match LOGGER.lock().unwrap().as_ref().map(|logger| &logger.sink) {
+        Some(LogSink::File(log_file)) => Some(log_file.path().to_path_buf()),
+        _ => None,
     }
+}
 
-    log::set_boxed_logger(Box::new(logger))?;
-    log::set_max_level(level);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn test_json_line_has_context() {
+        let context = LogContext {
+            worker: Some(3),
+            token: Some(17),
+            session_id: Some("c0ffee".to_string()),
+        };
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("handshake done"))
+                .level(log::Level::Debug)
+                .module_path(Some("nettest::mioserver::worker"))
+                .build(),
+            &context,
+        );
+        assert!(line.ends_with('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "DEBUG");
+        assert_eq!(json["module"], "nettest::mioserver::worker");
+        assert_eq!(json["message"], "handshake done");
+        assert_eq!(json["worker"], 3);
+        assert_eq!(json["token"], 17);
+        assert_eq!(json["session_id"], "c0ffee");
+
+        let line = json_line(&Record::builder().args(format_args!("idle")).build(), &LogContext::default());
+        assert!(!line.contains("worker") && !line.contains("session_id"));
+    }
+
+    #[test]
+    fn test_journald_datagram_multiline_value() {
+        let datagram = journal::journald_datagram(&[("PRIORITY", "6"), ("MESSAGE", "a\nb")]);
+        let mut expected = b"PRIORITY=6\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(datagram, expected);
+    }
 }
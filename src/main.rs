@@ -1,10 +1,21 @@
-use log::{debug, info};
-use tokio::signal;
+#[cfg(feature = "legacy-tokio-server")]
+use log::debug;
+#[cfg(any(feature = "server", feature = "legacy-tokio-server"))]
+use log::info;
+#[cfg(feature = "server")]
+use tokio::signal::{self, unix::SignalKind};
 
-use crate::config::parser::{ read_config_file};
+use crate::config::parser::{read_config_file, take_config_flag};
+use crate::config::FileConfig;
+#[cfg(feature = "server")]
+use crate::mioserver::reload::{self, Reloader};
+#[cfg(feature = "server")]
 use crate::mioserver::MioServer;
+#[cfg(feature = "legacy-tokio-server")]
 use crate::tokio_server::server::Server;
+#[cfg(feature = "legacy-tokio-server")]
 use crate::tokio_server::server_config::RmbtServerConfig;
+#[cfg(feature = "legacy-tokio-server")]
 use crate::tokio_server::utils::random_buffer;
 use std::error::Error as StdError;
 
@@ -15,67 +26,148 @@ pub mod stream;
 pub mod tokio_server;
 
 pub mod client;
+#[cfg(feature = "control-server")]
+pub mod control_server;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn StdError + Send + Sync>> {
+fn main() -> Result<(), Box<dyn StdError + Send + Sync>> {
     let mut args: Vec<String> = std::env::args().collect();
 
-    let config = read_config_file();
+    let config_path = take_config_flag(&mut args).map_err(|e| e.to_string())?;
+    let config = read_config_file(config_path.clone()).map_err(|e| e.to_string())?;
+    logger::set_options(config.log_options);
 
-    if  args.len() == 1 || args[1] == "-c" {
-        args = args.iter().skip(1).map(|s| s.clone()).collect();
-        client::client::client_run(args, config).await?;
-        return Ok(());
-    } else if args[1] == "-s" {
-        println!("args: {:?}", args);
-        args = args.iter().skip(1).map(|s| s.clone()).collect();
-
-        let mut mio_server = MioServer::new(args, config)?;
-        
-        // Создаем отдельный поток для обработки сигналов
-        let shutdown_signal = mio_server.get_shutdown_signal();
-        tokio::spawn(async move {
-            signal::ctrl_c().await.expect("Failed to listen for Ctrl+C");
-            info!("Ctrl+C received, shutting down server...");
-            shutdown_signal.store(true, std::sync::atomic::Ordering::Relaxed);
-        });
-        
-        mio_server.run()?;
-        info!("Server stopping...");
-        mio_server.shutdown().await?;
-        info!("Server stopped");
-    } else {
-
-        args = args.iter().skip(1).map(|s| s.clone()).collect();
-        let config = match RmbtServerConfig::from_args(args) {
-            Ok(config) => config,
-            Err(e) => {
-                eprintln!("Failed to parse configuration: {}", e);
-                return Err(e);
-            }
-        };       
+    if args.len() > 1 && args[1] == "-s" {
+        #[cfg(feature = "server")]
+        {
+            println!("args: {:?}", args);
+            let args = args.iter().skip(1).map(|s| s.clone()).collect();
 
+            // MioServer форкается и сбрасывает права до запуска tokio runtime:
+            // потоки runtime не переживают fork
+            let mio_server = MioServer::new(args, config)?;
+            let reloader = mio_server.reloader(config_path);
+            return tokio::runtime::Runtime::new()?.block_on(run_mio_server(mio_server, reloader));
+        }
+        #[cfg(not(feature = "server"))]
+        return Err(mode_not_built("Server mode (-s)", "server"));
+    }
 
-        random_buffer::init_random_buffer();
-        let buf = random_buffer::get_random_buffer();
-        debug!("First 10 bytes: {:?}", &buf[..10]);
-        debug!("Last 10 bytes: {:?}", &buf[buf.len() - 10..]);
+    if args.len() > 1 && args[1] == "--control" {
+        #[cfg(feature = "control-server")]
+        return tokio::runtime::Runtime::new()?
+            .block_on(control_server::run_control_server(&args[2..]))
+            .map_err(|e| e.into());
+        #[cfg(not(feature = "control-server"))]
+        return Err(mode_not_built("Control server mode (--control)", "control-server"));
+    }
 
-        let (server, _shutdown_tx) = match Server::new(config) {
-            Ok(server) => server,
-            Err(e) => {
-                // error!("Failed to initialize server: {}", e);
-                return Err(e);
-            }
-        };
+    tokio::runtime::Runtime::new()?.block_on(async_main(args, config))
+}
 
-        info!("Starting server...");
-        server.run().await?;
-        info!("Server stopped");
+/// Error for a mode that was left out of this build.
+#[allow(dead_code)]
+fn mode_not_built(mode: &str, feature: &str) -> Box<dyn StdError + Send + Sync> {
+    format!(
+        "{} is not available: nettest was built without the \"{}\" cargo feature",
+        mode, feature
+    )
+    .into()
+}
 
-        
+#[cfg(feature = "server")]
+async fn run_mio_server(mut mio_server: MioServer, reloader: Reloader) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    // Создаем отдельный поток для обработки сигналов
+    let shutdown_signal = mio_server.get_shutdown_signal();
+    let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = signal::ctrl_c() => info!("Ctrl+C received, draining server..."),
+            _ = sigterm.recv() => info!("SIGTERM received, draining server..."),
+        }
+        shutdown_signal.store(true, std::sync::atomic::Ordering::Relaxed);
 
-    }
+        // Повторный сигнал прерывает ожидание активных тестов
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        info!("Second signal received, exiting without waiting for tests");
+        std::process::exit(1);
+    });
+    
+    // SIGHUP перечитывает TLS сертификат и конфиг без перезапуска; лог
+    // переоткрывает обработчик логгера, signal-hook вызывает его тоже
+    let tls_config = mio_server.get_tls_config();
+    let mut sighup = signal::unix::signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("SIGHUP received, reloading configuration...");
+            if let Some(tls_config) = &tls_config {
+                if let Err(e) = tls_config.reload() {
+                    info!("Keeping previous TLS certificate, reload failed: {}", e);
+                }
+            }
+            if let Err(e) = reloader.reload() {
+                reload::report(&format!("Keeping previous configuration, reload failed: {}", e));
+            }
+        }
+    });
+
+    mio_server.run()?;
+    info!("Server stopping...");
+    mio_server.shutdown().await?;
+    info!("Server stopped");
     Ok(())
+}
+
+async fn async_main(args: Vec<String>, config: FileConfig) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    if  args.len() == 1 || args[1] == "-c" {
+        #[cfg(feature = "client")]
+        {
+            let args = args.iter().skip(1).map(|s| s.clone()).collect();
+            client::client::client_run(args, config).await?;
+            return Ok(());
+        }
+        #[cfg(not(feature = "client"))]
+        {
+            let _ = config;
+            return Err(mode_not_built("Client mode (-c)", "client"));
+        }
+    }
+
+    #[cfg(feature = "legacy-tokio-server")]
+    return run_tokio_server(args).await;
+    #[cfg(not(feature = "legacy-tokio-server"))]
+    Err(mode_not_built("The legacy tokio server", "legacy-tokio-server"))
+}
 
+#[cfg(feature = "legacy-tokio-server")]
+async fn run_tokio_server(args: Vec<String>) -> Result<(), Box<dyn StdError + Send + Sync>> {
+    let args: Vec<String> = args.iter().skip(1).map(|s| s.clone()).collect();
+    let config = match RmbtServerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to parse configuration: {}", e);
+            return Err(e);
+        }
+    };       
+
+
+    random_buffer::init_random_buffer();
+    let buf = random_buffer::get_random_buffer();
+    debug!("First 10 bytes: {:?}", &buf[..10]);
+    debug!("Last 10 bytes: {:?}", &buf[buf.len() - 10..]);
+
+    let (server, _shutdown_tx) = match Server::new(config) {
+        Ok(server) => server,
+        Err(e) => {
+            // error!("Failed to initialize server: {}", e);
+            return Err(e);
+        }
+    };
+
+    info!("Starting server...");
+    server.run().await?;
+    info!("Server stopped");
+    Ok(())
 }
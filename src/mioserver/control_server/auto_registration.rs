@@ -1,33 +1,97 @@
-use crate::mioserver::server::ServerConfig;
+use crate::mioserver::metrics::METRICS;
+use crate::mioserver::reload::RuntimeConfigStore;
+use crate::mioserver::server::{AcceptedConnection, IpFamilies, ServerConfig};
 use anyhow::Result;
-use log::info;
-use serde::Serialize;
+use log::{debug, info};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use tokio::time::{interval, Duration};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::time::{interval, sleep, Duration, MissedTickBehavior};
 
-#[derive(Debug, Serialize)]
-struct AutoMeasurementServerRegistrationRequest {
-    token: Option<String>,
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoMeasurementServerRegistrationRequest {
+    pub token: Option<String>,
     #[serde(rename = "tlsPort")]
-    tls_port: Option<i32>,
+    pub tls_port: Option<i32>,
     #[serde(rename = "tcpPort")]
-    tcp_port: i32,
-    version: Option<String>,
-    hostname: Option<String>,
+    pub tcp_port: i32,
+    pub version: Option<String>,
+    pub hostname: Option<String>,
+    #[serde(rename = "ipV4Support")]
+    pub ip_v4_support: bool,
+    #[serde(rename = "ipV6Support")]
+    pub ip_v6_support: bool,
 }
 
+/// Heartbeat with the server's current load; "message" stays "PING" for
+/// control servers that only check that field.
 #[derive(Debug, Serialize)]
 struct PingRequest {
-    message: String,
+    message: &'static str,
+    // Вместе с адресом отличает сервер от соседей на том же хосте
+    #[serde(rename = "tcpPort")]
+    tcp_port: i32,
+    #[serde(rename = "activeTests")]
+    active_tests: usize,
+    #[serde(rename = "queuedConnections")]
+    queued_connections: usize,
+    capacity: usize,
+    // Средняя скорость с прошлого heartbeat, бит/с
+    #[serde(rename = "downloadBps")]
+    download_bps: u64,
+    #[serde(rename = "uploadBps")]
+    upload_bps: u64,
+    #[serde(rename = "uptimeSeconds")]
+    uptime_seconds: u64,
 }
 
-pub async fn register_server(config: &ServerConfig) -> Result<()> {
+/// What the control server said about a heartbeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingOutcome {
+    Alive,
+    // 404/401: сервер забыт, нужно зарегистрироваться заново
+    Unregistered,
+}
+
+/// Shared counters heartbeats read the load from.
+#[derive(Clone)]
+pub struct ServerLoad {
+    pub worker_connection_counts: Arc<Mutex<Vec<usize>>>,
+    pub global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>,
+    pub tests_per_worker: usize,
+}
+
+/// Exponential backoff with jitter: 1 s, 2 s, 4 s ... up to 5 min, each
+/// delay drawn from its upper half so restarted servers do not retry in step.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = BACKOFF_BASE
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(BACKOFF_MAX);
+        self.attempt += 1;
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(fastrand::u64(millis / 2..=millis))
+    }
+}
+
+pub async fn register_server(config: &ServerConfig, ip_families: IpFamilies) -> Result<()> {
     let control_server_url = config.control_server.clone();
 
     //if hostname isSome and keyPath is some and certPath is some then tlsPort is Some(tls_address.port()) else tlsPort is None
     let tls_port = if config.hostname.is_some() && config.key_path.is_some() && config.cert_path.is_some() {
-        Some(config.tls_address.port() as i32)
+        config.tls_addresses.first().map(|addr| addr.port() as i32)
     } else {
         None
     };
@@ -35,9 +99,11 @@ pub async fn register_server(config: &ServerConfig) -> Result<()> {
     let request = AutoMeasurementServerRegistrationRequest {
         token: config.secret_key.clone(),
         tls_port,
-        tcp_port: config.tcp_address.port() as i32,
+        tcp_port: registered_tcp_port(config),
         version: config.version.clone(),
         hostname: config.hostname.clone(),
+        ip_v4_support: ip_families.ipv4,
+        ip_v6_support: ip_families.ipv6,
     };
     info!("Registering server with control server json: {:?}", serde_json::to_string(&request).unwrap());
     
@@ -67,25 +133,27 @@ pub async fn register_server(config: &ServerConfig) -> Result<()> {
     Ok(())
 }
 
-pub async fn ping_server(config: &ServerConfig) -> Result<()> {
-    let control_server_url = config.control_server.clone();
-    
-    let request = PingRequest {
-        message: "PING".to_string(),
-    };
-    
-    let client = reqwest::Client::new();
-    let url = format!("{}/measurementServer/ping", control_server_url);
-    
+/// TCP port announced on registration; ping and deregister repeat it.
+fn registered_tcp_port(config: &ServerConfig) -> i32 {
+    config.tcp_addresses.first().map_or(5005, |addr| addr.port()) as i32
+}
+
+async fn ping_server(client: &reqwest::Client, config: &ServerConfig, request: &PingRequest) -> Result<PingOutcome> {
+    let url = format!("{}/measurementServer/ping", config.control_server);
+
     let response = client
         .post(&url)
         .header("x-nettest-client", config.x_nettest_client.clone())
         .header("Content-Type", "application/json")
-        .json(&request)
+        .json(request)
         .send()
         .await?;
-    
+
     let status = response.status();
+    if status == StatusCode::NOT_FOUND || status == StatusCode::UNAUTHORIZED {
+        info!("Control server answered ping with {}", status);
+        return Ok(PingOutcome::Unregistered);
+    }
     if !status.is_success() {
         let error_text = response.text().await?;
         info!("Failed to ping server: {} - {}", status, error_text);
@@ -95,9 +163,9 @@ pub async fn ping_server(config: &ServerConfig) -> Result<()> {
             error_text
         ));
     }
-    
-    info!("Successfully pinged control server");
-    Ok(())
+
+    debug!("Successfully pinged control server");
+    Ok(PingOutcome::Alive)
 }
 
 pub async fn deregister_server(config: &ServerConfig) -> Result<()> {
@@ -112,6 +180,7 @@ pub async fn deregister_server(config: &ServerConfig) -> Result<()> {
         .delete(&url)
         .header("x-nettest-client", config.x_nettest_client.clone())
         .header("Content-Type", "application/json")
+        .json(&serde_json::json!({ "tcpPort": registered_tcp_port(config) }))
         .send()
         .await?;
     
@@ -129,27 +198,195 @@ pub async fn deregister_server(config: &ServerConfig) -> Result<()> {
     Ok(())
 }
 
-pub async fn start_ping_job(config: ServerConfig, shutdown_signal: Arc<AtomicBool>) {
-    let mut interval_timer = interval(Duration::from_secs(10));
-    
-    info!("Starting ping job - will ping control server every 10 seconds");
-    
+/// Registers the server, retrying with backoff until it succeeds, the server
+/// shuts down or a reload turns registration off. Every attempt uses the
+/// current settings; returns the ones the server registered with.
+async fn register_with_backoff(
+    runtime: &RuntimeConfigStore,
+    ip_families: IpFamilies,
+    shutdown_signal: &AtomicBool,
+) -> Option<ServerConfig> {
+    let mut backoff = Backoff::default();
     loop {
-        info!("Ping job loop");
-        // Проверяем сигнал завершения
+        let config = runtime.current().server_config.clone();
+        if !config.server_registration {
+            return None;
+        }
+        match register_server(&config, ip_families).await {
+            Ok(()) => return Some(config),
+            Err(e) => {
+                let delay = backoff.next_delay();
+                info!("Server registration failed: {}; retrying in {:?}", e, delay);
+                if !sleep_unless_shutdown(delay, shutdown_signal).await {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Whether the control server has to be told about a settings change.
+fn registration_changed(registered: &ServerConfig, current: &ServerConfig) -> bool {
+    registered.server_registration != current.server_registration
+        || registered.control_server != current.control_server
+        || registered.x_nettest_client != current.x_nettest_client
+        || registered.hostname != current.hostname
+        || registered.secret_key != current.secret_key
+}
+
+/// Sleeps for `duration`; returns false early if the server shuts down.
+async fn sleep_unless_shutdown(duration: Duration, shutdown_signal: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while !shutdown_signal.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        sleep(remaining.min(Duration::from_millis(500))).await;
+    }
+    false
+}
+
+/// Byte counters at the previous heartbeat, to report recent throughput.
+struct ThroughputMeter {
+    sent: u64,
+    received: u64,
+    at: Instant,
+}
+
+impl ThroughputMeter {
+    fn new() -> Self {
+        ThroughputMeter {
+            sent: METRICS.bytes_sent(),
+            received: METRICS.bytes_received(),
+            at: Instant::now(),
+        }
+    }
+
+    /// Download and upload rate in bit/s since the previous call.
+    fn sample(&mut self) -> (u64, u64) {
+        let (sent, received) = (METRICS.bytes_sent(), METRICS.bytes_received());
+        let seconds = self.at.elapsed().as_secs_f64().max(0.001);
+        let rates = (
+            ((sent - self.sent) as f64 * 8.0 / seconds) as u64,
+            ((received - self.received) as f64 * 8.0 / seconds) as u64,
+        );
+        *self = ThroughputMeter {
+            sent,
+            received,
+            at: Instant::now(),
+        };
+        rates
+    }
+}
+
+fn heartbeat(load: &ServerLoad, throughput: &mut ThroughputMeter, started: Instant, tcp_port: i32) -> PingRequest {
+    let (active_tests, workers) = {
+        let counts = load.worker_connection_counts.lock().unwrap();
+        (counts.iter().sum(), counts.len())
+    };
+    let (download_bps, upload_bps) = throughput.sample();
+    PingRequest {
+        message: "PING",
+        tcp_port,
+        active_tests,
+        queued_connections: load.global_queue.lock().unwrap().len(),
+        capacity: workers * load.tests_per_worker,
+        download_bps,
+        upload_bps,
+        uptime_seconds: started.elapsed().as_secs(),
+    }
+}
+
+/// Registers the server and then sends a heartbeat every 10 seconds. When the
+/// control server no longer knows the server (404/401), registers again. After
+/// a reload changed the registration settings, deregisters with the old ones
+/// and registers with the new ones, if registration is still enabled.
+pub async fn start_registration_job(
+    runtime: Arc<RuntimeConfigStore>,
+    ip_families: IpFamilies,
+    load: ServerLoad,
+    shutdown_signal: Arc<AtomicBool>,
+) {
+    let started = Instant::now();
+    let client = reqwest::Client::new();
+    let mut throughput = ThroughputMeter::new();
+    // Настройки, с которыми сервер сейчас зарегистрирован
+    let mut registered: Option<ServerConfig> = None;
+    let mut interval_timer = interval(PING_INTERVAL);
+    interval_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        // Первый tick срабатывает сразу и регистрирует сервер
+        interval_timer.tick().await;
         if shutdown_signal.load(Ordering::Relaxed) {
             info!("Ping job received shutdown signal, stopping...");
             break;
         }
-        
-        // Ждем следующего интервала
-        interval_timer.tick().await;
-        
-        // Отправляем ping
-        if let Err(e) = ping_server(&config).await {
-            info!("Ping job error: {}", e);
+
+        let config = runtime.current().server_config.clone();
+        match registered.take() {
+            Some(old) if !registration_changed(&old, &config) => {
+                let request = heartbeat(&load, &mut throughput, started, registered_tcp_port(&config));
+                match ping_server(&client, &config, &request).await {
+                    Ok(PingOutcome::Alive) => {
+                        registered = Some(old);
+                        continue;
+                    }
+                    Ok(PingOutcome::Unregistered) => {
+                        info!("Control server no longer knows this server, registering again");
+                    }
+                    Err(e) => {
+                        info!("Ping job error: {}", e);
+                        registered = Some(old);
+                        continue;
+                    }
+                }
+            }
+            Some(old) => {
+                info!("Registration settings changed, deregistering from {}", old.control_server);
+                if let Err(e) = deregister_server(&old).await {
+                    info!("Deregistration failed: {}", e);
+                }
+            }
+            None if !config.server_registration => continue,
+            None => {}
+        }
+
+        registered = register_with_backoff(&runtime, ip_families, &shutdown_signal).await;
+        if registered.is_some() {
+            info!("Pinging control server every {:?}", PING_INTERVAL);
         }
     }
-    
+
     info!("Ping job stopped");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let mut backoff = Backoff::default();
+        let first = backoff.next_delay();
+        assert!(first >= Duration::from_millis(500) && first <= BACKOFF_BASE);
+        let second = backoff.next_delay();
+        assert!(second >= BACKOFF_BASE && second <= 2 * BACKOFF_BASE);
+        for _ in 0..40 {
+            backoff.next_delay();
+        }
+        let late = backoff.next_delay();
+        assert!(late >= BACKOFF_MAX / 2 && late <= BACKOFF_MAX);
+    }
+
+    #[test]
+    fn test_registration_changed() {
+        let registered = crate::mioserver::parser::parse_args(vec!["-s".to_string()], Default::default()).unwrap();
+        let mut current = registered.clone();
+        current.limits.idle_timeout = Duration::from_secs(30);
+        assert!(!registration_changed(&registered, &current));
+        current.control_server = "http://127.0.0.1:8080".to_string();
+        assert!(registration_changed(&registered, &current));
+    }
+}
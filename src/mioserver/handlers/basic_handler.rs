@@ -1,4 +1,4 @@
-use crate::mioserver::{handlers::{common::{handle_main_command_receive, handle_main_command_send}, getchunks::{handle_get_chunks_receive_ok, handle_get_chunks_send_chunks, handle_get_chunks_send_chunks_last, handle_get_chunks_send_ok, handle_get_chunks_send_time}, gettime::{handle_get_time_receive_ok, handle_get_time_send_chunk, handle_get_time_send_time, handle_perf_send_last_chunk}, greeting_handler::{handle_greeting_accep_token_read, handle_greeting_receive_token, handle_greeting_send_accept_token, handle_greeting_send_chunksize, handle_greeting_send_ok, handle_greeting_send_version}, ping::{handle_ping_receive_ok, handle_ping_send_time, handle_pong_send}, put::{handle_put_receive_chunk, handle_put_send_bytes, handle_put_send_ok, handle_put_send_time}, putnoresult::{handle_put_no_result_receive_chunk, handle_put_no_result_send_ok, handle_put_no_result_send_time}, puttimeresult::{handle_put_time_result_receive_chunk, handle_put_time_result_send_ok, handle_put_time_result_send_time}}, server::TestState, ServerTestPhase};
+use crate::mioserver::{handlers::{common::{handle_command_send_err, handle_limit_send_err, handle_main_command_receive, handle_main_command_send}, getchunks::{handle_get_chunks_receive_ok, handle_get_chunks_send_chunks, handle_get_chunks_send_chunks_last, handle_get_chunks_send_ok, handle_get_chunks_send_time}, gettime::{handle_get_time_receive_ok, handle_get_time_send_chunk, handle_get_time_send_time, handle_perf_send_last_chunk}, greeting_handler::{handle_greeting_accep_token_read, handle_greeting_receive_token, handle_greeting_send_accept_token, handle_greeting_send_chunksize, handle_greeting_send_err, handle_greeting_send_ok, handle_greeting_send_version}, ping::{handle_ping_receive_ok, handle_ping_send_time, handle_pong_send}, put::{handle_put_receive_chunk, handle_put_send_bytes, handle_put_send_ok, handle_put_send_time}, putnoresult::{handle_put_no_result_receive_chunk, handle_put_no_result_send_ok, handle_put_no_result_send_time}, puttimeresult::{handle_put_time_result_receive_chunk, handle_put_time_result_send_ok, handle_put_time_result_send_time}, signed_result::handle_signed_result_send}, server::TestState, ServerTestPhase};
 use mio::Poll;
 use std::io;
 use log::{debug};
@@ -37,6 +37,7 @@ pub fn handle_client_writable_data(state: &mut TestState, poll: &Poll) -> io::Re
 
         ServerTestPhase::GreetingSendAcceptToken => handle_greeting_send_accept_token(poll, state),
         ServerTestPhase::GreetingSendOk => handle_greeting_send_ok(poll, state),
+        ServerTestPhase::GreetingSendErr => handle_greeting_send_err(poll, state),
         ServerTestPhase::GreetingSendChunksize => handle_greeting_send_chunksize(poll, state),
        
         ServerTestPhase::GetChunkSendOk => handle_get_chunks_send_ok(poll, state),
@@ -45,6 +46,8 @@ pub fn handle_client_writable_data(state: &mut TestState, poll: &Poll) -> io::Re
         ServerTestPhase::GetChunksSendTime => handle_get_chunks_send_time(poll, state),
 
         ServerTestPhase::AcceptCommandSend => handle_main_command_send(poll, state),
+        ServerTestPhase::CommandSendErr => handle_command_send_err(poll, state),
+        ServerTestPhase::LimitSendErr => handle_limit_send_err(poll, state),
 
         ServerTestPhase::PongSend => handle_pong_send(poll, state),
         ServerTestPhase::PingSendTime => handle_ping_send_time(poll, state),
@@ -65,6 +68,8 @@ pub fn handle_client_writable_data(state: &mut TestState, poll: &Poll) -> io::Re
         ServerTestPhase::PutTimeResultSendOk => handle_put_time_result_send_ok(poll, state),
         ServerTestPhase::PutTimeResultSendTimeResult => handle_put_time_result_send_time(poll, state),
 
+        ServerTestPhase::SignedResultSend => handle_signed_result_send(poll, state),
+
         _ => {
             debug!("Unknown measurement state: {:?}", state.measurement_state);
             Ok(1)
@@ -1,15 +1,21 @@
-use log::{debug, trace};
+use log::{debug, info, trace};
 use mio::{Interest, Poll};
 use std::io;
+use std::time::Duration;
 
 use crate::{
-    config::constants::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE},
+    config::constants::{ACCEPT_COMMANDS, ACCEPT_COMMANDS_UNSIGNED, CMD_SIGNEDRESULT, RESP_ERR},
     mioserver::{server::TestState, ServerTestPhase},
 };
 
 pub fn handle_main_command_send(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
     debug!("handle_get_put_ping_quit_send");
-    let command = b"ACCEPT GETCHUNKS GETTIME PUT PUTNORESULT PING QUIT\n";
+    // SIGNEDRESULT is only offered when the server has a key to sign with
+    let command = if state.sig_key.is_some() {
+        ACCEPT_COMMANDS.as_bytes()
+    } else {
+        ACCEPT_COMMANDS_UNSIGNED.as_bytes()
+    };
     if state.write_pos == 0 {
         trace!("Wrote handle_get_put_ping_quit_send {}", command.len());
         state.write_buffer[0..command.len()].copy_from_slice(command);
@@ -46,10 +52,14 @@ pub fn handle_main_command_receive(poll: &Poll, state: &mut TestState) -> io::Re
         }
 
         if state.read_buffer[state.read_pos - 1..state.read_pos] == [b'\n'] {
-            let command_str = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]);
+            let command_str = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]).into_owned();
 
             debug!("command_str: {}", command_str);
 
+            if state.limits.session_expired(state.started) {
+                return reject_and_close(poll, state, n, "maximum session length reached");
+            }
+
             if command_str.contains("GETCHUNKS") {
                 let commands: Vec<&str> = command_str.split_terminator('\n').collect();
 
@@ -57,26 +67,19 @@ pub fn handle_main_command_receive(poll: &Poll, state: &mut TestState) -> io::Re
 
                 let parts: Vec<&str> = command_str[9..].trim().split_whitespace().collect();
 
-                let num_chunks = match parts[0].parse::<usize>() {
-                    Ok(n) => n,
-                    Err(_) => {
-                        return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                    }
+                let num_chunks = match state.limits.chunk_count(parts.first().copied()) {
+                    Ok(count) => count,
+                    Err(reason) => return reject_command(poll, state, n, &reason),
                 };
 
-                let chunk_size = if parts.len() == 1 {
-                    MIN_CHUNK_SIZE
-                } else {
-                    match parts[1].parse::<usize>() {
-                        Ok(size) if size >= MIN_CHUNK_SIZE && size <= MAX_CHUNK_SIZE => size,
-                        _ => {
-                            return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                        }
-                    }
+                let chunk_size = match state.limits.chunk_size(parts.get(1).copied()) {
+                    Ok(size) => size,
+                    Err(reason) => return reject_command(poll, state, n, &reason),
                 };
 
                 state.num_chunks = num_chunks;
                 state.chunk_size = chunk_size;
+                state.session.start_command(command_str, Some(chunk_size));
                 state.measurement_state = ServerTestPhase::GetChunkSendChunk;
                 state.read_pos = 0;
 
@@ -87,6 +90,7 @@ pub fn handle_main_command_receive(poll: &Poll, state: &mut TestState) -> io::Re
             }
 
             if command_str.starts_with("PING\n") {
+                state.session.start_command(&command_str, None);
                 state.read_pos = 0;
 
                 state.measurement_state = ServerTestPhase::PongSend;
@@ -96,39 +100,39 @@ pub fn handle_main_command_receive(poll: &Poll, state: &mut TestState) -> io::Re
                 return Ok(n);
             }
 
+            if command_str.starts_with(CMD_SIGNEDRESULT) {
+                state.session.start_command(&command_str, None);
+                state.read_pos = 0;
+
+                state.measurement_state = ServerTestPhase::SignedResultSend;
+                state
+                    .stream
+                    .reregister(poll, state.token, Interest::WRITABLE)?;
+                return Ok(n);
+            }
+
             if command_str.starts_with("GETTIME") {
                 let parts: Vec<&str> = command_str[7..].trim().split_whitespace().collect();
 
                 // Parse duration using strtoul-like parsing
-                let duration = match parts[0].parse::<u64>() {
-                    Ok(d) => d,
-                    Err(_) => {
-                        return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                    }
+                let duration = match parts.first().map(|p| p.parse::<u64>()) {
+                    Some(Ok(d)) => d,
+                    _ => return reject_command(poll, state, n, "invalid GETTIME duration"),
                 };
+                let max_gettime = state.limits.max_gettime;
+                if !max_gettime.is_zero() && Duration::from_secs(duration) > max_gettime {
+                    let reason = format!("GETTIME {} s over the limit of {} s", duration, max_gettime.as_secs());
+                    return reject_command(poll, state, n, &reason);
+                }
 
-                state.duration = duration;
-
-                let chunk_size = if parts.len() == 1 {
-                    MIN_CHUNK_SIZE
-                } else {
-                    match parts[1].parse::<usize>() {
-                        Ok(size) => {
-                            if size < MIN_CHUNK_SIZE || size > MAX_CHUNK_SIZE {
-                                return Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    "Invalid command",
-                                ));
-                            }
-                            size
-                        }
-                        Err(_) => {
-                            return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                        }
-                    }
+                let chunk_size = match state.limits.chunk_size(parts.get(1).copied()) {
+                    Ok(size) => size,
+                    Err(reason) => return reject_command(poll, state, n, &reason),
                 };
 
+                state.duration = duration;
                 state.chunk_size = chunk_size;
+                state.session.start_command(&command_str, Some(chunk_size));
                 state.read_pos = 0;
                 state.measurement_state = ServerTestPhase::GetTimeSendChunk;
 
@@ -138,86 +142,107 @@ pub fn handle_main_command_receive(poll: &Poll, state: &mut TestState) -> io::Re
                 return Ok(n);
             }
 
-            if command_str.starts_with("PUTNORESULT") {
+            // PUTNORESULT, PUTTIMERESULT и PUT принимают один необязательный размер чанка
+            let put_phase = if command_str.starts_with("PUTNORESULT") {
+                Some(ServerTestPhase::PutNoResultSendOk)
+            } else if command_str.starts_with("PUTTIMERESULT") {
+                Some(ServerTestPhase::PutTimeResultSendOk)
+            } else if command_str.starts_with("PUT") {
+                Some(ServerTestPhase::PutSendOk)
+            } else {
+                None
+            };
+            if let Some(phase) = put_phase {
                 let parts: Vec<&str> = command_str.split_whitespace().collect();
-
                 if parts.len() > 2 {
-                    return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                }
-
-
-                if parts.len() == 2 {
-                    match parts[1].parse::<usize>() {
-                        Ok(size) if size >= MIN_CHUNK_SIZE && size <= MAX_CHUNK_SIZE => {
-                            state.chunk_size = size;
-                        }
-                        _ => {
-                            trace!("Invalid chunk size");
-                        }
-                    }
-                } else {
-                    state.chunk_size = MIN_CHUNK_SIZE;
+                    return reject_command(poll, state, n, "too many PUT arguments");
                 }
+                state.chunk_size = match state.limits.chunk_size(parts.get(1).copied()) {
+                    Ok(size) => size,
+                    Err(reason) => return reject_command(poll, state, n, &reason),
+                };
+                state.session.start_command(&command_str, Some(state.chunk_size));
                 state.read_pos = 0;
-                state.measurement_state = ServerTestPhase::PutNoResultSendOk;
+                state.measurement_state = phase;
                 state
                     .stream
                     .reregister(poll, state.token, Interest::WRITABLE)?;
                 return Ok(n);
             }
 
-            if command_str.starts_with("PUTTIMERESULT") {
-                state.read_pos = 0;
-                state.measurement_state = ServerTestPhase::PutTimeResultSendOk;
-                let parts: Vec<&str> = command_str.split_whitespace().collect();
-
-
-                match parts[1].parse::<usize>() {
-                    Ok(size) if size >= MIN_CHUNK_SIZE && size <= MAX_CHUNK_SIZE => {
-                        state.chunk_size = size;
-                    }
-                    _ => {
-                        state.chunk_size = MIN_CHUNK_SIZE;
-                    }
-                }
-
+            state.measurement_state = ServerTestPhase::AcceptCommandReceive;
+            return Ok(n);
+        }
+    }
+}
 
-                state
-                    .stream
-                    .reregister(poll, state.token, Interest::WRITABLE)?;
+/// Answers ERR to a command the server will not run and offers the commands again.
+fn reject_command(poll: &Poll, state: &mut TestState, n: usize, reason: &str) -> io::Result<usize> {
+    info!("Rejecting command from {:?}: {}", state.client_addr, reason);
+    state.read_pos = 0;
+    state.write_pos = 0;
+    state.measurement_state = ServerTestPhase::CommandSendErr;
+    state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
+    Ok(n)
+}
 
-                return Ok(n);
-            }
+/// Answers ERR and closes the connection, e.g. after the session ran too long.
+pub fn reject_and_close(poll: &Poll, state: &mut TestState, n: usize, reason: &str) -> io::Result<usize> {
+    info!("Closing connection from {:?}: {}", state.client_addr, reason);
+    state.read_pos = 0;
+    state.write_pos = 0;
+    state.measurement_state = ServerTestPhase::LimitSendErr;
+    state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
+    Ok(n)
+}
 
-            if command_str.starts_with("PUT") {
-                let parts: Vec<&str> = command_str.split_whitespace().collect();
+/// Stops an upload that went over `max_put_bytes`. Returns true if the
+/// connection is now closing with ERR.
+pub fn put_limit_exceeded(poll: &Poll, state: &mut TestState) -> io::Result<bool> {
+    let max_put_bytes = state.limits.max_put_bytes;
+    let bytes = state.session.command_bytes();
+    if max_put_bytes == 0 || bytes <= max_put_bytes {
+        return Ok(false);
+    }
+    let reason = format!("upload of {} bytes over the limit of {}", bytes, max_put_bytes);
+    reject_and_close(poll, state, 1, &reason)?;
+    Ok(true)
+}
 
-                if parts.len() > 2 {
-                    return Err(io::Error::new(io::ErrorKind::Other, "Invalid command"));
-                }
+pub fn handle_command_send_err(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
+    debug!("handle_command_send_err");
+    if write_err(state)? {
+        state.measurement_state = ServerTestPhase::AcceptCommandSend;
+        state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
+    }
+    Ok(1)
+}
 
-                if parts.len() == 2 {
-                    match parts[1].parse::<usize>() {
-                        Ok(size) if size >= MIN_CHUNK_SIZE && size <= MAX_CHUNK_SIZE => {
-                            state.chunk_size = size;
-                        }
-                        _ => {
-                            state.chunk_size = MIN_CHUNK_SIZE;
-                        }
-                    }
-                } else {
-                    state.chunk_size = MIN_CHUNK_SIZE;
-                }
-                state.read_pos = 0;
-                state.measurement_state = ServerTestPhase::PutSendOk;
-                state
-                    .stream
-                    .reregister(poll, state.token, Interest::WRITABLE)?;
-                return Ok(n);
-            }
+pub fn handle_limit_send_err(_poll: &Poll, state: &mut TestState) -> io::Result<usize> {
+    debug!("handle_limit_send_err");
+    if write_err(state)? {
+        state.stream.flush()?;
+        // Returning 0 makes the worker close the connection
+        return Ok(0);
+    }
+    Ok(1)
+}
 
-            state.measurement_state = ServerTestPhase::AcceptCommandReceive;
-            return Ok(n);
+/// Writes ERR; true once all of it is sent.
+fn write_err(state: &mut TestState) -> io::Result<bool> {
+    let err = RESP_ERR.as_bytes();
+    if state.write_pos == 0 {
+        state.write_buffer[..err.len()].copy_from_slice(err);
+    }
+    loop {
+        let n = state.stream.write(&state.write_buffer[state.write_pos..err.len()])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "EOF"));
+        }
+        state.write_pos += n;
+        if state.write_pos == err.len() {
+            state.write_pos = 0;
+            return Ok(true);
         }
     }
 }
@@ -1,10 +1,10 @@
 use log::{debug, trace};
 use mio::{Interest, Poll};
-use std::{io, time::Instant};
+use std::{io, sync::atomic::Ordering, time::Instant};
 
 use crate::{
-    client::globals::{CHUNK_STORAGE, CHUNK_TERMINATION_STORAGE},
-    mioserver::{server::TestState, ServerTestPhase},
+    client::globals::chunk_of_size,
+    mioserver::{metrics::METRICS, server::TestState, ServerTestPhase},
 };
 
 pub fn handle_get_chunks_send_ok(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
@@ -37,7 +37,7 @@ pub fn handle_get_chunks_send_chunks(poll: &Poll, state: &mut TestState) -> io::
         state.write_pos = 0;
         state.clock = Some(Instant::now());
     }
-    let chunk = CHUNK_STORAGE.get(&(chunk_size as u64)).unwrap();
+    let chunk = chunk_of_size(chunk_size as u64, false, &mut state.chunk);
     let is_last = state.processed_chunks == chunk_num - 1;
     if is_last {
         state.measurement_state = ServerTestPhase::GetChunksSendChunksLast;
@@ -46,11 +46,19 @@ pub fn handle_get_chunks_send_chunks(poll: &Poll, state: &mut TestState) -> io::
     }
     loop {
         debug!("Sending chunk: {} token {:?}", state.processed_chunks, state.token);
+        if state.write_pos == 0 && state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::WRITABLE)) {
+            return Ok(1);
+        }
 
         let n = state.stream.write(&chunk[state.write_pos..])?;
         if n == 0 {
             return Ok(0);
         }
+        METRICS.bytes_sent_getchunks.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
         state.write_pos += n;
         if state.write_pos == chunk.len() {
             debug!("Sent chunk: {} token {:?}", state.processed_chunks, state.token);
@@ -73,14 +81,22 @@ pub fn handle_get_chunks_send_chunks(poll: &Poll, state: &mut TestState) -> io::
 pub fn handle_get_chunks_send_chunks_last(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
     debug!("handle_get_chunks_send_chunks_last token {:?}", state.token);
     let chunk_size = state.chunk_size;
-    let chunk = CHUNK_TERMINATION_STORAGE.get(&(chunk_size as u64)).unwrap();
+    let chunk = chunk_of_size(chunk_size as u64, true, &mut state.terminal_chunk);
     loop {
         trace!("Sending last chunk: {}", state.processed_chunks);
+        if state.write_pos == 0 && state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::WRITABLE)) {
+            return Ok(1);
+        }
 
         let n = state.stream.write(&chunk[state.write_pos..])?;
         if n == 0 {
             return Ok(0);
         }
+        METRICS.bytes_sent_getchunks.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
         state.write_pos += n;
         if state.write_pos == chunk.len() {
             trace!("Sent last chunk: {}", state.processed_chunks);
@@ -105,6 +121,7 @@ pub fn handle_get_chunks_receive_ok(poll: &Poll, state: &mut TestState) -> io::R
         if state.read_buffer[..state.read_pos] == b"OK\n"[..] {
             state.measurement_state = ServerTestPhase::GetChunksSendTime;
             state.time_ns = Some(state.clock.unwrap().elapsed().as_nanos());
+            state.session.finish_command(state.time_ns.unwrap());
             state.read_pos = 0;
             state
                 .stream
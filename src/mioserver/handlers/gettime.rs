@@ -1,11 +1,11 @@
-use std::{io, time::Instant};
+use std::{io, sync::atomic::Ordering, time::Instant};
 
 use log::{debug, trace};
 use mio::{Interest, Poll};
 
 use crate::{
-    client::globals::{get_chunk, CHUNK_STORAGE, CHUNK_TERMINATION_STORAGE},
-    mioserver::{server::TestState, ServerTestPhase},
+    client::globals::chunk_of_size,
+    mioserver::{metrics::METRICS, server::TestState, ServerTestPhase},
 };
 
 pub fn handle_get_time_send_chunk(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
@@ -15,20 +15,24 @@ pub fn handle_get_time_send_chunk(poll: &Poll, state: &mut TestState) -> io::Res
 
     if state.clock.is_none() {
         state.clock = Some(Instant::now());
+        state.total_bytes_sent = 0;
     }
 
     let is_last = state.clock.unwrap().elapsed().as_nanos() > duration as u128 * 1000000000;
 
-   let chunk = CHUNK_STORAGE.get(&(chunk_size as u64)).unwrap_or_else(|| {
-        if state.chunk.is_none() {
-            let chunk = get_chunk(chunk_size as u64, false);
-            state.chunk = Some(chunk);
-        }
-        state.chunk.as_ref().unwrap()
-    });
+    let chunk = chunk_of_size(chunk_size as u64, false, &mut state.chunk);
     loop {
+        if state.write_pos == 0 && state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::WRITABLE)) {
+            return Ok(1);
+        }
         let n = state.stream.write(&chunk[state.write_pos..])?;
         state.write_pos += n;
+        state.total_bytes_sent += n as u64;
+        METRICS.bytes_sent_gettime.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
         if state.write_pos == chunk.len() {
             debug!("handle_get_time_send_chunk token {:?}", state.token);
             state.write_pos = 0;
@@ -53,18 +57,19 @@ pub fn handle_get_time_send_chunk(poll: &Poll, state: &mut TestState) -> io::Res
 
 pub fn handle_perf_send_last_chunk(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
     debug!("handle_perf_send_last_chunk token {:?}", state.token);
-    let chunk = CHUNK_TERMINATION_STORAGE
-        .get(&(state.chunk_size as u64))
-        .unwrap_or_else(|| {
-            if state.terminal_chunk.is_none() {
-                let chunk = get_chunk(state.chunk_size as u64, true);
-                state.terminal_chunk = Some(chunk);
-            }
-            state.terminal_chunk.as_ref().unwrap()
-        });
+    let chunk = chunk_of_size(state.chunk_size as u64, true, &mut state.terminal_chunk);
     loop {
+        if state.write_pos == 0 && state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::WRITABLE)) {
+            return Ok(1);
+        }
         let n = state.stream.write(&chunk[state.write_pos..])?;
         state.write_pos += n;
+        state.total_bytes_sent += n as u64;
+        METRICS.bytes_sent_gettime.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
         if state.write_pos == chunk.len() {
             // debug!("handle_get_time_send_chunk token {:?}", state.token);
             state.write_pos = 0;
@@ -91,6 +96,8 @@ pub fn handle_get_time_receive_ok(poll: &Poll, state: &mut TestState) -> io::Res
         if state.read_buffer[0..state.read_pos] == b"OK\n"[..] {
             state.measurement_state = ServerTestPhase::GetTimeSendTime;
             state.time_ns = Some(state.clock.unwrap().elapsed().as_nanos());
+            state.sent_time_ns = state.time_ns;
+            state.session.finish_command(state.time_ns.unwrap());
             state.read_pos = 0;
             state
                 .stream
@@ -1,10 +1,16 @@
 use std::io;
 
 use anyhow::Result;
-use log::{debug, trace};
+use lazy_static::lazy_static;
+use log::{debug, info, trace};
 use mio::{Interest, Poll};
+use regex::Regex;
 
-use crate::{client::constants::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE}, config::constants::CHUNK_SIZE, mioserver::{server::TestState, ServerTestPhase}};
+use crate::{config::constants::{CHUNK_SIZE, RESP_ERR, TOKEN_PATTERN}, mioserver::{pacer::Pacer, server::TestState, ServerTestPhase}};
+
+lazy_static! {
+    static ref TOKEN_REGEX: Regex = Regex::new(TOKEN_PATTERN).unwrap();
+}
 
 pub fn handle_greeting_accep_token_read(
     poll: &Poll,
@@ -108,20 +114,93 @@ pub fn handle_greeting_receive_token(
         let n = state
             .stream
             .read(&mut state.read_buffer[state.read_pos..])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF"));
+        }
         state.read_pos += n;
         let end = b"\n";
-        //compare last 2 bytes with end
-        //TODO handle properly client uuid
-        if n > 0 && state.read_buffer[state.read_pos - 1..state.read_pos] == *end {
-            state.measurement_state = ServerTestPhase::GreetingSendOk;
-            trace!("Greeting received token: {}", String::from_utf8_lossy(&state.read_buffer));
+        if state.read_buffer[state.read_pos - 1..state.read_pos] == *end {
+            let line = String::from_utf8_lossy(&state.read_buffer[..state.read_pos]).to_string();
+            trace!("Greeting received token: {}", line);
             state.read_pos = 0;
+            state.measurement_state = if check_token(state, line.trim_end()) {
+                state.pacer = state.bandwidth_caps.cap_for(state.token_label.as_deref()).map(Pacer::new);
+                ServerTestPhase::GreetingSendOk
+            } else {
+                ServerTestPhase::GreetingSendErr
+            };
             state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
             return Ok(n);
         }
     }
 }
 
+fn check_token(state: &mut TestState, line: &str) -> bool {
+    let captures = TOKEN_REGEX.captures(line);
+    if let Some(captures) = &captures {
+        state.token_uuid = Some(captures[1].to_string());
+    }
+
+    let validator = match &state.token_validator {
+        Some(validator) => validator.clone(),
+        None => return true,
+    };
+
+    let captures = match captures {
+        Some(captures) => captures,
+        None => {
+            info!("Invalid token format: {}", line);
+            return false;
+        }
+    };
+    let (token_uuid, start_time, hmac) = (&captures[1], &captures[2], &captures[3]);
+
+    match validator.validate_now(token_uuid, start_time, hmac) {
+        Ok(Some(label)) => state.token_label = Some(label.to_string()),
+        Ok(None) => {
+            info!("Token was not accepted; uuid: {}", token_uuid);
+            return false;
+        }
+        Err(e) => {
+            info!("Token validation error for uuid {}: {}", token_uuid, e);
+            return false;
+        }
+    }
+
+    if !validator.register_use(token_uuid) {
+        info!("Token reuse rejected; uuid: {}", token_uuid);
+        return false;
+    }
+
+    debug!("Valid token; uuid: {}", token_uuid);
+    true
+}
+
+pub fn handle_greeting_send_err(
+    _poll: &Poll,
+    state: &mut TestState,
+) -> Result<usize, std::io::Error> {
+    debug!("handle_greeting_send_err");
+    let err = RESP_ERR.as_bytes();
+
+    if state.write_pos == 0 {
+        state.write_buffer[..err.len()].copy_from_slice(err);
+    }
+    loop {
+        let n = state.stream.write(&state.write_buffer[state.write_pos..err.len()])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "EOF"));
+        }
+        state.write_pos += n;
+        if state.write_pos == err.len() {
+            state.write_pos = 0;
+            state.stream.flush()?;
+            // Returning 0 makes the worker close the connection
+            return Ok(0);
+        }
+    }
+}
+
 pub fn handle_greeting_send_ok(
     poll: &Poll,
     state: &mut TestState,
@@ -147,7 +226,12 @@ pub fn handle_greeting_send_ok(
 pub fn handle_greeting_send_chunksize( poll: &Poll,
     state: &mut TestState,) -> Result<usize, std::io::Error> {
     debug!("handle_greeting_send_ok");
-    let chunk_size_msg = format!("CHUNKSIZE {} {} {}\n", CHUNK_SIZE, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE); //todo compare version
+    let limits = state.limits;
+    let default_chunk_size = CHUNK_SIZE.clamp(limits.min_chunk_size, limits.max_chunk_size);
+    let chunk_size_msg = format!(
+        "CHUNKSIZE {} {} {}\n",
+        default_chunk_size, limits.min_chunk_size, limits.max_chunk_size
+    ); //todo compare version
 
     if state.write_pos == 0 {
         state.write_buffer[..chunk_size_msg.len()].copy_from_slice(chunk_size_msg.as_bytes());
@@ -6,4 +6,5 @@ pub mod ping;
 pub mod gettime;
 pub mod putnoresult;
 pub mod put;
-pub mod puttimeresult;
\ No newline at end of file
+pub mod puttimeresult;
+pub mod signed_result;
\ No newline at end of file
@@ -1,9 +1,9 @@
-use std::{io, time::Instant};
+use std::{io, sync::atomic::Ordering, time::Instant};
 
 use log::{debug};
 use mio::{Interest, Poll};
 
-use crate::mioserver::{server::TestState, ServerTestPhase};
+use crate::mioserver::{handlers::common::put_limit_exceeded, metrics::METRICS, server::TestState, ServerTestPhase};
 
 pub fn handle_put_send_ok(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
     debug!("handle_put_send_ok");
@@ -77,6 +77,9 @@ pub fn handle_put_receive_chunk(
 ) -> io::Result<usize> {
     debug!("handle_put_receive_chunk");
     loop {
+        if state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::READABLE)) {
+            return Ok(1);
+        }
         let n = state
             .stream
             .read(&mut state.chunk_buffer[state.read_pos..])?;
@@ -85,9 +88,18 @@ pub fn handle_put_receive_chunk(
         }
         state.read_pos += n;
         state.total_bytes += n as u64;
+        METRICS.bytes_received_put.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
+        if put_limit_exceeded(poll, state)? {
+            return Ok(n);
+        }
         if state.read_pos == state.chunk_size {
             state.measurement_state = ServerTestPhase::PutSendBytes;
             state.time_ns = Some(state.clock.unwrap().elapsed().as_nanos());
+            state.session.finish_command(state.time_ns.unwrap());
             state
                 .stream
                 .reregister(poll, state.token, Interest::WRITABLE)?;
@@ -1,9 +1,9 @@
-use std::{io, time::Instant};
+use std::{io, sync::atomic::Ordering, time::Instant};
 
 use log::{debug, info, trace};
 use mio::{Interest, Poll};
 
-use crate::mioserver::{server::TestState, ServerTestPhase};
+use crate::mioserver::{handlers::common::put_limit_exceeded, metrics::METRICS, server::TestState, ServerTestPhase};
 
 pub fn handle_put_no_result_send_ok(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
     debug!("handle_put_no_result_send_ok");
@@ -42,6 +42,9 @@ pub fn handle_put_no_result_receive_chunk(
 ) -> io::Result<usize> {
     debug!("handle_put_no_result_receive_chunk");
     loop {
+        if state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::READABLE)) {
+            return Ok(1);
+        }
         let n = state
             .stream
             .read(&mut state.chunk_buffer[state.read_pos..])?;
@@ -50,12 +53,21 @@ pub fn handle_put_no_result_receive_chunk(
             return Err(io::Error::new(io::ErrorKind::Other, "EOF"));
         }
         state.read_pos += n;
+        METRICS.bytes_received_putnoresult.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
+        if put_limit_exceeded(poll, state)? {
+            return Ok(n);
+        }
         trace!("Read {} bytes", state.read_pos);
         if state.read_pos == state.chunk_size {
             trace!("Chunk size reached");
             if state.chunk_buffer[state.read_pos - 1] == 0xFF {
                 trace!("Last byte is 0xFF");
                 state.time_ns = Some(state.clock.unwrap().elapsed().as_nanos());
+                state.session.finish_command(state.time_ns.unwrap());
                 state.measurement_state = ServerTestPhase::PutNoResultSendTime;
                 state.read_pos = 0;
                 state
@@ -1,10 +1,10 @@
-use std::{io, time::Instant};
+use std::{io, sync::atomic::Ordering, time::Instant};
 
 use log::{debug, info, trace};
 use mio::{Interest, Poll};
 
 use crate::{
-    mioserver::{server::TestState, ServerTestPhase},
+    mioserver::{handlers::common::put_limit_exceeded, metrics::METRICS, server::TestState, ServerTestPhase},
 };
 
 pub fn handle_put_time_result_send_ok(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
@@ -34,6 +34,7 @@ pub fn handle_put_time_result_send_ok(poll: &Poll, state: &mut TestState) -> io:
             //TODO: remove this
             state.chunk_buffer = vec![0u8; state.chunk_size as usize];
             state.clock = Some(Instant::now());
+            state.total_bytes_received = 0;
 
             state
                 .stream
@@ -49,6 +50,9 @@ pub fn handle_put_time_result_receive_chunk(
 ) -> io::Result<usize> {
     debug!("handle_put_time_result_receive_chunk");
     loop {
+        if state.pacer.as_mut().is_some_and(|pacer| pacer.pause_if_over(Interest::READABLE)) {
+            return Ok(1);
+        }
         let n = state
             .stream
             .read(&mut state.chunk_buffer[state.read_pos..])?;
@@ -58,6 +62,15 @@ pub fn handle_put_time_result_receive_chunk(
         }
         state.read_pos += n;
         state.total_bytes += n as u64;
+        state.total_bytes_received += n as u64;
+        METRICS.bytes_received_puttimeresult.fetch_add(n as u64, Ordering::Relaxed);
+        state.session.add_bytes(n as u64);
+        if let Some(pacer) = &mut state.pacer {
+            pacer.consume(n);
+        }
+        if put_limit_exceeded(poll, state)? {
+            return Ok(n);
+        }
         trace!("Read {} bytes", state.read_pos);
         if state.read_pos == state.chunk_size {
             trace!("Chunk size reached");
@@ -66,6 +79,8 @@ pub fn handle_put_time_result_receive_chunk(
                     .bytes_received
                     .push_back((tt as u64, state.total_bytes));
             if state.chunk_buffer[state.read_pos - 1] == 0xFF {
+                state.received_time_ns = Some(tt);
+                state.session.finish_command(tt);
                 state.measurement_state = ServerTestPhase::PutTimeResultSendTimeResult;
                 state.read_pos = 0;
                 state.write_pos = 0;
@@ -88,9 +103,8 @@ pub fn handle_put_time_result_send_time(poll: &Poll, state: &mut TestState) -> i
     let result = state.bytes_received.iter().map(|(t, b)| format!("({} {})", t, b)).collect::<Vec<String>>().join("; ");
     let command = format!("TIMERESULT {}\n", result);
     if state.write_pos == 0 {
-        if state.chunk_buffer.len() < command.len() {
-            state.chunk_buffer.resize(command.len(), 0);
-        }
+        // The buffer still holds the last upload chunk, only the result line is sent
+        state.chunk_buffer.resize(command.len(), 0);
         state.chunk_buffer[0..command.len()].copy_from_slice(command.as_bytes());
     }
     loop {
@@ -1,86 +1,92 @@
-use std::{io, time::Instant};
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use hmac::{Hmac, Mac};
 use log::debug;
 use mio::{Interest, Poll};
 use sha1::Sha1;
 
-use crate::mioserver::{server::TestState, ServerTestPhase};
+use crate::{
+    config::constants::RESP_ERR,
+    mioserver::{server::TestState, ServerTestPhase},
+};
 
-pub fn handle_signed_result(poll: &Poll, state: &mut TestState) -> Result<usize, std::io::Error> {
-    debug!("handle_signed_result");
+type HmacSha1 = Hmac<Sha1>;
 
-    if state.write_pos == 0 {
-        let message = format!(
-            "GETTIME:({} {}); PUTTIMERESULT:({} {}); CLIENT_IP:{}; TIMESTAMP:{}\n",
-            state.total_bytes_received,
-            state.received_time_ns.unwrap(),
-            state.total_bytes_sent,
-            state.sent_time_ns.unwrap(),
-            state.client_addr.unwrap(),
-            Instant::now().elapsed().as_nanos()
-        );
+pub fn handle_signed_result_send(poll: &Poll, state: &mut TestState) -> io::Result<usize> {
+    debug!("handle_signed_result_send");
 
-        let secret_key = state.sig_key.as_ref().unwrap();
-        let signature = sign_message(&message, &secret_key)?;
+    if state.signed_result.is_none() {
+        let response = match &state.sig_key {
+            Some(secret_key) => {
+                let message = signed_result_message(state);
+                let signature = sign_message(&message, secret_key)?;
 
-        debug!("Signed message: {}", message);
-        debug!("Signature: {}", signature);
+                debug!("Signed message: {}", message);
+                debug!("Signature: {}", signature);
 
-        let envelope = format!("{}:{}", message, signature);
-
-        state.write_pos = envelope.len();
-        state.write_buffer[0..state.write_pos].copy_from_slice(envelope.as_bytes());
+                format!("SIGNEDRESULT {} {}\n", message, signature)
+            }
+            None => {
+                debug!("No signing key configured");
+                RESP_ERR.to_string()
+            }
+        };
+        state.write_pos = 0;
+        state.signed_result = Some(response);
     }
 
+    let response = state.signed_result.clone().unwrap();
     loop {
         let n = state
             .stream
-            .write(&mut state.chunk_buffer[state.read_pos..])?;
+            .write(&response.as_bytes()[state.write_pos..])?;
         if n == 0 {
             debug!("EOF");
-            return Err(io::Error::new(io::ErrorKind::Other, "EOF"));
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "EOF"));
         }
-        state.read_pos += n;
-        if state.read_pos >= state.write_pos {
+        state.write_pos += n;
+        if state.write_pos == response.len() {
             state.write_pos = 0;
             state.read_pos = 0;
-            state.measurement_state = ServerTestPhase::SignedResultReceiveOk;
-            state.stream.reregister(poll, state.token, Interest::READABLE)?;
-            return Ok(n);
-        }
-    }
-}
-
-pub fn handle_signed_result_receive_ok(poll: &Poll, state: &mut TestState) -> Result<usize, std::io::Error> {
-    let ok = b"OK\n";
-    loop {
-        let n = state.stream.read(&mut state.read_buffer)?;
-        if n == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF"));
-        }
-        state.read_pos += n;
-        if state.read_buffer[0..ok.len()] == ok[..] {
-            let time = state.clock.unwrap().elapsed().as_nanos();
-            state.clock = None;
-            state.sent_time_ns = Some(time);
+            state.signed_result = None;
             state.measurement_state = ServerTestPhase::AcceptCommandSend;
-            state.read_pos = 0;
             state.stream.reregister(poll, state.token, Interest::WRITABLE)?;
             return Ok(n);
         }
     }
 }
 
-fn sign_message(message: &str, secret_key: &str) -> Result<String, std::io::Error> {
-    type HmacSha256 = Hmac<Sha1>;
+fn signed_result_message(state: &TestState) -> String {
+    let client_ip = state
+        .client_addr
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
 
-    let mut mac = HmacSha256::new_from_slice(secret_key.as_bytes())
+    format!(
+        "GETTIME:({} {}); PUTTIMERESULT:({} {}); CLIENT_IP:{}; TIMESTAMP:{}",
+        state.total_bytes_sent,
+        state.sent_time_ns.unwrap_or(0),
+        state.total_bytes_received,
+        state.received_time_ns.unwrap_or(0),
+        client_ip,
+        timestamp
+    )
+}
+
+fn sign_message(message: &str, secret_key: &str) -> Result<String, std::io::Error> {
+    let mut mac = HmacSha1::new_from_slice(secret_key.as_bytes())
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
     mac.update(message.as_bytes());
     let result = mac.finalize();
-    let signature = base64::encode(result.into_bytes());
 
-    Ok(signature)
+    Ok(BASE64.encode(result.into_bytes()))
 }
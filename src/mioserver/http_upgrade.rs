@@ -0,0 +1,195 @@
+use std::fmt;
+
+use crate::tokio_server::utils::websocket::Handshake;
+
+/// Largest upgrade request (request line and headers) the server reads.
+pub const MAX_REQUEST_SIZE: usize = 8192;
+/// Paths a client may upgrade on; RMBT clients use `/rmbt`, websocket clients `/`.
+pub const UPGRADE_PATHS: &[&str] = &["/", "/rmbt"];
+
+/// Protocol the client asked to switch to.
+#[derive(Debug)]
+pub enum UpgradeRequest {
+    Rmbt,
+    WebSocket(Handshake),
+}
+
+/// Why an upgrade request is refused; each maps to an HTTP error response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpgradeError {
+    BadRequest(&'static str),
+    NotFound(String),
+    MethodNotAllowed(String),
+    UpgradeRequired,
+    TooLarge,
+}
+
+impl UpgradeError {
+    /// Complete response sent before the connection is closed.
+    pub fn response(&self) -> String {
+        let (status, extra_headers) = match self {
+            UpgradeError::BadRequest(_) => ("400 Bad Request", ""),
+            UpgradeError::NotFound(_) => ("404 Not Found", ""),
+            UpgradeError::MethodNotAllowed(_) => ("405 Method Not Allowed", "Allow: GET\r\n"),
+            UpgradeError::UpgradeRequired => (
+                "426 Upgrade Required",
+                "Upgrade: RMBT, websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n",
+            ),
+            UpgradeError::TooLarge => ("431 Request Header Fields Too Large", ""),
+        };
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, extra_headers
+        )
+    }
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpgradeError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            UpgradeError::NotFound(path) => write!(f, "unknown path {}", path),
+            UpgradeError::MethodNotAllowed(method) => write!(f, "method {} not allowed", method),
+            UpgradeError::UpgradeRequired => write!(f, "no RMBT or websocket upgrade requested"),
+            UpgradeError::TooLarge => write!(f, "request larger than {} bytes", MAX_REQUEST_SIZE),
+        }
+    }
+}
+
+/// Parses the HTTP/1.1 upgrade request at the start of `buf`. Returns
+/// `Ok(None)` while the header block is incomplete.
+pub fn parse_upgrade_request(buf: &[u8]) -> Result<Option<UpgradeRequest>, UpgradeError> {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Err(UpgradeError::TooLarge);
+        }
+        return Ok(None);
+    };
+    if end + 4 > MAX_REQUEST_SIZE {
+        return Err(UpgradeError::TooLarge);
+    }
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| UpgradeError::BadRequest("request is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    // Клиенты RMBT шлют "GET /rmbt HTTP/1.1 " с пробелом в конце
+    let request_line = lines.next().unwrap_or_default();
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    let [method, target, version] = parts.as_slice() else {
+        return Err(UpgradeError::BadRequest("malformed request line"));
+    };
+    if *version != "HTTP/1.1" {
+        return Err(UpgradeError::BadRequest("only HTTP/1.1 can be upgraded"));
+    }
+
+    let mut upgrade = Vec::new();
+    let mut connection = Vec::new();
+    let mut handshake = Handshake::new();
+    let mut websocket_version = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(UpgradeError::BadRequest("malformed header line"));
+        };
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err(UpgradeError::BadRequest("malformed header name"));
+        }
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade.extend(tokens(value)),
+            "connection" => connection.extend(tokens(value)),
+            "host" => handshake.host = Some(value.to_string()),
+            "origin" => handshake.origin = Some(value.to_string()),
+            "sec-websocket-key" => handshake.key = Some(value.to_string()),
+            "sec-websocket-version" => websocket_version = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if *method != "GET" {
+        return Err(UpgradeError::MethodNotAllowed(method.to_string()));
+    }
+    let path = target.split('?').next().unwrap_or_default();
+    if !UPGRADE_PATHS.contains(&path) {
+        return Err(UpgradeError::NotFound(path.to_string()));
+    }
+    if !connection.iter().any(|token| token == "upgrade") {
+        return Err(UpgradeError::UpgradeRequired);
+    }
+
+    if upgrade.iter().any(|token| token == "websocket") {
+        if handshake.key.as_deref().is_none_or(str::is_empty) {
+            return Err(UpgradeError::BadRequest("missing Sec-WebSocket-Key"));
+        }
+        if websocket_version.as_deref() != Some("13") {
+            return Err(UpgradeError::UpgradeRequired);
+        }
+        handshake.resource = Some(target.to_string());
+        return Ok(Some(UpgradeRequest::WebSocket(handshake)));
+    }
+    if upgrade.iter().any(|token| token == "rmbt") {
+        return Ok(Some(UpgradeRequest::Rmbt));
+    }
+    Err(UpgradeError::UpgradeRequired)
+}
+
+/// Lowercase comma-separated tokens of a header value, e.g. "keep-alive, Upgrade".
+fn tokens(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+}
+
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::constants::RMBT_UPGRADE_REQUEST;
+
+    fn parse(request: &str) -> Result<Option<UpgradeRequest>, UpgradeError> {
+        parse_upgrade_request(request.as_bytes())
+    }
+
+    #[test]
+    fn test_rmbt_upgrade() {
+        assert!(matches!(parse(RMBT_UPGRADE_REQUEST), Ok(Some(UpgradeRequest::Rmbt))));
+        assert!(matches!(parse("GET /rmbt HTTP/1.1\r\n"), Ok(None)));
+    }
+
+    #[test]
+    fn test_websocket_upgrade() {
+        let request = "GET / HTTP/1.1\r\nHost: example.org\r\nConnection: keep-alive, Upgrade\r\n\
+                       Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let Ok(Some(UpgradeRequest::WebSocket(handshake))) = parse(request) else {
+            panic!("websocket upgrade not recognized");
+        };
+        assert_eq!(handshake.key.as_deref(), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert_eq!(handshake.host.as_deref(), Some("example.org"));
+
+        let without_key = "GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert!(matches!(parse(without_key), Err(UpgradeError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_rejected_requests() {
+        assert_eq!(parse("GET /rmbt HTTP/1.1\r\n\r\n").unwrap_err(), UpgradeError::UpgradeRequired);
+        assert!(matches!(
+            parse("GET /admin HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: RMBT\r\n\r\n"),
+            Err(UpgradeError::NotFound(_))
+        ));
+        assert!(matches!(
+            parse("POST /rmbt HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: RMBT\r\n\r\n"),
+            Err(UpgradeError::MethodNotAllowed(_))
+        ));
+        assert!(matches!(parse("\x16\x03\x01\x02\x00\r\n\r\n"), Err(UpgradeError::BadRequest(_))));
+        assert!(matches!(parse("GET / HTTP/1.0\r\n\r\n"), Err(UpgradeError::BadRequest(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nno colon\r\n\r\n"), Err(UpgradeError::BadRequest(_))));
+        assert_eq!(
+            parse_upgrade_request(&vec![b'a'; MAX_REQUEST_SIZE]).unwrap_err(),
+            UpgradeError::TooLarge
+        );
+        assert!(UpgradeError::UpgradeRequired.response().starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::mioserver::server::file_read_error;
+
+/// Client networks the server answers. An address has to match the allow
+/// list, if there is one, and must not match the deny list.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        IpFilter { allow, deny }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allow_count(&self) -> usize {
+        self.allow.len()
+    }
+
+    pub fn deny_count(&self) -> usize {
+        self.deny.len()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // IPv4 клиент на dual-stack сокете приходит как ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parses "192.0.2.0/24", "2001:db8::/32" or a single address.
+pub fn parse_network(s: &str) -> Result<IpNet, anyhow::Error> {
+    let s = s.trim();
+    if let Ok(net) = s.parse::<IpNet>() {
+        return Ok(net.trunc());
+    }
+    s.parse::<IpAddr>()
+        .map(IpNet::from)
+        .map_err(|_| anyhow::anyhow!("Invalid network '{}', expected an address or CIDR", s))
+}
+
+/// Reads one network per line; blank lines and text after '#' are ignored.
+pub fn read_network_file(path: &str) -> Result<Vec<IpNet>, anyhow::Error> {
+    let content = fs::read_to_string(path).map_err(|e| file_read_error(path, e))?;
+    let mut networks = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        networks.push(parse_network(line).map_err(|e| anyhow::anyhow!("{}: line {}: {}", path, number + 1, e))?);
+    }
+    // Пустой allow-файл открыл бы сервер для всех
+    if networks.is_empty() {
+        return Err(anyhow::anyhow!("No networks found in {}", path));
+    }
+    Ok(networks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let filter = IpFilter::new(
+            vec![parse_network("192.0.2.0/24").unwrap(), parse_network("2001:db8::/32").unwrap()],
+            vec![parse_network("192.0.2.66").unwrap()],
+        );
+        assert!(filter.permits(ip("192.0.2.1")));
+        assert!(filter.permits(ip("::ffff:192.0.2.1")));
+        assert!(filter.permits(ip("2001:db8::1")));
+        assert!(!filter.permits(ip("192.0.2.66")));
+        assert!(!filter.permits(ip("198.51.100.1")));
+        assert!(!filter.permits(ip("2001:db9::1")));
+
+        let deny_only = IpFilter::new(Vec::new(), vec![parse_network("198.51.100.7/16").unwrap()]);
+        assert!(!deny_only.permits(ip("198.51.0.1")));
+        assert!(deny_only.permits(ip("192.0.2.1")));
+        assert!(parse_network("192.0.2.0/33").is_err());
+    }
+
+    #[test]
+    fn test_read_network_file() {
+        let path = std::env::temp_dir().join(format!("nettest-allow-{}.txt", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "# customers\n192.0.2.0/24\n\n2001:db8::/32  # v6\n").unwrap();
+        assert_eq!(read_network_file(&path).unwrap().len(), 2);
+        fs::write(&path, "192.0.2.0/24\nnot-a-net\n").unwrap();
+        assert!(read_network_file(&path).unwrap_err().to_string().contains("line 2"));
+        fs::write(&path, "# nothing yet\n").unwrap();
+        assert!(read_network_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+
+use crate::config::constants::{MAX_CHUNKS, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Limits for a single test. Requests beyond them are answered with ERR.
+/// A zero `max_*` value disables that limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TestLimits {
+    pub max_gettime: Duration,
+    // На одну команду PUT/PUTNORESULT/PUTTIMERESULT
+    pub max_put_bytes: u64,
+    pub max_chunks: usize,
+    pub min_chunk_size: usize,
+    pub max_chunk_size: usize,
+    pub idle_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub max_session: Duration,
+}
+
+impl Default for TestLimits {
+    fn default() -> Self {
+        TestLimits {
+            max_gettime: Duration::from_secs(60),
+            max_put_bytes: 0,
+            max_chunks: MAX_CHUNKS,
+            min_chunk_size: MIN_CHUNK_SIZE,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            idle_timeout: Duration::from_secs(15),
+            handshake_timeout: Duration::from_secs(3),
+            max_session: Duration::ZERO,
+        }
+    }
+}
+
+impl TestLimits {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.min_chunk_size == 0 || self.min_chunk_size > self.max_chunk_size {
+            return Err(anyhow::anyhow!(
+                "Invalid chunk size range {}..{}",
+                self.min_chunk_size,
+                self.max_chunk_size
+            ));
+        }
+        // Чанки больше MAX_CHUNK_SIZE не из чего нарезать
+        if self.max_chunk_size > MAX_CHUNK_SIZE {
+            return Err(anyhow::anyhow!(
+                "Maximum chunk size {} is over {}",
+                self.max_chunk_size,
+                MAX_CHUNK_SIZE
+            ));
+        }
+        if self.idle_timeout.is_zero() || self.handshake_timeout.is_zero() {
+            return Err(anyhow::anyhow!("Idle and handshake timeouts must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Chunk size argument of a command; the minimum if it is missing.
+    pub fn chunk_size(&self, arg: Option<&str>) -> Result<usize, String> {
+        let Some(arg) = arg else {
+            return Ok(self.min_chunk_size);
+        };
+        let size: usize = arg.parse().map_err(|_| format!("invalid chunk size '{}'", arg))?;
+        if size < self.min_chunk_size || size > self.max_chunk_size {
+            return Err(format!(
+                "chunk size {} outside {}..{}",
+                size, self.min_chunk_size, self.max_chunk_size
+            ));
+        }
+        Ok(size)
+    }
+
+    /// Chunk count argument of GETCHUNKS; at least one chunk, the last one
+    /// carries the terminator.
+    pub fn chunk_count(&self, arg: Option<&str>) -> Result<usize, String> {
+        let count: usize = match arg.map(|a| a.parse()) {
+            Some(Ok(count)) if count > 0 => count,
+            _ => return Err("invalid GETCHUNKS count".to_string()),
+        };
+        if self.max_chunks > 0 && count > self.max_chunks {
+            return Err(format!("{} chunks over the limit of {}", count, self.max_chunks));
+        }
+        Ok(count)
+    }
+
+    pub fn session_expired(&self, started: Instant) -> bool {
+        !self.max_session.is_zero() && started.elapsed() >= self.max_session
+    }
+}
+
+/// Limit for all clients inside one network: "10.0.0.0/8,100,50" means at most
+/// 100 concurrent connections and 50 new connections per second. 0 disables a limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CidrLimit {
+    pub net: IpNet,
+    pub max_concurrent: usize,
+    pub max_per_second: u32,
+}
+
+impl FromStr for CidrLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+        if parts.len() != 3 {
+            return Err(anyhow::anyhow!(
+                "Invalid CIDR limit '{}', expected <cidr>,<max_concurrent>,<max_per_second>",
+                s
+            ));
+        }
+        Ok(CidrLimit {
+            net: parts[0].parse::<IpNet>()?.trunc(),
+            max_concurrent: parts[1].parse()?,
+            max_per_second: parts[2].parse()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Concurrency,
+    Rate,
+}
+
+impl LimitExceeded {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LimitExceeded::Concurrency => "concurrency",
+            LimitExceeded::Rate => "rate",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} limit exceeded", self.label())
+    }
+}
+
+struct Usage {
+    active: usize,
+    window_start: Instant,
+    window_count: u32,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Usage {
+            active: 0,
+            window_start: now,
+            window_count: 0,
+        }
+    }
+
+    fn check(&mut self, now: Instant, max_concurrent: usize, max_per_second: u32) -> Result<(), LimitExceeded> {
+        if now.duration_since(self.window_start) >= RATE_WINDOW {
+            self.window_start = now;
+            self.window_count = 0;
+        }
+        if max_concurrent > 0 && self.active >= max_concurrent {
+            return Err(LimitExceeded::Concurrency);
+        }
+        if max_per_second > 0 && self.window_count >= max_per_second {
+            return Err(LimitExceeded::Rate);
+        }
+        Ok(())
+    }
+
+    fn acquire(&mut self) {
+        self.active += 1;
+        self.window_count += 1;
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.active == 0 && now.duration_since(self.window_start) >= RATE_WINDOW
+    }
+}
+
+// Лимиты лежат рядом со счётчиками: SIGHUP меняет их под тем же mutex
+struct LimiterState {
+    max_per_ip: usize,
+    max_rate_per_ip: u32,
+    cidr_limits: Vec<CidrLimit>,
+    ips: HashMap<IpAddr, Usage>,
+    cidrs: Vec<Usage>,
+    last_prune: Instant,
+}
+
+/// Считает соединения по IP клиента и по подсетям до того, как они попадут в очередь.
+pub struct ConnectionLimiter {
+    state: Mutex<LimiterState>,
+}
+
+/// Held for the lifetime of an accepted connection; releases its slot on drop.
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize, max_rate_per_ip: u32, cidr_limits: Vec<CidrLimit>) -> Self {
+        let now = Instant::now();
+        ConnectionLimiter {
+            state: Mutex::new(LimiterState {
+                max_per_ip,
+                max_rate_per_ip,
+                cidrs: cidr_limits.iter().map(|_| Usage::new(now)).collect(),
+                cidr_limits,
+                ips: HashMap::new(),
+                last_prune: now,
+            }),
+        }
+    }
+
+    /// Replaces the limits without losing track of open connections: a new
+    /// network starts with the connections its addresses already hold, and a
+    /// network that stays keeps its rate window.
+    pub fn reconfigure(&self, max_per_ip: usize, max_rate_per_ip: u32, cidr_limits: Vec<CidrLimit>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let cidrs = cidr_limits
+            .iter()
+            .map(|limit| {
+                let active = state
+                    .ips
+                    .iter()
+                    .filter(|(ip, _)| limit.net.contains(*ip))
+                    .map(|(_, usage)| usage.active)
+                    .sum();
+                let window = state
+                    .cidr_limits
+                    .iter()
+                    .position(|old| old.net == limit.net)
+                    .map(|i| (state.cidrs[i].window_start, state.cidrs[i].window_count));
+                let (window_start, window_count) = window.unwrap_or((now, 0));
+                Usage {
+                    active,
+                    window_start,
+                    window_count,
+                }
+            })
+            .collect();
+        state.max_per_ip = max_per_ip;
+        state.max_rate_per_ip = max_rate_per_ip;
+        state.cidr_limits = cidr_limits;
+        state.cidrs = cidrs;
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        if now.duration_since(state.last_prune) >= RATE_WINDOW {
+            state.ips.retain(|_, usage| !usage.is_idle(now));
+            state.last_prune = now;
+        }
+
+        let matching: Vec<usize> = state
+            .cidr_limits
+            .iter()
+            .enumerate()
+            .filter(|(_, limit)| limit.net.contains(&ip))
+            .map(|(i, _)| i)
+            .collect();
+        for &i in &matching {
+            let limit = &state.cidr_limits[i];
+            state.cidrs[i].check(now, limit.max_concurrent, limit.max_per_second)?;
+        }
+
+        let usage = state.ips.entry(ip).or_insert_with(|| Usage::new(now));
+        usage.check(now, state.max_per_ip, state.max_rate_per_ip)?;
+        usage.acquire();
+        for i in matching {
+            state.cidrs[i].acquire();
+        }
+
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(usage) = state.ips.get_mut(&ip) {
+            usage.active = usage.active.saturating_sub(1);
+        }
+        for (limit, usage) in state.cidr_limits.iter().zip(state.cidrs.iter_mut()) {
+            if limit.net.contains(&ip) {
+                usage.active = usage.active.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_test_limits_chunk_size() {
+        let limits = TestLimits::default();
+        assert_eq!(limits.chunk_size(None), Ok(MIN_CHUNK_SIZE));
+        assert_eq!(limits.chunk_size(Some("8192")), Ok(8192));
+        assert!(limits.chunk_size(Some("1024")).is_err());
+        assert!(limits.chunk_size(Some("8M")).is_err());
+        assert!(limits.validate().is_ok());
+        assert!(TestLimits { min_chunk_size: 8192, max_chunk_size: 4096, ..limits }.validate().is_err());
+        assert!(TestLimits { max_chunk_size: 2 * MAX_CHUNK_SIZE, ..limits }.validate().is_err());
+    }
+
+    #[test]
+    fn test_test_limits_chunk_count() {
+        let limits = TestLimits { max_chunks: 100, ..TestLimits::default() };
+        assert_eq!(limits.chunk_count(Some("1")), Ok(1));
+        assert_eq!(limits.chunk_count(Some("100")), Ok(100));
+        assert!(limits.chunk_count(Some("0")).is_err());
+        assert!(limits.chunk_count(Some("101")).is_err());
+        assert!(limits.chunk_count(Some("-1")).is_err());
+        assert!(limits.chunk_count(None).is_err());
+    }
+
+    #[test]
+    fn test_parse_cidr_limit() {
+        let limit: CidrLimit = "10.1.2.3/8, 100, 50".parse().unwrap();
+        assert_eq!(limit.net, "10.0.0.0/8".parse::<IpNet>().unwrap());
+        assert_eq!(limit.max_concurrent, 100);
+        assert_eq!(limit.max_per_second, 50);
+        assert!("10.0.0.0/8,100".parse::<CidrLimit>().is_err());
+        assert!("not-a-net,1,1".parse::<CidrLimit>().is_err());
+    }
+
+    #[test]
+    fn test_per_ip_concurrency_is_released_on_drop() {
+        let limiter = Arc::new(ConnectionLimiter::new(2, 0, Vec::new()));
+        let first = limiter.try_acquire(ip("192.0.2.1")).unwrap();
+        let _second = limiter.try_acquire(ip("192.0.2.1")).unwrap();
+        assert_eq!(limiter.try_acquire(ip("192.0.2.1")).err(), Some(LimitExceeded::Concurrency));
+        assert!(limiter.try_acquire(ip("192.0.2.2")).is_ok());
+
+        drop(first);
+        assert!(limiter.try_acquire(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn test_per_ip_rate() {
+        let limiter = Arc::new(ConnectionLimiter::new(0, 2, Vec::new()));
+        drop(limiter.try_acquire(ip("192.0.2.1")).unwrap());
+        drop(limiter.try_acquire(ip("192.0.2.1")).unwrap());
+        assert_eq!(limiter.try_acquire(ip("192.0.2.1")).err(), Some(LimitExceeded::Rate));
+    }
+
+    #[test]
+    fn test_cidr_limit_covers_mapped_ipv4() {
+        let limits = vec!["192.0.2.0/24,1,0".parse().unwrap()];
+        let limiter = Arc::new(ConnectionLimiter::new(0, 0, limits));
+        let _permit = limiter.try_acquire(ip("192.0.2.1")).unwrap();
+        assert_eq!(limiter.try_acquire(ip("::ffff:192.0.2.7")).err(), Some(LimitExceeded::Concurrency));
+        assert!(limiter.try_acquire(ip("198.51.100.1")).is_ok());
+    }
+
+    #[test]
+    fn test_reconfigure_keeps_open_connections() {
+        let limiter = Arc::new(ConnectionLimiter::new(0, 0, Vec::new()));
+        let _first = limiter.try_acquire(ip("192.0.2.1")).unwrap();
+        let second = limiter.try_acquire(ip("192.0.2.2")).unwrap();
+
+        limiter.reconfigure(1, 0, vec!["192.0.2.0/24,2,0".parse().unwrap()]);
+        assert_eq!(limiter.try_acquire(ip("192.0.2.1")).err(), Some(LimitExceeded::Concurrency));
+        assert_eq!(limiter.try_acquire(ip("192.0.2.3")).err(), Some(LimitExceeded::Concurrency));
+
+        drop(second);
+        assert!(limiter.try_acquire(ip("192.0.2.3")).is_ok());
+    }
+}
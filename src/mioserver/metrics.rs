@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{debug, info};
+
+use crate::mioserver::limits::LimitExceeded;
+use crate::mioserver::server::AcceptedConnection;
+use crate::mioserver::ServerTestPhase;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+    Quic,
+}
+
+impl Transport {
+    const ALL: [Transport; 5] = [Transport::Tcp, Transport::Tls, Transport::Ws, Transport::Wss, Transport::Quic];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "TCP",
+            Transport::Tls => "TLS",
+            Transport::Ws => "WS",
+            Transport::Wss => "WSS",
+            Transport::Quic => "QUIC",
+        }
+    }
+
+    pub fn websocket(&self) -> Transport {
+        match self {
+            Transport::Tcp | Transport::Ws => Transport::Ws,
+            Transport::Tls | Transport::Wss => Transport::Wss,
+            // WebSocket поверх QUIC не поддерживается, upgrade завершится ошибкой
+            Transport::Quic => Transport::Quic,
+        }
+    }
+}
+
+/// Счётчики сервера, которые воркеры обновляют без блокировок.
+#[derive(Default)]
+pub struct Metrics {
+    pub bytes_sent_gettime: AtomicU64,
+    pub bytes_sent_getchunks: AtomicU64,
+    pub bytes_received_put: AtomicU64,
+    pub bytes_received_putnoresult: AtomicU64,
+    pub bytes_received_puttimeresult: AtomicU64,
+    handshake_failures: [AtomicU64; 5],
+    pub handshake_timeouts: AtomicU64,
+    pub idle_timeouts: AtomicU64,
+    connections_rejected: [AtomicU64; 2],
+    pub connections_denied: AtomicU64,
+    queue_wait_ns_sum: AtomicU64,
+    queue_wait_count: AtomicU64,
+    phases: Mutex<HashMap<usize, HashMap<ServerTestPhase, usize>>>,
+}
+
+impl Metrics {
+    pub fn handshake_failed(&self, transport: Transport) {
+        self.handshake_failures[transport as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self, reason: LimitExceeded) {
+        self.connections_rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_queue_wait(&self, wait: Duration) {
+        self.queue_wait_ns_sum
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        self.queue_wait_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Payload bytes sent by all download commands.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent_gettime.load(Ordering::Relaxed) + self.bytes_sent_getchunks.load(Ordering::Relaxed)
+    }
+
+    /// Payload bytes received by all upload commands.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received_put.load(Ordering::Relaxed)
+            + self.bytes_received_putnoresult.load(Ordering::Relaxed)
+            + self.bytes_received_puttimeresult.load(Ordering::Relaxed)
+    }
+
+    /// Заменяет снимок фаз тестов для одного воркера.
+    pub fn set_worker_phases(&self, worker: usize, phases: HashMap<ServerTestPhase, usize>) {
+        self.phases.lock().unwrap().insert(worker, phases);
+    }
+
+    /// Renders all metrics in the OpenMetrics text format.
+    pub fn render(&self, connections: &[usize], queue_depth: usize, oldest_wait: Duration) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE nettest_worker_connections gauge\n");
+        out.push_str("# HELP nettest_worker_connections Active connections per worker.\n");
+        for (worker, count) in connections.iter().enumerate() {
+            let _ = writeln!(out, "nettest_worker_connections{{worker=\"{}\"}} {}", worker, count);
+        }
+
+        out.push_str("# TYPE nettest_queue_depth gauge\n");
+        out.push_str("# HELP nettest_queue_depth Accepted connections waiting for a worker.\n");
+        let _ = writeln!(out, "nettest_queue_depth {}", queue_depth);
+
+        out.push_str("# TYPE nettest_queue_oldest_wait_seconds gauge\n");
+        out.push_str("# HELP nettest_queue_oldest_wait_seconds Age of the oldest queued connection.\n");
+        let _ = writeln!(out, "nettest_queue_oldest_wait_seconds {}", oldest_wait.as_secs_f64());
+
+        out.push_str("# TYPE nettest_queue_wait_seconds summary\n");
+        out.push_str("# HELP nettest_queue_wait_seconds Time connections spent in the queue before a worker took them.\n");
+        let _ = writeln!(
+            out,
+            "nettest_queue_wait_seconds_sum {}",
+            self.queue_wait_ns_sum.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(
+            out,
+            "nettest_queue_wait_seconds_count {}",
+            self.queue_wait_count.load(Ordering::Relaxed)
+        );
+
+        let mut phases: BTreeMap<String, usize> = BTreeMap::new();
+        for worker_phases in self.phases.lock().unwrap().values() {
+            for (phase, count) in worker_phases {
+                *phases.entry(format!("{:?}", phase)).or_default() += count;
+            }
+        }
+        out.push_str("# TYPE nettest_tests gauge\n");
+        out.push_str("# HELP nettest_tests Connections by current test phase.\n");
+        for (phase, count) in phases {
+            let _ = writeln!(out, "nettest_tests{{phase=\"{}\"}} {}", phase, count);
+        }
+
+        out.push_str("# TYPE nettest_bytes_sent counter\n");
+        out.push_str("# HELP nettest_bytes_sent Payload bytes sent to clients.\n");
+        for (command, value) in [
+            ("GETTIME", &self.bytes_sent_gettime),
+            ("GETCHUNKS", &self.bytes_sent_getchunks),
+        ] {
+            let _ = writeln!(
+                out,
+                "nettest_bytes_sent_total{{command=\"{}\"}} {}",
+                command,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# TYPE nettest_bytes_received counter\n");
+        out.push_str("# HELP nettest_bytes_received Payload bytes received from clients.\n");
+        for (command, value) in [
+            ("PUT", &self.bytes_received_put),
+            ("PUTNORESULT", &self.bytes_received_putnoresult),
+            ("PUTTIMERESULT", &self.bytes_received_puttimeresult),
+        ] {
+            let _ = writeln!(
+                out,
+                "nettest_bytes_received_total{{command=\"{}\"}} {}",
+                command,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# TYPE nettest_handshake_failures counter\n");
+        out.push_str("# HELP nettest_handshake_failures Failed connection handshakes.\n");
+        for transport in Transport::ALL {
+            let _ = writeln!(
+                out,
+                "nettest_handshake_failures_total{{transport=\"{}\"}} {}",
+                transport.label(),
+                self.handshake_failures[transport as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# TYPE nettest_timeouts counter\n");
+        out.push_str("# HELP nettest_timeouts Connections closed on timeout.\n");
+        for (kind, value) in [
+            ("handshake", &self.handshake_timeouts),
+            ("idle", &self.idle_timeouts),
+        ] {
+            let _ = writeln!(
+                out,
+                "nettest_timeouts_total{{kind=\"{}\"}} {}",
+                kind,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# TYPE nettest_connections_rejected counter\n");
+        out.push_str("# HELP nettest_connections_rejected Connections refused by per-IP or per-network limits.\n");
+        for reason in [LimitExceeded::Concurrency, LimitExceeded::Rate] {
+            let _ = writeln!(
+                out,
+                "nettest_connections_rejected_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                self.connections_rejected[reason as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# TYPE nettest_connections_denied counter\n");
+        out.push_str("# HELP nettest_connections_denied Connections refused by the IP allow and deny lists.\n");
+        let _ = writeln!(
+            out,
+            "nettest_connections_denied_total {}",
+            self.connections_denied.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Запускает HTTP listener, который отдаёт метрики по GET /metrics.
+pub fn start_metrics_server(
+    listener: TcpListener,
+    worker_connection_counts: Arc<Mutex<Vec<usize>>>,
+    global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>,
+) -> io::Result<()> {
+    info!("Metrics endpoint listening on http://{}/metrics", listener.local_addr()?);
+
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) =
+                            serve_request(stream, &worker_connection_counts, &global_queue)
+                        {
+                            debug!("Metrics request failed: {}", e);
+                        }
+                    }
+                    Err(e) => debug!("Metrics accept error: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn serve_request(
+    mut stream: TcpStream,
+    worker_connection_counts: &Mutex<Vec<usize>>,
+    global_queue: &Mutex<VecDeque<AcceptedConnection>>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let connections = worker_connection_counts.lock().unwrap().clone();
+            let (queue_depth, oldest_wait) = {
+                let queue = global_queue.lock().unwrap();
+                let oldest = queue.front().map(|entry| entry.queued_at.elapsed()).unwrap_or_default();
+                (queue.len(), oldest)
+            };
+            let body = METRICS.render(&connections, queue_depth, oldest_wait);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                CONTENT_TYPE,
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_openmetrics() {
+        let metrics = Metrics::default();
+        metrics.bytes_sent_gettime.fetch_add(4096, Ordering::Relaxed);
+        metrics.handshake_failed(Transport::Wss);
+        metrics.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+        metrics.connection_rejected(LimitExceeded::Rate);
+        metrics.connections_denied.fetch_add(2, Ordering::Relaxed);
+        metrics.observe_queue_wait(Duration::from_millis(500));
+        metrics.set_worker_phases(0, HashMap::from([(ServerTestPhase::GetTimeSendChunk, 2)]));
+        metrics.set_worker_phases(1, HashMap::from([(ServerTestPhase::GetTimeSendChunk, 1)]));
+
+        let out = metrics.render(&[3, 0], 1, Duration::from_secs(2));
+
+        assert!(out.contains("nettest_worker_connections{worker=\"0\"} 3\n"));
+        assert!(out.contains("nettest_worker_connections{worker=\"1\"} 0\n"));
+        assert!(out.contains("nettest_queue_depth 1\n"));
+        assert!(out.contains("nettest_queue_oldest_wait_seconds 2\n"));
+        assert!(out.contains("nettest_queue_wait_seconds_sum 0.5\n"));
+        assert!(out.contains("nettest_queue_wait_seconds_count 1\n"));
+        assert!(out.contains("nettest_tests{phase=\"GetTimeSendChunk\"} 3\n"));
+        assert!(out.contains("nettest_bytes_sent_total{command=\"GETTIME\"} 4096\n"));
+        assert!(out.contains("nettest_handshake_failures_total{transport=\"WSS\"} 1\n"));
+        assert!(out.contains("nettest_handshake_failures_total{transport=\"TCP\"} 0\n"));
+        assert!(out.contains("nettest_timeouts_total{kind=\"idle\"} 1\n"));
+        assert!(out.contains("nettest_connections_rejected_total{reason=\"rate\"} 1\n"));
+        assert!(out.contains("nettest_connections_denied_total 2\n"));
+        assert!(out.ends_with("# EOF\n"));
+    }
+}
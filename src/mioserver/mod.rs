@@ -1,10 +1,40 @@
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "server")]
 pub mod handlers;
+#[cfg(feature = "server")]
 pub mod server_test_phase;
+#[cfg(feature = "server")]
 pub mod worker;
+#[cfg(feature = "server")]
 pub mod parser;
+#[cfg(feature = "server")]
 pub mod control_server;
+#[cfg(feature = "server")]
+pub mod metrics;
+// Нужен и без сервера: на лимиты ссылается FileConfig
+pub mod limits;
+#[cfg(feature = "server")]
+pub mod tls;
+#[cfg(feature = "server")]
+pub mod session_log;
+#[cfg(feature = "server")]
+pub mod proxy_protocol;
+#[cfg(feature = "server")]
+pub mod http_upgrade;
+#[cfg(feature = "server")]
+pub mod systemd;
+#[cfg(feature = "server")]
+pub mod pacer;
+#[cfg(feature = "server")]
+pub mod reload;
+#[cfg(feature = "server")]
+pub mod ip_filter;
+#[cfg(all(feature = "server", feature = "quic"))]
+pub mod quic;
 
+#[cfg(feature = "server")]
 pub use server::MioServer; 
+#[cfg(feature = "server")]
 pub use server_test_phase::ServerTestPhase;
 
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use mio::Interest;
+
+// Сколько трафика можно отправить подряд после паузы
+const BURST: Duration = Duration::from_millis(50);
+// Не меньше двух минимальных чанков, иначе пауза после каждого чанка
+const MIN_BURST_BYTES: f64 = 8192.0;
+
+/// Bandwidth caps in bits per second: one for all tests and overrides for
+/// tokens signed with a labelled key. A zero cap means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BandwidthCaps {
+    pub default: u64,
+    pub per_label: HashMap<String, u64>,
+}
+
+impl BandwidthCaps {
+    /// Cap for a test whose token was accepted by the key `label`.
+    pub fn cap_for(&self, label: Option<&str>) -> Option<u64> {
+        let cap = label
+            .and_then(|label| self.per_label.get(label))
+            .copied()
+            .unwrap_or(self.default);
+        (cap > 0).then_some(cap)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default == 0 && self.per_label.values().all(|cap| *cap == 0)
+    }
+}
+
+/// Cap for one key label: "partner,100" limits tests with tokens of the
+/// key labelled "partner" to 100 Mbit/s; 0 exempts them from the global cap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelBandwidth {
+    pub label: String,
+    pub bits_per_second: u64,
+}
+
+impl FromStr for LabelBandwidth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((label, mbps)) = s.split_once(',') else {
+            return Err(anyhow::anyhow!("Invalid bandwidth cap '{}', expected <label>,<Mbit/s>", s));
+        };
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(anyhow::anyhow!("Invalid bandwidth cap '{}', empty label", s));
+        }
+        Ok(LabelBandwidth {
+            label: label.to_string(),
+            bits_per_second: parse_mbps(mbps.trim())?,
+        })
+    }
+}
+
+/// "2.5" (Mbit/s) to bits per second.
+pub fn parse_mbps(value: &str) -> Result<u64, anyhow::Error> {
+    let mbps: f64 = value.parse()?;
+    if !mbps.is_finite() || mbps < 0.0 {
+        return Err(anyhow::anyhow!("Invalid bandwidth '{}'", value));
+    }
+    Ok((mbps * 1_000_000.0) as u64)
+}
+
+/// Token bucket for one test. Transfers go into debt by up to one chunk and
+/// then wait until the debt is paid off, so chunks (and websocket frames)
+/// are never split.
+#[derive(Debug)]
+pub struct Pacer {
+    bits_per_second: u64,
+    bytes_per_second: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+    // Когда и в каком направлении продолжить передачу
+    paused: Option<(Instant, Interest)>,
+    throttled: Duration,
+}
+
+impl Pacer {
+    pub fn new(bits_per_second: u64) -> Self {
+        let bytes_per_second = bits_per_second as f64 / 8.0;
+        let burst = (bytes_per_second * BURST.as_secs_f64()).max(MIN_BURST_BYTES);
+        Pacer {
+            bits_per_second,
+            bytes_per_second,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+            paused: None,
+            throttled: Duration::ZERO,
+        }
+    }
+
+    pub fn bits_per_second(&self) -> u64 {
+        self.bits_per_second
+    }
+
+    /// Total time transfers were held back.
+    pub fn throttled(&self) -> Duration {
+        self.throttled
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_second).min(self.burst);
+        self.updated = now;
+    }
+
+    /// Checks the bucket before the next chunk. Returns true if the transfer
+    /// has to wait; the worker resumes it in direction `interest` when due.
+    pub fn pause_if_over(&mut self, interest: Interest) -> bool {
+        let now = Instant::now();
+        // События сокета во время паузы не должны её продлевать
+        if let Some((at, _)) = self.paused {
+            if at > now {
+                return true;
+            }
+            self.paused = None;
+        }
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return false;
+        }
+        let wait = Duration::from_secs_f64(-self.tokens / self.bytes_per_second);
+        self.paused = Some((now + wait, interest));
+        self.throttled += wait;
+        true
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+
+    pub fn resume_at(&self) -> Option<Instant> {
+        self.paused.map(|(at, _)| at)
+    }
+
+    /// Direction of a paused transfer that may continue now.
+    pub fn take_due(&mut self, now: Instant) -> Option<Interest> {
+        match self.paused {
+            Some((at, interest)) if at <= now => {
+                self.paused = None;
+                Some(interest)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_for_label() {
+        let caps = BandwidthCaps {
+            default: 100_000_000,
+            per_label: HashMap::from([("lab".to_string(), 10_000_000), ("partner".to_string(), 0)]),
+        };
+        assert_eq!(caps.cap_for(None), Some(100_000_000));
+        assert_eq!(caps.cap_for(Some("other")), Some(100_000_000));
+        assert_eq!(caps.cap_for(Some("lab")), Some(10_000_000));
+        assert_eq!(caps.cap_for(Some("partner")), None);
+        assert!(BandwidthCaps::default().is_empty());
+    }
+
+    #[test]
+    fn test_parse_label_bandwidth() {
+        let cap: LabelBandwidth = "lab, 2.5".parse().unwrap();
+        assert_eq!(cap.label, "lab");
+        assert_eq!(cap.bits_per_second, 2_500_000);
+        assert!("lab".parse::<LabelBandwidth>().is_err());
+        assert!(",10".parse::<LabelBandwidth>().is_err());
+        assert!("lab,-1".parse::<LabelBandwidth>().is_err());
+    }
+
+    #[test]
+    fn test_pacer_waits_off_debt() {
+        // 8 Mbit/s = 1 MB/s, burst 50 KB
+        let mut pacer = Pacer::new(8_000_000);
+        assert!(!pacer.pause_if_over(Interest::WRITABLE));
+        pacer.consume(150_000);
+        assert!(pacer.pause_if_over(Interest::WRITABLE));
+        let resume_at = pacer.resume_at().unwrap();
+        let wait = resume_at - Instant::now();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100), "{:?}", wait);
+        assert_eq!(pacer.take_due(Instant::now()), None);
+        assert_eq!(pacer.take_due(resume_at), Some(Interest::WRITABLE));
+        assert!(pacer.throttled() >= wait);
+    }
+}
@@ -1,22 +1,71 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use crate::{
-    config::FileConfig, logger, mioserver::server::ServerConfig, tokio_server::{server_config::parse_listen_address, utils::user}
+    config::{parse_listen_address, FileConfig}, mioserver::{ip_filter::parse_network, limits::CidrLimit, pacer::{parse_mbps, BandwidthCaps, LabelBandwidth}, server::ServerConfig}, tokio_server::utils::daemon
 };
+use log::LevelFilter;
 
+/// Builds the server config from the file config and the command line, which
+/// wins. Has no side effects, so SIGHUP can run it again on a changed file.
 pub fn parse_args(
     args: Vec<String>,
     default_config: FileConfig,
 ) -> Result<ServerConfig, anyhow::Error> {
+    let tcp_addresses = if default_config.server_listen.is_empty() {
+        vec![default_config.server_tcp_port.clone()]
+    } else {
+        default_config.server_listen.clone()
+    };
+    let tls_addresses = if default_config.server_tls_listen.is_empty() {
+        vec![default_config.server_tls_port.clone().unwrap_or("443".to_string())]
+    } else {
+        default_config.server_tls_listen.clone()
+    };
+
     let mut config = ServerConfig {
-        tcp_address: parse_listen_address(&default_config.server_tcp_port).unwrap(),
-        tls_address: parse_listen_address(&default_config.server_tls_port.unwrap_or("443".to_string())).unwrap(),
+        tcp_addresses: parse_listen_addresses(&tcp_addresses)?,
+        tls_addresses: parse_listen_addresses(&tls_addresses)?,
+        proxy_protocol_addresses: parse_listen_addresses(&default_config.proxy_protocol_listen)?,
+        quic_addresses: parse_listen_addresses(&default_config.server_quic_listen)?,
         cert_path: default_config.cert_path,
         key_path: default_config.key_path,
         num_workers: default_config.server_workers, 
+        tests_per_worker: default_config.tests_per_worker,
         user: default_config.user,
         daemon: default_config.daemonize,
         version: Some("2.0.0".to_string()),
         secret_key: default_config.secret_key,
-        log_level: None,
+        secret_keys_file: default_config.secret_keys_file,
+        key_grace_period: Duration::from_secs(default_config.key_grace_period),
+        metrics_address: default_config
+            .metrics_listen
+            .map(|addr| parse_listen_address(&addr))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid metrics_listen: {}", e))?,
+        ip_connection_limit: default_config.ip_connection_limit,
+        ip_connection_rate: default_config.ip_connection_rate,
+        cidr_connection_limits: default_config
+            .cidr_connection_limits
+            .iter()
+            .map(|limit| limit.parse())
+            .collect::<Result<Vec<CidrLimit>, _>>()?,
+        ip_allow: default_config.ip_allow.iter().map(|net| parse_network(net)).collect::<Result<_, _>>()?,
+        ip_deny: default_config.ip_deny.iter().map(|net| parse_network(net)).collect::<Result<_, _>>()?,
+        ip_allow_file: default_config.ip_allow_file,
+        ip_deny_file: default_config.ip_deny_file,
+        drain_timeout: Duration::from_secs(default_config.drain_timeout),
+        limits: default_config.test_limits,
+        bandwidth_caps: BandwidthCaps {
+            default: default_config.max_bandwidth.as_deref().map(parse_mbps).transpose()?.unwrap_or(0),
+            per_label: HashMap::new(),
+        },
+        reuse_port: default_config.reuse_port,
+        pid_file: default_config.pid_file,
+        session_log: default_config.session_log,
+        // -log важнее ключа logger из файла
+        log_level: (default_config.logger != LevelFilter::Off).then_some(default_config.logger),
         server_registration: default_config.server_registration,
         control_server: default_config.control_server,
         hostname: default_config.hostname,
@@ -24,17 +73,58 @@ pub fn parse_args(
         registration_token: default_config.registration_token,
     };
 
+    for cap in &default_config.label_bandwidth_limits {
+        let cap: LabelBandwidth = cap.parse()?;
+        config.bandwidth_caps.per_label.insert(cap.label, cap.bits_per_second);
+    }
+
+    // Первый -l/-L заменяет адреса из конфига, следующие добавляются
+    let mut tcp_from_args = false;
+    let mut tls_from_args = false;
+    let mut quic_from_args = false;
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "-l" | "-L" => {
                 i += 1;
                 if i < args.len() {
-                    let addr = parse_listen_address(&args[i]).unwrap();
-                    if args[i - 1] == "-L" {
-                        config.tls_address = addr;
+                    let addr = parse_listen_address(&args[i])
+                        .map_err(|e| anyhow::anyhow!("Invalid listen address '{}': {}", args[i], e))?;
+                    let (addresses, from_args) = if args[i - 1] == "-L" {
+                        (&mut config.tls_addresses, &mut tls_from_args)
                     } else {
-                        config.tcp_address = addr;
+                        (&mut config.tcp_addresses, &mut tcp_from_args)
+                    };
+                    if !*from_args {
+                        addresses.clear();
+                        *from_args = true;
+                    }
+                    if !addresses.contains(&addr) {
+                        addresses.push(addr);
+                    }
+                }
+            }
+            "-quic-listen" => {
+                i += 1;
+                if i < args.len() {
+                    let addr = parse_listen_address(&args[i])
+                        .map_err(|e| anyhow::anyhow!("Invalid QUIC listen address '{}': {}", args[i], e))?;
+                    if !quic_from_args {
+                        config.quic_addresses.clear();
+                        quic_from_args = true;
+                    }
+                    if !config.quic_addresses.contains(&addr) {
+                        config.quic_addresses.push(addr);
+                    }
+                }
+            }
+            "-proxy" => {
+                i += 1;
+                if i < args.len() {
+                    let addr = parse_listen_address(&args[i])
+                        .map_err(|e| anyhow::anyhow!("Invalid PROXY protocol address '{}': {}", args[i], e))?;
+                    if !config.proxy_protocol_addresses.contains(&addr) {
+                        config.proxy_protocol_addresses.push(addr);
                     }
                 }
             }
@@ -56,17 +146,33 @@ pub fn parse_args(
                     config.num_workers = Some(args[i].parse().unwrap());
                 }
             }
+            "-m" => {
+                i += 1;
+                if i < args.len() {
+                    config.tests_per_worker = args[i].parse::<usize>()?.max(1);
+                }
+            }
             "-u" => {
+                i += 1;
                 if i < args.len() {
-                    user::UserPrivileges::check_root()?;
-                    i += 1;
                     config.user = Some(args[i].clone());
                 }
-                i += 1;
             }
             "-d" => {
                 config.daemon = true;
             }
+            "-session-log" => {
+                i += 1;
+                if i < args.len() {
+                    config.session_log = Some(args[i].clone());
+                }
+            }
+            "-pid" => {
+                i += 1;
+                if i < args.len() {
+                    config.pid_file = Some(args[i].clone());
+                }
+            }
             "-log" => {
                 i += 1;
                 if i < args.len() {
@@ -79,6 +185,139 @@ pub fn parse_args(
                     config.secret_key = Some(args[i].clone());
                 }
             }
+            "-keys" => {
+                i += 1;
+                if i < args.len() {
+                    config.secret_keys_file = Some(args[i].clone());
+                }
+            }
+            "-key-grace" => {
+                i += 1;
+                if i < args.len() {
+                    config.key_grace_period = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-metrics" => {
+                i += 1;
+                if i < args.len() {
+                    config.metrics_address = Some(
+                        parse_listen_address(&args[i])
+                            .map_err(|e| anyhow::anyhow!("Invalid metrics address: {}", e))?,
+                    );
+                }
+            }
+            "-ip-limit" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_connection_limit = args[i].parse()?;
+                }
+            }
+            "-ip-rate" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_connection_rate = args[i].parse()?;
+                }
+            }
+            "-cidr-limit" => {
+                i += 1;
+                if i < args.len() {
+                    config.cidr_connection_limits.push(args[i].parse()?);
+                }
+            }
+            "-allow" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_allow.push(parse_network(&args[i])?);
+                }
+            }
+            "-deny" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_deny.push(parse_network(&args[i])?);
+                }
+            }
+            "-allow-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_allow_file = Some(args[i].clone());
+                }
+            }
+            "-deny-file" => {
+                i += 1;
+                if i < args.len() {
+                    config.ip_deny_file = Some(args[i].clone());
+                }
+            }
+            "-reuseport" => {
+                config.reuse_port = true;
+            }
+            "-drain" => {
+                i += 1;
+                if i < args.len() {
+                    config.drain_timeout = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-max-gettime" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.max_gettime = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-max-put" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.max_put_bytes = args[i].parse()?;
+                }
+            }
+            "-max-chunks" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.max_chunks = args[i].parse()?;
+                }
+            }
+            "-min-chunk" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.min_chunk_size = args[i].parse()?;
+                }
+            }
+            "-max-chunk" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.max_chunk_size = args[i].parse()?;
+                }
+            }
+            "-idle-timeout" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.idle_timeout = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-handshake-timeout" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.handshake_timeout = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-max-session" => {
+                i += 1;
+                if i < args.len() {
+                    config.limits.max_session = Duration::from_secs(args[i].parse()?);
+                }
+            }
+            "-bandwidth" => {
+                i += 1;
+                if i < args.len() {
+                    config.bandwidth_caps.default = parse_mbps(&args[i])?;
+                }
+            }
+            "-label-bandwidth" => {
+                i += 1;
+                if i < args.len() {
+                    let cap: LabelBandwidth = args[i].parse()?;
+                    config.bandwidth_caps.per_label.insert(cap.label, cap.bits_per_second);
+                }
+            }
             "-register" => {
                 config.server_registration = true;
             }
@@ -92,14 +331,30 @@ pub fn parse_args(
         }
         i += 1;
     }
-    if config.log_level.is_some() {
-        logger::init_logger(config.log_level.unwrap()).unwrap();
+    config.limits.validate()?;
+    for addr in &config.proxy_protocol_addresses {
+        if !config.tcp_addresses.contains(addr) && !config.tls_addresses.contains(addr) {
+            return Err(anyhow::anyhow!("PROXY protocol address {} is not a listen address", addr));
+        }
+    }
+    if config.daemon && config.pid_file.is_none() {
+        config.pid_file = Some(daemon::DEFAULT_PID_FILE.to_string());
     }
     Ok(config)
 }
 
 
 
+fn parse_listen_addresses(addresses: &[String]) -> Result<Vec<SocketAddr>, anyhow::Error> {
+    addresses
+        .iter()
+        .map(|addr| {
+            parse_listen_address(addr)
+                .map_err(|e| anyhow::anyhow!("Invalid listen address '{}': {}", addr, e))
+        })
+        .collect()
+}
+
 fn print_help() {
     println!("==== Nettest Server ====");
     println!("By default, rmbtd will listen TCP on port 5005");
@@ -108,15 +363,163 @@ fn print_help() {
     println!("Usage: nettest -s [-l <listen_address>] [-c <cert_path>] [-k <key_path>] [-t <num_threads>] [-u <user>] [-d] [-D] [-w] [-v <version>]");
     println!("command line arguments:\n");
     println!(" -l/-L  listen on (IP and) port; -L for SSL; default port is 5005, 443 for TLS");
+    println!("        may be repeated; a port alone listens on both IPv4 and IPv6");
     println!("        examples: \"443\",\"1.2.3.4:1234\",\"[2001:1234::567A]:1234\"");
+    println!(" -proxy expect a PROXY protocol v1/v2 header on connections to this -l/-L address;");
+    println!("        may be repeated; use only behind a balancer, the header sets the client address\n");
+    println!("        under systemd socket activation the sockets from LISTEN_FDS are used instead;");
+    println!("        name them tcp, tls, tcp-proxy or tls-proxy with FileDescriptorName=\n");
+    println!(" -quic-listen  serve QUIC on this UDP (IP and) port, e.g. \"5005\"; may be repeated;");
+    println!("        uses the -c/-k certificate, every client stream is one test connection;");
+    println!("        all QUIC packets of one address are handled by a single thread, so one");
+    println!("        address is limited to one core; listen on several ports to use more\n");
     println!(" -c     path to SSL certificate in PEM format;");
     println!("        intermediate certificates following server cert in same file if needed");
     println!("        required\n");
-    println!(" -k     path to SSL key file in PEM format; required\n");
+    println!(" -k     path to SSL key file in PEM format; required");
+    println!("        certificate and key are reloaded on SIGHUP or when the files change\n");
     println!(" -t     number of worker threads to run for handling connections (default: 200)\n");
-    println!(" -u     drop root privileges and setuid to specified user; must be root\n");
+    println!(" -m     number of tests a worker thread runs at once (default: 4)\n");
+    println!(" -u     drop root privileges and setuid to specified user after binding ports; must be root;");
+    println!("        SIGHUP and certificate reloads run as that user, so -c/-k, -keys and the allow/deny");
+    println!("        files must stay readable by it (e.g. group-readable, not root-only 0600)\n");
     println!(" -d     fork into background as daemon (no argument)\n");
-    println!(" -log    log level: info, debug, trace\n");
+    println!(" -session-log  append one JSON line per finished test session to this file\n");
+    println!(" -pid   write PID to this file (default with -d: /run/nettest.pid)\n");
+    println!(" -log    log level: info, debug, trace (default: logger from the config file)\n");
     println!(" -e     encryption key for resut signature\n");
-    println!(" -register  enable server registration\n");
+    println!(" -keys  file with secret keys for token validation, one \"key label\" per line;");
+    println!("        re-read on SIGHUP, any listed key is accepted and the session log records its label\n");
+    println!(" -key-grace  seconds a key removed from the keys file on SIGHUP is still accepted (default: 300)\n");
+    println!(" -metrics  serve OpenMetrics on (IP and) port, e.g. \"127.0.0.1:9105\"\n");
+    println!(" -ip-limit  max concurrent connections per client IP (default: unlimited)\n");
+    println!(" -ip-rate  max new connections per second per client IP (default: unlimited)\n");
+    println!(" -cidr-limit  limits for a network: <cidr>,<max_concurrent>,<max_per_second>;");
+    println!("        may be repeated, 0 disables a limit\n");
+    println!(" -allow/-deny  serve only / never serve this network, e.g. \"192.0.2.0/24\" or \"2001:db8::/32\";");
+    println!("        may be repeated; deny wins, and with an allow list everyone else is refused\n");
+    println!(" -allow-file/-deny-file  the same, read from a file with one network per line\n");
+    println!(" -reuseport  open one SO_REUSEPORT listener per worker instead of a shared accept queue\n");
+    println!(" -drain  seconds to let running tests finish on SIGINT/SIGTERM (default: 60)\n");
+    println!(" -max-gettime  longest GETTIME in seconds (default: 60, 0: unlimited)\n");
+    println!(" -max-put  bytes a single PUT command may upload (default: unlimited)\n");
+    println!(" -max-chunks  most chunks a GETCHUNKS may ask for (default: 300000)\n");
+    println!(" -min-chunk/-max-chunk  allowed chunk sizes in bytes (default: 4096 and 4194304)\n");
+    println!(" -idle-timeout  seconds without traffic before a test is dropped (default: 15)\n");
+    println!(" -handshake-timeout  seconds for the upgrade request and TLS handshake (default: 3)\n");
+    println!(" -max-session  longest test session in seconds (default: unlimited)");
+    println!("        commands beyond these limits are answered with ERR\n");
+    println!(" -bandwidth  cap per test in Mbit/s for downloads and uploads (default: unlimited)\n");
+    println!(" -label-bandwidth  cap for tokens of a labelled key: <label>,<Mbit/s>;");
+    println!("        may be repeated, 0 exempts the label from -bandwidth\n");
+    println!(" -register  enable server registration; without -keys any client can test\n");
+    println!(" --config  config file to read instead of ~/.config/nettest/nettest.conf or /etc/nettest.conf;");
+    println!("        NETTEST_<KEY> environment variables override its keys;");
+    println!("        SIGHUP reads it again and applies log level, keys, connection and test limits, allow/deny lists,");
+    println!("        bandwidth caps and registration settings; listen addresses and workers need a restart\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("-s").chain(list.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_repeated_listen_addresses() {
+        let config = parse_args(
+            args(&["-l", "127.0.0.1:5005", "-l", "[::1]:5006", "-L", "8443"]),
+            FileConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.tcp_addresses,
+            vec!["127.0.0.1:5005".parse().unwrap(), "[::1]:5006".parse().unwrap()]
+        );
+        assert_eq!(config.tls_addresses, vec!["[::]:8443".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_default_listen_addresses() {
+        let config = parse_args(args(&[]), FileConfig::default()).unwrap();
+        assert_eq!(config.tcp_addresses, vec!["[::]:5005".parse().unwrap()]);
+        assert_eq!(config.tls_addresses, vec!["[::]:443".parse().unwrap()]);
+        assert!(parse_args(args(&["-l", "localhost:80"]), FileConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_proxy_protocol_addresses() {
+        let config = parse_args(
+            args(&["-l", "5005", "-l", "127.0.0.1:5006", "-proxy", "127.0.0.1:5006"]),
+            FileConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(config.proxy_protocol_addresses, vec!["127.0.0.1:5006".parse().unwrap()]);
+        assert!(parse_args(args(&["-l", "5005", "-proxy", "5006"]), FileConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_quic_listen_addresses() {
+        let config = parse_args(args(&[]), FileConfig::default()).unwrap();
+        assert!(config.quic_addresses.is_empty());
+
+        let file_config = FileConfig {
+            server_quic_listen: vec!["443".to_string()],
+            ..FileConfig::default()
+        };
+        let config = parse_args(args(&[]), file_config.clone()).unwrap();
+        assert_eq!(config.quic_addresses, vec!["[::]:443".parse().unwrap()]);
+
+        let config = parse_args(args(&["-quic-listen", "127.0.0.1:5005", "-quic-listen", "5006"]), file_config).unwrap();
+        assert_eq!(
+            config.quic_addresses,
+            vec!["127.0.0.1:5005".parse().unwrap(), "[::]:5006".parse().unwrap()]
+        );
+        assert!(parse_args(args(&["-quic-listen", "localhost:443"]), FileConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_test_limits() {
+        let config = parse_args(
+            args(&["-max-gettime", "10", "-max-put", "1000000", "-idle-timeout", "30", "-max-chunk", "65536"]),
+            FileConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(config.limits.max_gettime, Duration::from_secs(10));
+        assert_eq!(config.limits.max_put_bytes, 1_000_000);
+        assert_eq!(config.limits.idle_timeout, Duration::from_secs(30));
+        assert_eq!(config.limits.max_chunk_size, 65536);
+        assert!(parse_args(args(&["-max-chunk", "1024"]), FileConfig::default()).is_err());
+        assert!(parse_args(args(&["-handshake-timeout", "0"]), FileConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_caps() {
+        let file_config = FileConfig {
+            max_bandwidth: Some("100".to_string()),
+            label_bandwidth_limits: vec!["lab,10".to_string()],
+            ..FileConfig::default()
+        };
+        let config = parse_args(args(&["-label-bandwidth", "partner,0"]), file_config.clone()).unwrap();
+        assert_eq!(config.bandwidth_caps.default, 100_000_000);
+        assert_eq!(config.bandwidth_caps.cap_for(Some("lab")), Some(10_000_000));
+        assert_eq!(config.bandwidth_caps.cap_for(Some("partner")), None);
+
+        let config = parse_args(args(&["-bandwidth", "0.5"]), file_config).unwrap();
+        assert_eq!(config.bandwidth_caps.cap_for(None), Some(500_000));
+        assert!(parse_args(args(&["-bandwidth", "fast"]), FileConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_daemon_pid_file() {
+        let config = parse_args(args(&["-d"]), FileConfig::default()).unwrap();
+        assert!(config.daemon);
+        assert_eq!(config.pid_file.as_deref(), Some(daemon::DEFAULT_PID_FILE));
+
+        let config = parse_args(args(&["-pid", "/tmp/nettest.pid", "-l", "5005"]), FileConfig::default()).unwrap();
+        assert!(!config.daemon);
+        assert_eq!(config.pid_file.as_deref(), Some("/tmp/nettest.pid"));
+    }
 }
@@ -0,0 +1,213 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Максимальная длина строки v1 по спецификации, включая CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+/// Upper bound for a v2 header including TLVs; balancers send far less.
+pub const MAX_HEADER_LEN: usize = 1024;
+
+/// What a PROXY protocol v1/v2 header says about the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// The balancer's own connection (health check, v1 UNKNOWN, v2 LOCAL):
+    /// the socket's peer address is kept.
+    Local,
+    Proxied { source: SocketAddr, destination: SocketAddr },
+}
+
+/// Parses a PROXY header at the start of `buf`. Returns the header and its
+/// length, or `None` if more data is needed.
+pub fn parse_header(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.len() < V2_SIGNATURE.len() && V2_SIGNATURE.starts_with(buf) {
+        return Ok(None);
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+    let prefix_len = buf.len().min(V1_PREFIX.len());
+    if buf[..prefix_len] == V1_PREFIX[..prefix_len] {
+        return parse_v1(buf);
+    }
+    Err(invalid("connection does not start with a PROXY protocol header"))
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header is too long"));
+        }
+        return Ok(None);
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err(invalid("PROXY v1 header is too long"));
+    }
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["UNKNOWN", ..] => Ok(Some((ProxyHeader::Local, len))),
+        [family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let source: IpAddr = source.parse().map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let destination: IpAddr = destination
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 destination address"))?;
+            if source.is_ipv4() != (*family == "TCP4") || destination.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            let source_port: u16 = source_port.parse().map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            let destination_port: u16 = destination_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 destination port"))?;
+            Ok(Some((
+                ProxyHeader::Proxied {
+                    source: SocketAddr::new(source, source_port),
+                    destination: SocketAddr::new(destination, destination_port),
+                },
+                len,
+            )))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if len > MAX_HEADER_LEN {
+        return Err(invalid("PROXY v2 header is too long"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[V2_HEADER_LEN..len];
+    match command {
+        0x0 => return Ok(Some((ProxyHeader::Local, len))),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    let header = match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let source = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let destination = Ipv4Addr::new(addresses[4], addresses[5], addresses[6], addresses[7]);
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(source.into(), u16::from_be_bytes([addresses[8], addresses[9]])),
+                destination: SocketAddr::new(destination.into(), u16::from_be_bytes([addresses[10], addresses[11]])),
+            }
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let source: [u8; 16] = addresses[0..16].try_into().unwrap();
+            let destination: [u8; 16] = addresses[16..32].try_into().unwrap();
+            ProxyHeader::Proxied {
+                source: SocketAddr::new(Ipv6Addr::from(source).into(), u16::from_be_bytes([addresses[32], addresses[33]])),
+                destination: SocketAddr::new(
+                    Ipv6Addr::from(destination).into(),
+                    u16::from_be_bytes([addresses[34], addresses[35]]),
+                ),
+            }
+        }
+        // UNSPEC: адреса нет, как у LOCAL
+        0x00 => ProxyHeader::Local,
+        _ => return Err(invalid("unsupported PROXY v2 address family")),
+    };
+    Ok(Some((header, len)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 443\r\nGET / HTTP/1.1\r\n";
+        let (header, len) = parse_header(buf).unwrap().unwrap();
+        assert_eq!(len, 45);
+        assert_eq!(
+            header,
+            ProxyHeader::Proxied {
+                source: "192.0.2.1:40000".parse().unwrap(),
+                destination: "198.51.100.1:443".parse().unwrap(),
+            }
+        );
+
+        let (header, _) = parse_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 443\r\n").unwrap().unwrap();
+        assert!(matches!(header, ProxyHeader::Proxied { source, .. } if source == "[2001:db8::1]:40000".parse().unwrap()));
+        assert_eq!(parse_header(b"PROXY UNKNOWN\r\n").unwrap(), Some((ProxyHeader::Local, 15)));
+    }
+
+    #[test]
+    fn test_parse_v1_incomplete_and_invalid() {
+        assert_eq!(parse_header(b"PRO").unwrap(), None);
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1").unwrap(), None);
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 2\r\n").is_err());
+        assert!(parse_header(&[b'P'; 200]).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend_from_slice(&40000u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // TLV после адресов пропускается
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let mut buf = v2(0x1, 0x11, &addresses);
+        let header_len = buf.len();
+        buf.extend_from_slice(b"\x16\x03\x01");
+
+        let (header, len) = parse_header(&buf).unwrap().unwrap();
+        assert_eq!(len, header_len);
+        assert_eq!(
+            header,
+            ProxyHeader::Proxied {
+                source: "192.0.2.1:40000".parse().unwrap(),
+                destination: "198.51.100.1:443".parse().unwrap(),
+            }
+        );
+        assert_eq!(parse_header(&buf[..20]).unwrap(), None);
+        assert_eq!(parse_header(&buf[..5]).unwrap(), None);
+
+        let local = v2(0x0, 0x00, &[]);
+        assert_eq!(parse_header(&local).unwrap(), Some((ProxyHeader::Local, 16)));
+    }
+
+    #[test]
+    fn test_parse_v2_ipv6() {
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0x9c, 0x40, 0x01, 0xbb]);
+        let (header, len) = parse_header(&v2(0x1, 0x21, &addresses)).unwrap().unwrap();
+        assert_eq!(len, 52);
+        assert!(matches!(header, ProxyHeader::Proxied { source, destination }
+            if source == "[2001:db8::1]:40000".parse().unwrap() && destination.port() == 443));
+        assert!(parse_header(&v2(0x1, 0x21, &addresses[..12])).is_err());
+    }
+}
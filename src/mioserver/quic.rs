@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::info;
+use mio::net::UdpSocket;
+use mio::Waker;
+
+use crate::mioserver::limits::ConnectionLimiter;
+use crate::mioserver::metrics::Transport;
+use crate::mioserver::reload::RuntimeConfigStore;
+use crate::mioserver::server::{acquire_permit, ip_permitted, AcceptedConnection, ConnectionType};
+use crate::mioserver::tls::TlsConfigStore;
+use crate::stream::quic::{self, QuicAcceptor, QuicStream};
+
+/// Streams accepted by the QUIC listeners, waiting for the acceptor to hand
+/// them to workers like TCP connections.
+pub struct QuicQueue {
+    pub connections: Mutex<VecDeque<AcceptedConnection>>,
+    pub waker: Waker,
+}
+
+/// Admission checks of a QUIC listener: allow/deny lists per connection,
+/// per-IP limits per stream, since every stream is one test thread.
+struct QuicHandoff {
+    queue: Arc<QuicQueue>,
+    runtime: Arc<RuntimeConfigStore>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    tls_config: Arc<TlsConfigStore>,
+    accepting: Arc<AtomicBool>,
+}
+
+impl QuicAcceptor for QuicHandoff {
+    fn accept_connection(&mut self, remote: SocketAddr) -> Option<Arc<quinn_proto::ServerConfig>> {
+        if !self.accepting.load(Ordering::Relaxed) {
+            return None;
+        }
+        if !ip_permitted(&self.runtime.current().ip_filter, remote, Transport::Quic) {
+            return None;
+        }
+        Some(self.tls_config.current_quic())
+    }
+
+    fn accept_stream(&mut self, stream: QuicStream) {
+        // Без разрешения поток закрывается, клиент получит reset
+        let Some(permit) = acquire_permit(&self.connection_limiter, stream.peer_addr()) else {
+            return;
+        };
+        self.queue.connections.lock().unwrap().push_back(AcceptedConnection {
+            connection: ConnectionType::Quic(stream),
+            queued_at: Instant::now(),
+            permit: Some(permit),
+            proxy_protocol: false,
+        });
+        if let Err(e) = self.queue.waker.wake() {
+            info!("Failed to wake acceptor for QUIC stream: {}", e);
+        }
+    }
+}
+
+/// Binds the UDP sockets while the server still runs as root.
+pub fn bind_quic_listeners(addresses: &[SocketAddr]) -> io::Result<Vec<UdpSocket>> {
+    let mut sockets = Vec::new();
+    for &addr in addresses {
+        // Как у TCP: [::] принимает и IPv4, если рядом нет 0.0.0.0 на том же порту
+        let only_v6 = addr.is_ipv6()
+            && addresses
+                .iter()
+                .any(|other| other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port());
+        let socket = quic::bind_udp_socket(addr, only_v6)?;
+        info!("MIO QUIC Server will listen on UDP {}", socket.local_addr()?);
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+/// Starts a driver thread for every bound QUIC socket.
+pub fn start_quic_listeners(
+    sockets: Vec<UdpSocket>,
+    queue: Arc<QuicQueue>,
+    runtime: Arc<RuntimeConfigStore>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    tls_config: Arc<TlsConfigStore>,
+    accepting: Arc<AtomicBool>,
+) -> io::Result<()> {
+    for socket in sockets {
+        let handoff = QuicHandoff {
+            queue: queue.clone(),
+            runtime: runtime.clone(),
+            connection_limiter: connection_limiter.clone(),
+            tls_config: tls_config.clone(),
+            accepting: accepting.clone(),
+        };
+        quic::listen(socket, tls_config.current_quic(), Box::new(handoff))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileConfig;
+    use crate::mioserver::parser::parse_args;
+    use crate::mioserver::reload::RuntimeConfig;
+    use crate::stream::quic::tests::{read_line, write_line, TEST_CERT, TEST_KEY};
+    use mio::{Poll, Token};
+
+    #[test]
+    fn test_stream_without_permit_is_dropped() {
+        let poll = Poll::new().unwrap();
+        let queue = Arc::new(QuicQueue {
+            connections: Mutex::new(VecDeque::new()),
+            waker: Waker::new(poll.registry(), Token(0)).unwrap(),
+        });
+        let config = parse_args(vec!["-s".to_string()], FileConfig::default()).unwrap();
+        let runtime = RuntimeConfigStore::new(RuntimeConfig::new(config).unwrap());
+        let connection_limiter = Arc::new(ConnectionLimiter::new(1, 0, Vec::new()));
+        let tls_config = TlsConfigStore::load(TEST_CERT.to_string(), TEST_KEY.to_string()).unwrap();
+        let sockets = bind_quic_listeners(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+        start_quic_listeners(
+            sockets,
+            queue.clone(),
+            runtime,
+            connection_limiter.clone(),
+            tls_config,
+            Arc::new(AtomicBool::new(true)),
+        )
+        .unwrap();
+
+        // Единственное разрешение для 127.0.0.1 уже занято
+        let permit = connection_limiter.try_acquire(addr.ip()).unwrap();
+        let mut streams = quic::connect(addr, 1).unwrap();
+        write_line(&mut streams[0], "GETTIME 1");
+        assert_eq!(read_line(&mut streams[0]), None);
+        assert!(queue.connections.lock().unwrap().is_empty());
+
+        drop(permit);
+        let mut streams = quic::connect(addr, 1).unwrap();
+        write_line(&mut streams[0], "GETTIME 1");
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        while queue.connections.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "stream was not queued");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let accepted = queue.connections.lock().unwrap().pop_front().unwrap();
+        assert!(matches!(accepted.connection, ConnectionType::Quic(_)));
+        assert!(accepted.permit.is_some());
+    }
+}
@@ -0,0 +1,250 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use log::{info, warn, LevelFilter};
+
+use crate::config::parser::read_config_file;
+use crate::logger;
+use crate::mioserver::ip_filter::{read_network_file, IpFilter};
+use crate::mioserver::limits::ConnectionLimiter;
+use crate::mioserver::pacer::BandwidthCaps;
+use crate::mioserver::parser::parse_args;
+use crate::mioserver::server::{file_read_error, ServerConfig};
+use crate::tokio_server::utils::secret_keys::read_secret_keys;
+use crate::tokio_server::utils::token_validator::TokenValidator;
+
+/// Server settings together with what is built from them, swapped as a whole
+/// on SIGHUP. Running tests keep the snapshot they started with.
+pub struct RuntimeConfig {
+    pub server_config: ServerConfig,
+    pub token_validator: Option<Arc<TokenValidator>>,
+    pub bandwidth_caps: Arc<BandwidthCaps>,
+    pub ip_filter: IpFilter,
+}
+
+impl RuntimeConfig {
+    /// Reads the secret keys and network list files, so a broken file fails
+    /// here and not in a worker.
+    pub fn new(server_config: ServerConfig) -> io::Result<Self> {
+        let token_validator = match &server_config.secret_keys_file {
+            Some(path) => {
+                let keys = read_secret_keys(path).map_err(|e| file_read_error(path, e))?;
+                if keys.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("No secret keys found in {}", path),
+                    ));
+                }
+                for label in server_config.bandwidth_caps.per_label.keys() {
+                    if !keys.iter().any(|k| &k.label == label) {
+                        info!("Bandwidth cap for unknown key label {}", label);
+                    }
+                }
+                Some(Arc::new(TokenValidator::new(
+                    keys.iter().map(|k| k.key.clone()).collect(),
+                    keys.iter().map(|k| k.label.clone()).collect(),
+                )))
+            }
+            // Зарегистрированный сервер виден всем клиентам, без ключей он открыт любому
+            None if server_config.server_registration => {
+                warn!("Server registration without secret_keys_file (-keys): any client can run tests here");
+                None
+            }
+            None => {
+                warn!("No secret keys file configured, client tokens are not validated");
+                None
+            }
+        };
+
+        if !server_config.bandwidth_caps.is_empty() {
+            info!(
+                "Bandwidth caps: {} bit/s per test, {} key label overrides",
+                server_config.bandwidth_caps.default,
+                server_config.bandwidth_caps.per_label.len()
+            );
+        }
+
+        let ip_filter = build_ip_filter(&server_config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if !ip_filter.is_empty() {
+            info!(
+                "IP filter: {} allowed and {} denied networks",
+                ip_filter.allow_count(),
+                ip_filter.deny_count()
+            );
+        }
+
+        Ok(RuntimeConfig {
+            bandwidth_caps: Arc::new(server_config.bandwidth_caps.clone()),
+            server_config,
+            token_validator,
+            ip_filter,
+        })
+    }
+}
+
+fn build_ip_filter(config: &ServerConfig) -> Result<IpFilter, anyhow::Error> {
+    let mut allow = config.ip_allow.clone();
+    if let Some(path) = &config.ip_allow_file {
+        allow.extend(read_network_file(path)?);
+    }
+    let mut deny = config.ip_deny.clone();
+    if let Some(path) = &config.ip_deny_file {
+        deny.extend(read_network_file(path)?);
+    }
+    Ok(IpFilter::new(allow, deny))
+}
+
+/// Current `RuntimeConfig`; workers take it for every new connection.
+pub struct RuntimeConfigStore {
+    current: RwLock<Arc<RuntimeConfig>>,
+}
+
+impl RuntimeConfigStore {
+    pub fn new(config: RuntimeConfig) -> Arc<Self> {
+        Arc::new(RuntimeConfigStore {
+            current: RwLock::new(Arc::new(config)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RuntimeConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn replace(&self, config: RuntimeConfig) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// Re-reads the config file and the original command line on SIGHUP.
+pub struct Reloader {
+    pub args: Vec<String>,
+    // None: тот же поиск файла, что и при запуске
+    pub config_path: Option<PathBuf>,
+    pub runtime: Arc<RuntimeConfigStore>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
+}
+
+impl Reloader {
+    /// Applies everything that can change without rebinding sockets. Nothing is
+    /// applied unless the whole new config is valid.
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let file_config = read_config_file(self.config_path.clone())?;
+        let parsed = parse_args(self.args.clone(), file_config)?;
+        let current = self.runtime.current();
+        let (server_config, needs_restart) = merge_reloadable(&current.server_config, parsed);
+        let mut next = RuntimeConfig::new(server_config)?;
+        if let (Some(previous), Some(validator)) = (&current.token_validator, &next.token_validator) {
+            next.token_validator = Some(Arc::new(previous.rotate(validator, next.server_config.key_grace_period)));
+        }
+
+        for setting in needs_restart {
+            report(&format!("Changed {} takes effect after a restart", setting));
+        }
+        let level = next.server_config.log_level.unwrap_or(LevelFilter::Off);
+        if next.server_config.log_level != current.server_config.log_level && !logger::set_level(level) {
+            report("Logging was not enabled at startup, restart to enable it");
+        }
+        self.connection_limiter.reconfigure(
+            next.server_config.ip_connection_limit,
+            next.server_config.ip_connection_rate,
+            next.server_config.cidr_connection_limits.clone(),
+        );
+        self.runtime.replace(next);
+        report("Configuration reloaded");
+        Ok(())
+    }
+}
+
+/// Logs a reload outcome; without a logger it goes to stderr, so a SIGHUP
+/// is never answered with silence.
+pub fn report(message: &str) {
+    if logger::is_enabled() {
+        info!("{}", message);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+/// Takes the settings that can change at runtime from `parsed` and keeps the
+/// rest of `current`. Also returns the kept settings that differ.
+fn merge_reloadable(current: &ServerConfig, parsed: ServerConfig) -> (ServerConfig, Vec<&'static str>) {
+    let mut needs_restart = Vec::new();
+    let mut check = |changed: bool, name: &'static str| {
+        if changed {
+            needs_restart.push(name);
+        }
+    };
+    check(parsed.tcp_addresses != current.tcp_addresses, "server_listen");
+    check(parsed.tls_addresses != current.tls_addresses, "server_tls_listen");
+    check(parsed.proxy_protocol_addresses != current.proxy_protocol_addresses, "proxy_protocol_listen");
+    check(parsed.quic_addresses != current.quic_addresses, "server_quic_listen");
+    // Сам сертификат перечитывается, а пути к нему — нет
+    check(parsed.cert_path != current.cert_path || parsed.key_path != current.key_path, "cert_path/key_path");
+    check(parsed.num_workers != current.num_workers, "server_workers");
+    check(parsed.tests_per_worker != current.tests_per_worker, "tests_per_worker");
+    check(parsed.user != current.user, "user");
+    check(parsed.daemon != current.daemon, "daemonize");
+    check(parsed.metrics_address != current.metrics_address, "metrics_listen");
+    check(parsed.reuse_port != current.reuse_port, "reuse_port");
+    check(parsed.pid_file != current.pid_file, "pid_file");
+    check(parsed.session_log != current.session_log, "session_log");
+
+    let config = ServerConfig {
+        secret_key: parsed.secret_key,
+        secret_keys_file: parsed.secret_keys_file,
+        key_grace_period: parsed.key_grace_period,
+        ip_connection_limit: parsed.ip_connection_limit,
+        ip_connection_rate: parsed.ip_connection_rate,
+        cidr_connection_limits: parsed.cidr_connection_limits,
+        ip_allow: parsed.ip_allow,
+        ip_deny: parsed.ip_deny,
+        ip_allow_file: parsed.ip_allow_file,
+        ip_deny_file: parsed.ip_deny_file,
+        drain_timeout: parsed.drain_timeout,
+        limits: parsed.limits,
+        bandwidth_caps: parsed.bandwidth_caps,
+        log_level: parsed.log_level,
+        server_registration: parsed.server_registration,
+        control_server: parsed.control_server,
+        hostname: parsed.hostname,
+        x_nettest_client: parsed.x_nettest_client,
+        registration_token: parsed.registration_token,
+        ..current.clone()
+    };
+    (config, needs_restart)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_merge_keeps_listen_addresses() {
+        let current = parse_args(vec!["-s".to_string()], FileConfig::default()).unwrap();
+        let mut file_config = FileConfig {
+            server_listen: vec!["127.0.0.1:6000".to_string()],
+            ip_connection_limit: 4,
+            control_server: "http://127.0.0.1:8080".to_string(),
+            ..FileConfig::default()
+        };
+        file_config.test_limits.idle_timeout = Duration::from_secs(30);
+        let parsed = parse_args(vec!["-s".to_string()], file_config).unwrap();
+
+        let (merged, needs_restart) = merge_reloadable(&current, parsed);
+        assert_eq!(merged.tcp_addresses, current.tcp_addresses);
+        assert_eq!(merged.limits.idle_timeout, Duration::from_secs(30));
+        assert_eq!(merged.ip_connection_limit, 4);
+        assert_eq!(merged.control_server, "http://127.0.0.1:8080");
+        assert_eq!(needs_restart, vec!["server_listen"]);
+    }
+
+    #[test]
+    fn test_registration_without_secret_keys_starts() {
+        let config = parse_args(vec!["-s".to_string(), "-register".to_string()], FileConfig::default()).unwrap();
+        assert!(RuntimeConfig::new(config).unwrap().token_validator.is_none());
+    }
+}
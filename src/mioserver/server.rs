@@ -1,38 +1,132 @@
 use bytes::BytesMut;
+use ipnet::IpNet;
 use log::{debug, info, LevelFilter};
 use mio::net::{TcpListener, TcpStream};
-use mio::{Poll, Token, Waker};
+use mio::{Events, Interest, Poll, Token, Waker};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::VecDeque;
 use std::io::{self};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
-use std::thread;
-use std::time::Instant;
-use crate::mioserver::control_server::auto_registration::{deregister_server, register_server, start_ping_job};
+use std::time::{Duration, Instant};
+use crate::mioserver::control_server::auto_registration::{deregister_server, start_registration_job, ServerLoad};
+
+pub const TCP_LISTENER: Token = Token(0);
+pub const TLS_LISTENER: Token = Token(1);
+pub const WORKER_WAKER: Token = Token(2);
+// QUIC listeners будят acceptor, когда в очереди появился поток
+pub const QUIC_WAKER: Token = Token(3);
+
+/// Hand-off queue from the acceptor to one worker; `waker` interrupts the worker's poll.
+pub struct WorkerQueue {
+    pub connections: Mutex<VecDeque<AcceptedConnection>>,
+    pub waker: Waker,
+}
 
 #[derive(Debug)]
 pub enum ConnectionType {
     Tcp(TcpStream),
     Tls(TcpStream), // Пока что тот же TcpStream, но с флагом TLS
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
+}
+
+impl ConnectionType {
+    fn label(&self) -> &'static str {
+        match self {
+            ConnectionType::Tcp(_) => "TCP",
+            ConnectionType::Tls(_) => "TLS",
+            #[cfg(feature = "quic")]
+            ConnectionType::Quic(_) => "QUIC",
+        }
+    }
+}
+
+/// An accepted socket on its way to a worker.
+pub struct AcceptedConnection {
+    pub connection: ConnectionType,
+    pub queued_at: Instant,
+    // None для PROXY listeners: лимиты проверяются по адресу из заголовка
+    pub permit: Option<ConnectionPermit>,
+    pub proxy_protocol: bool,
+}
+
+/// A bound listening socket and what its connections start with.
+pub struct ServerListener {
+    pub listener: TcpListener,
+    pub is_tls: bool,
+    pub proxy_protocol: bool,
 }
 
 use crate::config::FileConfig;
-use crate::mioserver::worker::WorkerThread;
+use crate::mioserver::limits::{CidrLimit, ConnectionLimiter, ConnectionPermit, TestLimits};
+use crate::mioserver::ip_filter::IpFilter;
+use crate::mioserver::metrics::{start_metrics_server, Transport, METRICS};
+use crate::mioserver::pacer::{BandwidthCaps, Pacer};
+#[cfg(feature = "quic")]
+use crate::mioserver::quic::{bind_quic_listeners, start_quic_listeners, QuicQueue};
+use crate::mioserver::reload::{Reloader, RuntimeConfig, RuntimeConfigStore};
+use crate::mioserver::session_log::{init_session_log, session_log_path, write_refused, SessionRecord};
+use crate::mioserver::systemd::{self, ActivatedListener, Watchdog};
+use crate::mioserver::worker::{WorkerAcceptor, WorkerThread};
+use crate::mioserver::tls::TlsConfigStore;
 use crate::mioserver::ServerTestPhase;
 use crate::stream::stream::Stream;
-use crate::tokio_server::server_config::parse_listen_address;
+#[cfg(feature = "quic")]
+use crate::stream::quic::QuicStream;
+use crate::logger;
+use crate::tokio_server::utils::daemon;
+use crate::tokio_server::utils::user::UserPrivileges;
+use crate::tokio_server::utils::token_validator::TokenValidator;
 
 pub struct MioServer {
-    tcp_listener: TcpListener,
-    tls_listener: Option<TcpListener>,
+    poll: Poll,
+    events: Events,
+    listeners: Vec<ServerListener>,
+    ip_families: IpFamilies,
     _worker_threads: Vec<WorkerThread>,
-    global_queue: Arc<Mutex<VecDeque<(ConnectionType, Instant)>>>, // Общая очередь с временными метками
+    worker_queues: Vec<Arc<WorkerQueue>>,
+    worker_connection_counts: Arc<Mutex<Vec<usize>>>,
+    global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>, // Общая очередь с временными метками
     server_config: ServerConfig,
+    // Аргументы запуска, SIGHUP применяет их поверх перечитанного файла
+    args: Vec<String>,
+    runtime: Arc<RuntimeConfigStore>,
     shutdown_signal: Arc<AtomicBool>,
+    accepting: Arc<AtomicBool>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    tls_config: Option<Arc<TlsConfigStore>>,
+    #[cfg(feature = "quic")]
+    quic_queue: Arc<QuicQueue>,
+    watchdog: Watchdog,
+}
+
+/// Address families the server actually accepts connections on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IpFamilies {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl IpFamilies {
+    fn add(&mut self, addr: SocketAddr, only_v6: bool) {
+        match addr.ip() {
+            IpAddr::V4(_) => self.ipv4 = true,
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some() => self.ipv4 = true,
+            IpAddr::V6(_) => {
+                self.ipv6 = true;
+                if !only_v6 {
+                    self.ipv4 = true;
+                }
+            }
+        }
+    }
 }
 
 pub struct TestState {
     pub token: Token,
+    pub started: Instant,
     pub last_active: Instant,
     pub stream: Stream,
     pub measurement_state: ServerTestPhase,
@@ -52,21 +146,59 @@ pub struct TestState {
     pub chunk_buffer: Vec<u8>,
     pub chunk: Option<BytesMut>,
     pub terminal_chunk: Option<BytesMut>,
-    pub bytes_received: VecDeque<(u64, u64)>
-
+    pub bytes_received: VecDeque<(u64, u64)>,
+    pub token_validator: Option<Arc<TokenValidator>>,
+    pub token_uuid: Option<String>,
+    pub client_addr: Option<SocketAddr>,
+    pub sig_key: Option<String>,
+    pub total_bytes_sent: u64,
+    pub sent_time_ns: Option<u128>,
+    pub total_bytes_received: u64,
+    pub received_time_ns: Option<u128>,
+    pub signed_result: Option<String>,
+    pub _permit: Option<ConnectionPermit>,
+    pub session: SessionRecord,
+    pub limits: TestLimits,
+    pub bandwidth_caps: Arc<BandwidthCaps>,
+    // Метка ключа, которым подписан токен
+    pub token_label: Option<String>,
+    // None, если у теста нет ограничения скорости
+    pub pacer: Option<Pacer>,
 }
 
 #[derive(Clone)]
 pub struct ServerConfig {
-    pub tcp_address: SocketAddr,
-    pub tls_address: SocketAddr,
+    pub tcp_addresses: Vec<SocketAddr>,
+    pub tls_addresses: Vec<SocketAddr>,
+    pub proxy_protocol_addresses: Vec<SocketAddr>,
+    // UDP адреса для QUIC, пусто — QUIC выключен
+    pub quic_addresses: Vec<SocketAddr>,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
     pub num_workers: Option<usize>,
+    pub tests_per_worker: usize,
     pub user: Option<String>,
     pub daemon: bool,
     pub version: Option<String>,
     pub secret_key: Option<String>,
+    pub secret_keys_file: Option<String>,
+    // Сколько ещё принимать токены ключа, удалённого из файла при перезагрузке
+    pub key_grace_period: Duration,
+    pub metrics_address: Option<SocketAddr>,
+    pub ip_connection_limit: usize,
+    pub ip_connection_rate: u32,
+    pub cidr_connection_limits: Vec<CidrLimit>,
+    pub ip_allow: Vec<IpNet>,
+    pub ip_deny: Vec<IpNet>,
+    // Файлы читаются при запуске и по SIGHUP, сети из них добавляются к ip_allow/ip_deny
+    pub ip_allow_file: Option<String>,
+    pub ip_deny_file: Option<String>,
+    pub drain_timeout: Duration,
+    pub limits: TestLimits,
+    pub bandwidth_caps: BandwidthCaps,
+    pub reuse_port: bool,
+    pub pid_file: Option<String>,
+    pub session_log: Option<String>,
     pub log_level: Option<LevelFilter>,
     pub server_registration: bool,
     pub control_server: String,
@@ -77,84 +209,200 @@ pub struct ServerConfig {
 
 impl MioServer {
     pub fn new(args: Vec<String>, config: FileConfig) -> io::Result<Self> {
-        let server_config = crate::mioserver::parser::parse_args(args, config.clone())
+        let server_config = crate::mioserver::parser::parse_args(args.clone(), config.clone())
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if let Some(level) = server_config.log_level {
+            logger::init_logger(level).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+        if server_config.user.is_some() {
+            UserPrivileges::check_root()?;
+        }
 
-        let tcp_listener = TcpListener::bind(server_config.tcp_address)?;
+        // Сертификат читаем один раз, воркеры берут общий rustls config
+        let tls_config = match (&server_config.cert_path, &server_config.key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let store = TlsConfigStore::load(cert_path.clone(), key_path.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Some(store)
+            }
+            _ => None,
+        };
+        let tls_enabled = tls_config.is_some();
+        let logical = server_config.num_workers.unwrap_or(30);
 
-        let tls_listener = if server_config.cert_path.is_some() && server_config.key_path.is_some() {
-            let tls_addr: SocketAddr = parse_listen_address(&server_config.tls_address.to_string()).unwrap();
-            match TcpListener::bind(tls_addr) {
-                Ok(listener) => {
-                    debug!("MIO TLS Server will listen on {}", tls_addr);
-                    Some(listener)
-                }
-                Err(e) => {
-                    debug!("Failed to bind TLS listener: {}", e);
-                    None
-                }
+        // С SO_REUSEPORT каждый воркер слушает сам, общий listener не нужен
+        let mut listeners = Vec::new();
+        let mut worker_listeners: Vec<Vec<ServerListener>> = (0..logical).map(|_| Vec::new()).collect();
+        let mut ip_families = IpFamilies::default();
+        let activated = systemd::listen_fds()?;
+        if !activated.is_empty() {
+            if server_config.reuse_port {
+                info!("Listening sockets come from systemd, ignoring -reuseport");
+            }
+            listeners = activated_listeners(&server_config, activated, tls_enabled, &mut ip_families)?;
+        } else if server_config.reuse_port {
+            for (i, worker) in worker_listeners.iter_mut().enumerate() {
+                *worker = bind_server_listeners(&server_config, tls_enabled, &mut ip_families, i == 0)?;
             }
+            info!("Each of {} workers listens with SO_REUSEPORT", logical);
         } else {
-            None
-        };
+            listeners = bind_server_listeners(&server_config, tls_enabled, &mut ip_families, true)?;
+        }
 
-        let logical = server_config.num_workers.unwrap_or(30);
+        let poll = Poll::new()?;
+        for listener in listeners.iter_mut() {
+            let token = if listener.is_tls { TLS_LISTENER } else { TCP_LISTENER };
+            poll.registry().register(&mut listener.listener, token, Interest::READABLE)?;
+        }
+
+        // QUIC всегда зашифрован, без сертификата его не поднять
+        if !server_config.quic_addresses.is_empty() && !tls_enabled {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "QUIC listeners need a certificate, set -c and -k",
+            ));
+        }
+        #[cfg(feature = "quic")]
+        let quic_sockets = bind_quic_listeners(&server_config.quic_addresses)?;
+        #[cfg(not(feature = "quic"))]
+        if !server_config.quic_addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "QUIC is not available: nettest was built without the quic feature",
+            ));
+        }
+
+        let runtime = RuntimeConfigStore::new(RuntimeConfig::new(server_config.clone())?);
+
+        // Порты (в т.ч. 443) привязаны и файлы прочитаны, дальше root не нужен.
+        // Форк до запуска любых потоков, иначе они не переживут fork.
+        let metrics_listener = server_config
+            .metrics_address
+            .map(std::net::TcpListener::bind)
+            .transpose()?;
+        if let Some(path) = &server_config.session_log {
+            init_session_log(Path::new(path))?;
+        }
+        if server_config.daemon {
+            info!("Daemonizing...");
+            logger::disable_stdout();
+            daemon::daemonize().map_err(|e| io::Error::new(io::ErrorKind::Interrupted, e.to_string()))?;
+        }
+        if let Some(pid_file) = &server_config.pid_file {
+            daemon::write_pid_file(Path::new(pid_file))?;
+        }
+        if let Some(user) = &server_config.user {
+            drop_privileges(user)?;
+        }
+
+        let connection_limiter = Arc::new(ConnectionLimiter::new(
+            server_config.ip_connection_limit,
+            server_config.ip_connection_rate,
+            server_config.cidr_connection_limits.clone(),
+        ));
+
+        let mut worker_polls = Vec::new();
         let mut worker_queues = Vec::new();
-        for i in 0..logical {
+        for _ in 0..logical {
             let poll = Poll::new()?;
-            let queue = Arc::new((
-                Mutex::new(VecDeque::<ConnectionType>::new()),
-                Waker::new(poll.registry(), Token(i + 1))?,
-            ));
+            let queue = Arc::new(WorkerQueue {
+                connections: Mutex::new(VecDeque::new()),
+                waker: Waker::new(poll.registry(), WORKER_WAKER)?,
+            });
+            worker_polls.push(poll);
             worker_queues.push(queue);
         }
 
         let worker_connection_counts = Arc::new(Mutex::new(vec![0; logical]));
         let global_queue = Arc::new(Mutex::new(VecDeque::new()));
 
+        if let Some(metrics_listener) = metrics_listener {
+            start_metrics_server(
+                metrics_listener,
+                worker_connection_counts.clone(),
+                global_queue.clone(),
+            )?;
+        }
+        if let Some(tls_config) = &tls_config {
+            tls_config.start_watcher()?;
+        }
+
+        let accepting = Arc::new(AtomicBool::new(true));
+        #[cfg(feature = "quic")]
+        let quic_queue = Arc::new(QuicQueue {
+            connections: Mutex::new(VecDeque::new()),
+            waker: Waker::new(poll.registry(), QUIC_WAKER)?,
+        });
+        #[cfg(feature = "quic")]
+        if let Some(tls_config) = &tls_config {
+            start_quic_listeners(
+                quic_sockets,
+                quic_queue.clone(),
+                runtime.clone(),
+                connection_limiter.clone(),
+                tls_config.clone(),
+                accepting.clone(),
+            )?;
+        }
         let mut worker_threads = Vec::new();
 
-        for i in 0..logical {
+        for (i, (poll, listeners)) in worker_polls.into_iter().zip(worker_listeners).enumerate() {
             let worker = WorkerThread::new(
                 i,
+                poll,
                 worker_connection_counts.clone(),
                 global_queue.clone(),
-                server_config.clone(),
+                server_config.tests_per_worker,
+                runtime.clone(),
+                WorkerAcceptor {
+                    queue: worker_queues[i].clone(),
+                    listeners,
+                    connection_limiter: connection_limiter.clone(),
+                    tls_config: tls_config.clone(),
+                    accepting: accepting.clone(),
+                },
             )?;
             worker_threads.push(worker);
         }
 
 
         Ok(Self {
-            tcp_listener,
-            tls_listener,
+            poll,
+            events: Events::with_capacity(128),
+            listeners,
+            ip_families,
             _worker_threads: worker_threads,
+            worker_queues,
+            worker_connection_counts,
             global_queue,
             server_config,
+            args,
+            runtime,
             shutdown_signal: Arc::new(AtomicBool::new(false)),
+            accepting,
+            connection_limiter,
+            tls_config,
+            #[cfg(feature = "quic")]
+            quic_queue,
+            watchdog: Watchdog::from_env(),
         })
     }
 
     pub fn run(&mut self) -> io::Result<()> {
-        info!("server_config.server_registration: {:?}", self.server_config.server_registration);
-        if self.server_config.server_registration {
-            info!("Registering server with control server...");
-            let config_clone = self.server_config.clone();
-            info!("Registering server with control server...");
-            let shutdown_signal = self.shutdown_signal.clone();
-            tokio::spawn(async move {
-                match register_server(&config_clone).await {
-                    Ok(_) => {
-                        info!("Server registration successful, starting ping job...");
-                        start_ping_job(config_clone, shutdown_signal).await;
-                    }
-                    Err(e) => {
-                        info!("Server registration failed: {}", e);
-                    }
-                }
-            });
-        }
-      
+        systemd::notify("READY=1");
+        // Задача работает и без регистрации: её может включить SIGHUP
+        let load = ServerLoad {
+            worker_connection_counts: self.worker_connection_counts.clone(),
+            global_queue: self.global_queue.clone(),
+            tests_per_worker: self.server_config.tests_per_worker,
+        };
+        tokio::spawn(start_registration_job(
+            self.runtime.clone(),
+            self.ip_families,
+            load,
+            self.shutdown_signal.clone(),
+        ));
+
         loop {
             // Проверяем сигнал завершения
             if self.shutdown_signal.load(Ordering::Relaxed) {
@@ -162,64 +410,134 @@ impl MioServer {
                 break;
             }
 
-            // Принимаем TCP соединения
-            match self.tcp_listener.accept() {
-                Ok((stream, _addr)) => {
-                    if let Err(e) = stream.set_nodelay(true) {
-                        debug!("Failed to set TCP_NODELAY: {}", e);
-                    }
-                    self.handle_connection(stream, false)?;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // Продолжаем
-                }
-                Err(e) => {
-                    debug!("Error accepting TCP connection: {}", e);
-                    return Err(e);
-                }
+            // Таймаут нужен только для проверки сигнала завершения
+            self.accept_connections(Duration::from_millis(100))?;
+            // Пинг только отсюда: если accept loop завис, systemd перезапустит сервер
+            self.watchdog.ping_if_due();
+        }
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for listener readiness and accepts everything pending.
+    fn accept_connections(&mut self, timeout: Duration) -> io::Result<()> {
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        let mut tcp_ready = false;
+        let mut tls_ready = false;
+        for event in self.events.iter() {
+            match event.token() {
+                TCP_LISTENER => tcp_ready = true,
+                TLS_LISTENER => tls_ready = true,
+                _ => {}
             }
+        }
 
-            // Принимаем TLS соединения если есть listener
-            if let Some(ref mut tls_listener) = self.tls_listener {
-                match tls_listener.accept() {
-                    Ok((stream, _addr)) => {
-                        if let Err(e) = stream.set_nodelay(true) {
-                            debug!("Failed to set TCP_NODELAY: {}", e);
+        // Edge-triggered: принимаем всё до WouldBlock со всех listeners этого типа
+        let runtime = self.runtime.current();
+        let mut accepted = Vec::new();
+        for listener in self.listeners.iter() {
+            if (listener.is_tls && !tls_ready) || (!listener.is_tls && !tcp_ready) {
+                continue;
+            }
+            loop {
+                match listener.listener.accept() {
+                    Ok((stream, addr)) => {
+                        if let Some(entry) = admit_connection(&self.connection_limiter, &runtime.ip_filter, stream, addr, listener) {
+                            accepted.push(entry);
                         }
-                        self.handle_connection(stream, true)?;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        // Продолжаем
                     }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
                     Err(e) => {
-                        debug!("Error accepting TLS connection: {}", e);
-                        return Err(e);
+                        debug!(
+                            "Error accepting {} connection: {}",
+                            if listener.is_tls { "TLS" } else { "TCP" },
+                            e
+                        );
+                        break;
                     }
                 }
             }
-
-            // Проверяем общую очередь на устаревшие соединения
-            self.check_global_queue()?;
-
-            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // Потоки QUIC уже прошли проверки в QUIC listener
+        #[cfg(feature = "quic")]
+        accepted.extend(self.quic_queue.connections.lock().unwrap().drain(..));
+        for entry in accepted {
+            self.handle_connection(entry);
         }
 
-       
         Ok(())
     }
 
-   pub  async fn shutdown(&mut self) -> io::Result<()> {
+    /// Deregisters from the control server, stops accepting and then waits up
+    /// to `drain_timeout` for queued and running tests to finish.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
         info!("Starting graceful shutdown...");
-        
-        if self.server_config.server_registration {
+        systemd::notify("STOPPING=1");
+
+        let runtime = self.runtime.current();
+        if runtime.server_config.server_registration {
             info!("Deregistering server from control server...");
-            let _ = deregister_server(&self.server_config).await;
+            // Пока идёт дерегистрация, продолжаем принимать клиентов, которым уже выдали этот сервер
+            let config = runtime.server_config.clone();
+            let deregistration = tokio::spawn(async move { deregister_server(&config).await });
+            while !deregistration.is_finished() {
+                self.accept_connections(Duration::from_millis(10))?;
+                self.watchdog.ping_if_due();
+                tokio::task::yield_now().await;
+            }
+            match deregistration.await {
+                Ok(Err(e)) => info!("Deregistration failed: {}", e),
+                Err(e) => info!("Deregistration task failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+
+        // Закрываем listeners, новые клиенты получат connection refused
+        self.listeners.clear();
+        self.accepting.store(false, Ordering::Relaxed);
+        info!("Stopped accepting new connections");
+
+        let drain_timeout = runtime.server_config.drain_timeout;
+        let deadline = Instant::now() + drain_timeout;
+        let mut last_reported = None;
+        loop {
+            let active = self.active_tests();
+            if active == 0 {
+                info!("All tests finished");
+                break;
+            }
+            if Instant::now() >= deadline {
+                info!(
+                    "Drain timeout of {:?} reached, abandoning {} tests",
+                    drain_timeout, active
+                );
+                break;
+            }
+            if last_reported != Some(active) {
+                info!("Draining: waiting for {} tests to finish", active);
+                last_reported = Some(active);
+            }
+            self.watchdog.ping_if_due();
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
-        
+
         info!("Server shutdown complete");
         Ok(())
     }
 
+    /// Соединения в очереди и у воркеров.
+    fn active_tests(&self) -> usize {
+        let queued = self.global_queue.lock().unwrap().len();
+        let running: usize = self.worker_connection_counts.lock().unwrap().iter().sum();
+        queued + running
+    }
+
     pub fn request_shutdown(&self) {
         self.shutdown_signal.store(true, Ordering::Relaxed);
         info!("Shutdown requested");
@@ -229,30 +547,299 @@ impl MioServer {
         self.shutdown_signal.clone()
     }
 
-    fn check_global_queue(&mut self) -> io::Result<()> {
-        // Убираем таймаут - соединения будут ждать пока воркер их не заберет
-        Ok(())
+    /// Shared TLS config, `None` when no certificate is configured.
+    pub fn get_tls_config(&self) -> Option<Arc<TlsConfigStore>> {
+        self.tls_config.clone()
     }
 
-    fn handle_connection(&mut self, stream: TcpStream, is_tls: bool) -> io::Result<()> {
-        let connection = if is_tls {
-            ConnectionType::Tls(stream)
-        } else {
-            ConnectionType::Tcp(stream)
+    /// Re-reads the config on SIGHUP; `config_path` is the --config argument.
+    pub fn reloader(&self, config_path: Option<PathBuf>) -> Reloader {
+        Reloader {
+            args: self.args.clone(),
+            config_path,
+            runtime: self.runtime.clone(),
+            connection_limiter: self.connection_limiter.clone(),
+        }
+    }
+
+    fn handle_connection(&mut self, entry: AcceptedConnection) {
+        let kind = entry.connection.label();
+
+        // Отдаём наименее загруженному воркеру; счётчик растёт сразу, чтобы
+        // следующее соединение уже учитывало эту нагрузку
+        let worker = {
+            let mut counts = self.worker_connection_counts.lock().unwrap();
+            let least_loaded = counts
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, count)| **count)
+                .map(|(i, _)| i);
+            match least_loaded {
+                Some(i) if counts[i] < self.server_config.tests_per_worker => {
+                    counts[i] += 1;
+                    Some(i)
+                }
+                _ => None,
+            }
         };
 
-        // Добавляем соединение в глобальную очередь
-        let mut global_queue = self.global_queue.lock().unwrap();
-        global_queue.push_back((connection, Instant::now()));
+        match worker {
+            Some(i) => {
+                self.worker_queues[i].connections.lock().unwrap().push_back(entry);
+                if let Err(e) = self.worker_queues[i].waker.wake() {
+                    info!("Failed to wake worker {}: {}", i, e);
+                }
+                debug!("{} connection handed to worker {}", kind, i);
+            }
+            None => {
+                // Все воркеры заняты, ждём в общей очереди
+                let mut global_queue = self.global_queue.lock().unwrap();
+                global_queue.push_back(entry);
+
+                info!(
+                    "{} connection added to global queue (queue size: {})",
+                    kind,
+                    global_queue.len()
+                );
+            }
+        }
+    }
+}
+
+/// Switches to `user` once everything that needs root is done. The log files
+/// are handed over first so they can still be reopened after logrotate.
+fn drop_privileges(user: &str) -> io::Result<()> {
+    let privileges = UserPrivileges::new(user)?;
+    for log_path in [logger::log_file_path(), session_log_path()].into_iter().flatten() {
+        privileges.chown(&log_path)?;
+    }
+    privileges.drop_privileges()?;
+    info!("Dropped privileges to user {}", user);
+    Ok(())
+}
+
+/// Error for a file the server reads again on reload (keys, network lists,
+/// certificates). After -u a root-only file only fails then, so a permission
+/// error names the user the read ran as.
+pub(crate) fn file_read_error(path: &str, e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        let uid = nix::unistd::geteuid();
+        return io::Error::new(
+            e.kind(),
+            format!("{} is not readable by uid {}; with -u the file must be readable by that user", path, uid),
+        );
+    }
+    io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+/// Проверяет allow/deny списки и лимиты для только что принятого сокета; сокет закрывается при drop,
+/// если адрес запрещён или лимит превышен. За PROXY listener'ом адрес сокета — это балансировщик,
+/// проверка откладывается до заголовка.
+pub(crate) fn admit_connection(
+    connection_limiter: &Arc<ConnectionLimiter>,
+    ip_filter: &IpFilter,
+    stream: TcpStream,
+    addr: SocketAddr,
+    listener: &ServerListener,
+) -> Option<AcceptedConnection> {
+    let permit = if listener.proxy_protocol {
+        None
+    } else {
+        let transport = if listener.is_tls { Transport::Tls } else { Transport::Tcp };
+        if !ip_permitted(ip_filter, addr, transport) {
+            return None;
+        }
+        Some(acquire_permit(connection_limiter, addr)?)
+    };
+
+    if let Err(e) = stream.set_nodelay(true) {
+        debug!("Failed to set TCP_NODELAY: {}", e);
+    }
+
+    let connection = if listener.is_tls {
+        ConnectionType::Tls(stream)
+    } else {
+        ConnectionType::Tcp(stream)
+    };
+    Some(AcceptedConnection {
+        connection,
+        queued_at: Instant::now(),
+        permit,
+        proxy_protocol: listener.proxy_protocol,
+    })
+}
+
+/// Checks a client address against the allow and deny lists; a refused
+/// connection is counted and written to the session log.
+pub(crate) fn ip_permitted(ip_filter: &IpFilter, addr: SocketAddr, transport: Transport) -> bool {
+    if ip_filter.permits(addr.ip()) {
+        return true;
+    }
+    METRICS.connections_denied.fetch_add(1, Ordering::Relaxed);
+    // debug: сканеры на on-net сервере иначе забьют лог
+    debug!("Refusing connection from {}: address not allowed", addr);
+    write_refused(addr, transport, "ip_denied");
+    false
+}
+
+pub(crate) fn acquire_permit(connection_limiter: &Arc<ConnectionLimiter>, addr: SocketAddr) -> Option<ConnectionPermit> {
+    match connection_limiter.try_acquire(addr.ip()) {
+        Ok(permit) => Some(permit),
+        Err(reason) => {
+            METRICS.connection_rejected(reason);
+            info!("Rejecting connection from {}: {}", addr, reason);
+            None
+        }
+    }
+}
+
+/// Binds all TCP and, if enabled, TLS addresses. A TLS address that cannot be
+/// bound is skipped so the plain TCP listeners still come up.
+fn bind_server_listeners(
+    config: &ServerConfig,
+    tls_enabled: bool,
+    ip_families: &mut IpFamilies,
+    log_addresses: bool,
+) -> io::Result<Vec<ServerListener>> {
+    let mut listeners = Vec::new();
+    for (listener, addr, only_v6) in bind_listeners(&config.tcp_addresses, config.reuse_port)? {
+        let proxy_protocol = expects_proxy_header(config, addr);
+        if log_addresses {
+            info!("MIO TCP Server will listen on {}{}", addr, if proxy_protocol { " (PROXY protocol)" } else { "" });
+        }
+        ip_families.add(addr, only_v6);
+        listeners.push(ServerListener { listener, is_tls: false, proxy_protocol });
+    }
+    if tls_enabled {
+        match bind_listeners(&config.tls_addresses, config.reuse_port) {
+            Ok(tls_listeners) => {
+                for (listener, addr, only_v6) in tls_listeners {
+                    let proxy_protocol = expects_proxy_header(config, addr);
+                    if log_addresses {
+                        info!("MIO TLS Server will listen on {}{}", addr, if proxy_protocol { " (PROXY protocol)" } else { "" });
+                    }
+                    ip_families.add(addr, only_v6);
+                    listeners.push(ServerListener { listener, is_tls: true, proxy_protocol });
+                }
+            }
+            Err(e) => {
+                debug!("Failed to bind TLS listener: {}", e);
+            }
+        }
+    }
+    Ok(listeners)
+}
 
+/// Wraps sockets passed by systemd. A socket named `tls`/`tcp` (optionally
+/// with a `-proxy` suffix) via `FileDescriptorName=` says what it carries;
+/// unnamed sockets are matched against the `-L` and `-proxy` addresses.
+fn activated_listeners(
+    config: &ServerConfig,
+    activated: Vec<ActivatedListener>,
+    tls_enabled: bool,
+    ip_families: &mut IpFamilies,
+) -> io::Result<Vec<ServerListener>> {
+    let mut listeners = Vec::new();
+    for ActivatedListener { listener, name } in activated {
+        let addr = listener.local_addr()?;
+        let (is_tls, proxy_protocol) = match name.as_deref() {
+            Some("tcp") => (false, false),
+            Some("tls") => (true, false),
+            Some("tcp-proxy") => (false, true),
+            Some("tls-proxy") => (true, true),
+            _ => (
+                config.tls_addresses.iter().any(|tls_addr| same_listen_address(*tls_addr, addr)),
+                expects_proxy_header(config, addr),
+            ),
+        };
+        if is_tls && !tls_enabled {
+            info!("Skipping TLS socket {} from systemd, no certificate configured", addr);
+            continue;
+        }
+        let only_v6 = addr.is_ipv6() && socket2::SockRef::from(&listener).only_v6()?;
         info!(
-            "{} connection added to global queue (queue size: {})",
+            "MIO {} Server uses socket {} from systemd{}",
             if is_tls { "TLS" } else { "TCP" },
-            global_queue.len()
+            addr,
+            if proxy_protocol { " (PROXY protocol)" } else { "" }
         );
+        ip_families.add(addr, only_v6);
+        listeners.push(ServerListener {
+            listener: TcpListener::from_std(listener),
+            is_tls,
+            proxy_protocol,
+        });
+    }
+    Ok(listeners)
+}
 
-        Ok(())
+/// Configured address `configured` covers the bound address `bound`; wildcards match any IP on the port.
+fn same_listen_address(configured: SocketAddr, bound: SocketAddr) -> bool {
+    configured.port() == bound.port() && (configured.ip().is_unspecified() || configured.ip() == bound.ip())
+}
+
+/// Whether connections to `addr` start with a PROXY header. A `[::]` listener
+/// that fell back to `0.0.0.0` keeps the setting of the configured address.
+fn expects_proxy_header(config: &ServerConfig, addr: SocketAddr) -> bool {
+    config.proxy_protocol_addresses.iter().any(|proxy_addr| {
+        *proxy_addr == addr
+            || (addr.ip().is_unspecified()
+                && proxy_addr.ip().is_unspecified()
+                && proxy_addr.port() == addr.port())
+    })
+}
+
+/// Binds every address and returns each listener with the address it is bound
+/// to and whether it is IPv6-only. The `[::]` wildcard is dual-stack unless an
+/// IPv4 wildcard on the same port is configured as well; on hosts without IPv6
+/// it falls back to `0.0.0.0`.
+pub(crate) fn bind_listeners(
+    addresses: &[SocketAddr],
+    reuse_port: bool,
+) -> io::Result<Vec<(TcpListener, SocketAddr, bool)>> {
+    let mut listeners = Vec::new();
+    for &addr in addresses {
+        let only_v6 = match addr.ip() {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(ip) if ip.is_unspecified() => addresses
+                .iter()
+                .any(|other| other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port()),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().is_none(),
+        };
+        match bind_listener(addr, only_v6, reuse_port) {
+            Ok(listener) => listeners.push((listener, addr, only_v6)),
+            Err(e) if addr.ip().is_unspecified() && addr.is_ipv6() && e.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+                let fallback = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+                if addresses.contains(&fallback) {
+                    continue;
+                }
+                info!("IPv6 is not available, listening on {} instead of {}", fallback, addr);
+                listeners.push((bind_listener(fallback, false, reuse_port)?, fallback, false));
+            }
+            Err(e) => {
+                return Err(io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)));
+            }
+        }
+    }
+    Ok(listeners)
+}
+
+/// Binds a non-blocking listener, optionally with SO_REUSEPORT so several
+/// workers can listen on the same address. IPV6_V6ONLY is always set
+/// explicitly for IPv6 sockets instead of relying on the system default.
+pub(crate) fn bind_listener(addr: SocketAddr, only_v6: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
     }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into()))
 }
 
 impl Drop for MioServer {
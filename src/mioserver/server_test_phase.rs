@@ -1,15 +1,20 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ServerTestPhase {
     GreetingReceiveConnectionType,
     GreetingSendVersion,
     GreetingSendAcceptToken,
     GreetingReceiveToken,
     GreetingSendOk,
+    GreetingSendErr,
     GreetingSendChunksize,
 
     AcceptTokenQuit,
     AcceptCommandReceive,
     AcceptCommandSend,
+    // ERR на команду сверх лимитов, затем снова ACCEPT
+    CommandSendErr,
+    // ERR и закрытие соединения
+    LimitSendErr,
 
     GetChunkSendOk,
     GetChunkSendChunk,
@@ -40,4 +45,6 @@ pub enum ServerTestPhase {
     PutTimeResultSendOk,
     PutTimeResultReceiveChunk,
     PutTimeResultSendTimeResult,
+
+    SignedResultSend,
 }
\ No newline at end of file
@@ -0,0 +1,208 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use log::{debug, info};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::logger::{self, LogFile};
+use crate::mioserver::metrics::Transport;
+use crate::mioserver::server::TestState;
+
+lazy_static::lazy_static! {
+    static ref SESSION_LOG: Mutex<Option<LogFile>> = Mutex::new(None);
+}
+
+/// One measurement command as the client sent it, with what the server measured.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRecord {
+    pub command: String,
+    pub params: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<usize>,
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ns: Option<u128>,
+}
+
+/// Everything the server knows about one test session, written as one JSON
+/// line when the connection closes.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub client_ip: Option<String>,
+    pub client_port: Option<u16>,
+    pub transport: &'static str,
+    pub token_uuid: Option<String>,
+    pub token_label: Option<String>,
+    // Только у тестов с ограничением скорости
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth_cap_bps: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttled_ms: Option<u64>,
+    pub commands: Vec<CommandRecord>,
+    pub ping_count: u32,
+    pub chunk_sizes: Vec<usize>,
+    pub final_phase: Option<String>,
+    pub close_reason: Option<String>,
+}
+
+impl SessionRecord {
+    pub fn new(client_addr: Option<SocketAddr>, transport: Transport) -> Self {
+        SessionRecord {
+            session_id: Uuid::new_v4().to_string(),
+            started_at: now(),
+            finished_at: None,
+            client_ip: client_addr.map(|addr| addr.ip().to_canonical().to_string()),
+            client_port: client_addr.map(|addr| addr.port()),
+            transport: transport.label(),
+            token_uuid: None,
+            token_label: None,
+            bandwidth_cap_bps: None,
+            throttled_ms: None,
+            commands: Vec::new(),
+            ping_count: 0,
+            chunk_sizes: Vec::new(),
+            final_phase: None,
+            close_reason: None,
+        }
+    }
+
+    /// Records a parsed command line, e.g. "GETTIME 7 4096". PINGs are only counted.
+    pub fn start_command(&mut self, line: &str, chunk_size: Option<usize>) {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default().to_string();
+        if command == "PING" {
+            self.ping_count += 1;
+            return;
+        }
+        if let Some(size) = chunk_size {
+            if !self.chunk_sizes.contains(&size) {
+                self.chunk_sizes.push(size);
+            }
+        }
+        self.commands.push(CommandRecord {
+            command,
+            params: parts.map(String::from).collect(),
+            chunk_size,
+            bytes: 0,
+            duration_ns: None,
+        });
+    }
+
+    /// Adds transferred payload bytes to the running command.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        if let Some(command) = self.commands.last_mut() {
+            command.bytes += bytes;
+        }
+    }
+
+    /// Payload bytes of the running command so far.
+    pub fn command_bytes(&self) -> u64 {
+        self.commands.last().map(|command| command.bytes).unwrap_or(0)
+    }
+
+    /// Stores the duration the server reports back to the client.
+    pub fn finish_command(&mut self, duration_ns: u128) {
+        if let Some(command) = self.commands.last_mut() {
+            command.duration_ns = Some(duration_ns);
+        }
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Opens the JSONL session log. It is reopened on SIGHUP like the main log.
+pub fn init_session_log(path: &Path) -> io::Result<()> {
+    let file = LogFile::open(path)?;
+    logger::install_reopen_handler();
+    info!("Writing session log to {}", path.display());
+    *SESSION_LOG.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn session_log_path() -> Option<std::path::PathBuf> {
+    SESSION_LOG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|file| file.path().to_path_buf())
+}
+
+/// Completes the session of a closed connection and appends it to the log.
+pub fn write_session(state: &mut TestState, close_reason: &str) {
+    let session = &mut state.session;
+    session.finished_at = Some(now());
+    session.token_uuid = state.token_uuid.clone();
+    session.token_label = state.token_label.clone();
+    if let Some(pacer) = &state.pacer {
+        session.bandwidth_cap_bps = Some(pacer.bits_per_second());
+        session.throttled_ms = Some(pacer.throttled().as_millis() as u64);
+    }
+    session.final_phase = Some(format!("{:?}", state.measurement_state));
+    session.close_reason = Some(close_reason.to_string());
+    append_session(session);
+}
+
+/// Logs a connection refused before any handshake, e.g. by the IP filter.
+pub fn write_refused(client_addr: SocketAddr, transport: Transport, close_reason: &str) {
+    if SESSION_LOG.lock().unwrap().is_none() {
+        return;
+    }
+    let mut session = SessionRecord::new(Some(client_addr), transport);
+    session.finished_at = Some(now());
+    session.close_reason = Some(close_reason.to_string());
+    append_session(&session);
+}
+
+fn append_session(session: &SessionRecord) {
+    let log = SESSION_LOG.lock().unwrap();
+    let Some(file) = log.as_ref() else {
+        return;
+    };
+    match serde_json::to_string(session) {
+        Ok(mut line) => {
+            line.push('\n');
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                debug!("Failed to write session log: {}", e);
+            }
+        }
+        Err(e) => debug!("Failed to serialize session {}: {}", session.session_id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_record_commands() {
+        let mut session = SessionRecord::new(Some("[::ffff:192.0.2.1]:40000".parse().unwrap()), Transport::Tls);
+        session.start_command("GETTIME 7 4096", Some(4096));
+        session.add_bytes(4096);
+        session.add_bytes(100);
+        session.finish_command(7_000_000_123);
+        session.start_command("PING", None);
+        session.start_command("PING", None);
+
+        assert_eq!(session.client_ip.as_deref(), Some("192.0.2.1"));
+        assert_eq!(session.ping_count, 2);
+        assert_eq!(session.chunk_sizes, vec![4096]);
+        assert_eq!(session.commands[0].params, vec!["7", "4096"]);
+        assert_eq!(session.commands[0].bytes, 4196);
+
+        let json: serde_json::Value = serde_json::to_value(&session).unwrap();
+        assert_eq!(json["transport"], "TLS");
+        assert_eq!(json["commands"][0]["command"], "GETTIME");
+        assert_eq!(json["commands"][0]["duration_ns"], 7_000_000_123u64);
+        assert_eq!(json["commands"].as_array().unwrap().len(), 1);
+        assert!(json.get("bandwidth_cap_bps").is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+// Первый дескриптор, который передаёт systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: i32 = 3;
+
+/// A listening socket inherited from systemd, with its `FileDescriptorName=`.
+pub struct ActivatedListener {
+    pub listener: TcpListener,
+    pub name: Option<String>,
+}
+
+/// Takes the sockets systemd passed via `LISTEN_FDS`. Returns an empty list
+/// when the process was not socket activated. The variables are removed so
+/// child processes do not pick the sockets up again.
+pub fn listen_fds() -> io::Result<Vec<ActivatedListener>> {
+    let pid_matches = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    let names = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let Some(count) = count.filter(|_| pid_matches) else {
+        return Ok(Vec::new());
+    };
+    let names: Vec<&str> = names.as_deref().map(|n| n.split(':').collect()).unwrap_or_default();
+
+    let mut listeners = Vec::new();
+    for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+        // SAFETY: systemd передаёт эти дескрипторы процессу, больше ими никто не владеет
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        listeners.push(ActivatedListener {
+            listener,
+            name: names.get(i).filter(|name| !name.is_empty()).map(|name| name.to_string()),
+        });
+    }
+    info!("Received {} listening sockets from systemd", listeners.len());
+    Ok(listeners)
+}
+
+/// Sends a state line like "READY=1" to the service manager. Does nothing
+/// outside systemd.
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notification(&path, state) {
+        debug!("sd_notify {} failed: {}", state, e);
+    }
+}
+
+fn send_notification(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `WATCHDOG=1` at half the interval systemd expects (`WatchdogSec=`).
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let pid_matches = env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && pid_matches)
+            .map(|usec| Duration::from_micros(usec) / 2);
+        if let Some(interval) = interval {
+            info!("systemd watchdog enabled, pinging every {:?}", interval);
+        }
+        Watchdog {
+            interval,
+            last_ping: Instant::now(),
+        }
+    }
+
+    pub fn ping_if_due(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.last_ping.elapsed() >= interval {
+            notify("WATCHDOG=1");
+            self.last_ping = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Error;
+use log::{debug, info};
+use rustls::ServerConfig;
+
+use crate::mioserver::server::file_read_error;
+use crate::stream::rustls_server::{load_certs, load_private_key};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Path the file resolves to, its mtime and size. Let's Encrypt swaps the
+/// `live/` symlinks, so the target path changes even if the mtime does not.
+type FileStamp = (PathBuf, Option<SystemTime>, u64);
+
+/// Rustls config shared by all workers. New TLS connections take the current
+/// config; running tests keep the one they started with.
+pub struct TlsConfigStore {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<ServerConfig>>,
+    // Тот же сертификат для QUIC listeners (rustls 0.23 внутри quinn)
+    #[cfg(feature = "quic")]
+    quic: RwLock<Arc<quinn_proto::ServerConfig>>,
+    // Отпечатки файлов последней попытки загрузки
+    stamps: Mutex<(Option<FileStamp>, Option<FileStamp>)>,
+}
+
+impl TlsConfigStore {
+    pub fn load(cert_path: String, key_path: String) -> Result<Arc<Self>, Error> {
+        let stamps = (file_stamp(&cert_path), file_stamp(&key_path));
+        let config = build_server_config(&cert_path, &key_path)
+            .map_err(|e| Error::msg(format!("Failed to load TLS certificate {}: {}", cert_path, e)))?;
+        #[cfg(feature = "quic")]
+        let quic = crate::stream::quic::server_config(&cert_path, &key_path)
+            .map_err(|e| Error::msg(format!("Failed to load TLS certificate {} for QUIC: {}", cert_path, e)))?;
+        info!("Loaded TLS certificate {}", cert_path);
+        Ok(Arc::new(TlsConfigStore {
+            cert_path,
+            key_path,
+            current: RwLock::new(config),
+            #[cfg(feature = "quic")]
+            quic: RwLock::new(quic),
+            stamps: Mutex::new(stamps),
+        }))
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn current_quic(&self) -> Arc<quinn_proto::ServerConfig> {
+        self.quic.read().unwrap().clone()
+    }
+
+    /// Re-reads the certificate and key. On error the previous config stays active.
+    pub fn reload(&self) -> Result<(), Error> {
+        *self.stamps.lock().unwrap() = (file_stamp(&self.cert_path), file_stamp(&self.key_path));
+        let config = build_server_config(&self.cert_path, &self.key_path)?;
+        #[cfg(feature = "quic")]
+        {
+            let quic = crate::stream::quic::server_config(&self.cert_path, &self.key_path)?;
+            *self.quic.write().unwrap() = quic;
+        }
+        *self.current.write().unwrap() = config;
+        info!("Reloaded TLS certificate {}", self.cert_path);
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) {
+        let stamps = (file_stamp(&self.cert_path), file_stamp(&self.key_path));
+        if *self.stamps.lock().unwrap() == stamps {
+            return;
+        }
+        debug!("TLS certificate or key changed on disk");
+        if let Err(e) = self.reload() {
+            info!("Keeping previous TLS certificate, reload failed: {}", e);
+        }
+    }
+
+    /// Checks the certificate and key files periodically and reloads them when they change.
+    pub fn start_watcher(self: &Arc<Self>) -> std::io::Result<()> {
+        let store = self.clone();
+        thread::Builder::new()
+            .name("tls-watcher".to_string())
+            .spawn(move || loop {
+                thread::sleep(WATCH_INTERVAL);
+                store.reload_if_changed();
+            })?;
+        Ok(())
+    }
+}
+
+fn build_server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, Error> {
+    let certs = load_certs(Path::new(cert_path)).map_err(|e| read_error(cert_path, e))?;
+    let key = load_private_key(Path::new(key_path)).map_err(|e| read_error(key_path, e))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::msg(format!("Failed to create server config: {}", e)))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn read_error(path: &str, e: Error) -> Error {
+    match e.downcast::<io::Error>() {
+        Ok(e) => file_read_error(path, e).into(),
+        Err(e) => e,
+    }
+}
+
+fn file_stamp(path: &str) -> Option<FileStamp> {
+    let resolved = fs::canonicalize(path).ok()?;
+    let metadata = fs::metadata(&resolved).ok()?;
+    Some((resolved, metadata.modified().ok(), metadata.len()))
+}
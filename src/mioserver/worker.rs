@@ -1,9 +1,10 @@
 use bytes::BytesMut;
 use log::{debug, info, trace};
 use mio::{Events, Interest, Poll, Token};
-use regex::Regex;
 use std::collections::{HashMap, VecDeque};
-use std::io::{self};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -12,11 +13,21 @@ use crate::config::constants::MIN_CHUNK_SIZE;
 use crate::mioserver::handlers::basic_handler::{
     handle_client_readable_data, handle_client_writable_data,
 };
-use crate::mioserver::server::{ConnectionType, ServerConfig, TestState};
+use crate::mioserver::http_upgrade::{parse_upgrade_request, UpgradeRequest};
+use crate::mioserver::limits::{ConnectionLimiter, ConnectionPermit};
+use crate::mioserver::metrics::{Transport, METRICS};
+use crate::mioserver::reload::RuntimeConfigStore;
+use crate::mioserver::proxy_protocol::{parse_header, ProxyHeader, MAX_HEADER_LEN};
+use crate::mioserver::server::{
+    acquire_permit, admit_connection, ip_permitted, AcceptedConnection, ConnectionType, ServerListener,
+    TestState, WorkerQueue, TCP_LISTENER, TLS_LISTENER, WORKER_WAKER,
+};
+use crate::mioserver::session_log::{write_session, SessionRecord};
+use crate::mioserver::tls::TlsConfigStore;
 use crate::mioserver::ServerTestPhase;
 use crate::stream::stream::Stream;
-use crate::tokio_server::utils::use_http::RMBT_UPGRADE;
-use crate::tokio_server::utils::websocket::Handshake;
+use crate::config::constants::RMBT_UPGRADE;
+use crate::logger;
 
 pub struct WorkerThread {
     _thread: thread::JoinHandle<()>,
@@ -26,29 +37,69 @@ struct Worker {
     id: usize,
     poll: Poll,
     connections: HashMap<Token, TestState>,
+    // Соединения, от которых ещё ждём HTTP upgrade
+    handshakes: HashMap<Token, PendingHandshake>,
     events: Events,
     worker_connection_counts: Arc<Mutex<Vec<usize>>>,
-    global_queue: Arc<Mutex<VecDeque<(ConnectionType, Instant)>>>, // Общая очередь
-    server_config: ServerConfig,
+    global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>, // Общая очередь
+    tests_per_worker: usize,
+    // Перечитывается по SIGHUP; тест берёт снимок при подключении
+    runtime: Arc<RuntimeConfigStore>,
+    acceptor: WorkerAcceptor,
+    listener_ready: bool,
     next_token: usize,
-    
+}
+
+struct PendingHandshake {
+    stream: Stream,
+    request: BytesMut,
+    started: Instant,
+    transport: Transport,
+    client_addr: Option<SocketAddr>,
+    permit: Option<ConnectionPermit>,
+    // Ждём PROXY заголовок; до него stream — голый TCP даже для TLS
+    proxy_header: bool,
+}
+
+/// How connections reach a worker: the hand-off queue filled by the server's
+/// acceptor and, in SO_REUSEPORT mode, the worker's own listeners.
+pub struct WorkerAcceptor {
+    pub queue: Arc<WorkerQueue>,
+    // Пусто без -reuseport
+    pub listeners: Vec<ServerListener>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
+    // None, если TLS не настроен
+    pub tls_config: Option<Arc<TlsConfigStore>>,
+    // Сбрасывается при остановке сервера
+    pub accepting: Arc<AtomicBool>,
 }
 
 impl WorkerThread {
     pub fn new(
         id: usize,
+        poll: Poll,
         worker_connection_counts: Arc<Mutex<Vec<usize>>>,
-        global_queue: Arc<Mutex<VecDeque<(ConnectionType, Instant)>>>,
-        server_config: ServerConfig,
+        global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>,
+        tests_per_worker: usize,
+        runtime: Arc<RuntimeConfigStore>,
+        acceptor: WorkerAcceptor,
     ) -> io::Result<Self> {
 
         let thread = thread::Builder::new()
             .stack_size(8 * 1024 * 1024) // 8MB stack
             .spawn(move || {
+                logger::set_worker(id);
                 debug!("Worker {}: starting", id);
-                let mut worker =
-                    Worker::new(id, worker_connection_counts, global_queue, server_config)
-                        .expect("Failed to create worker");
+                let mut worker = Worker::new(
+                    id,
+                    poll,
+                    worker_connection_counts,
+                    global_queue,
+                    tests_per_worker,
+                    runtime,
+                    acceptor,
+                )
+                .expect("Failed to create worker");
                 if let Err(e) = worker.run() {
                     info!("Worker {} error: {}", id, e);
                 }
@@ -61,11 +112,17 @@ impl WorkerThread {
 impl Worker {
     fn new(
         id: usize,
+        poll: Poll,
         worker_connection_counts: Arc<Mutex<Vec<usize>>>,
-        global_queue: Arc<Mutex<VecDeque<(ConnectionType, Instant)>>>,
-        server_config: ServerConfig,
+        global_queue: Arc<Mutex<VecDeque<AcceptedConnection>>>,
+        tests_per_worker: usize,
+        runtime: Arc<RuntimeConfigStore>,
+        mut acceptor: WorkerAcceptor,
     ) -> io::Result<Self> {
-        let poll = Poll::new()?;
+        for listener in acceptor.listeners.iter_mut() {
+            let token = if listener.is_tls { TLS_LISTENER } else { TCP_LISTENER };
+            poll.registry().register(&mut listener.listener, token, Interest::READABLE)?;
+        }
         let events = Events::with_capacity(1024);
         let connections = HashMap::new();
 
@@ -73,129 +130,194 @@ impl Worker {
             id,
             poll,
             connections,
+            handshakes: HashMap::new(),
             events,
             worker_connection_counts,
             global_queue,
-            server_config: server_config.clone(),
-            next_token: 1,
+            tests_per_worker,
+            runtime,
+            acceptor,
+            listener_ready: false,
+            next_token: WORKER_WAKER.0 + 1,
         })
     }
 
     fn run(&mut self) -> io::Result<()> {
         loop {
-            let maybe_connection = if self.connections.is_empty() {
-                let mut global_queue = self.global_queue.lock().unwrap();
-                if let Some((connection, _)) = global_queue.pop_front() {
-                    trace!("Worker {}: taking connection from global queue (queue size after: {})", 
-                        self.id, global_queue.len());
-                    {
-                        let mut counts = self.worker_connection_counts.lock().unwrap();
-                        counts[self.id] += 1;
-                        trace!(
-                            "Worker {}: connection count increased to {} (from global queue)",
-                            self.id,
-                            counts[self.id]
-                        );
-                    }
-                    Some(connection)
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
-
-            if let Some(connection) = maybe_connection {
-                let mut stream = match connection {
-                    ConnectionType::Tcp(stream) => Stream::Tcp(stream),
-                    ConnectionType::Tls(stream) => {
-                        let stream = Stream::new_rustls_server(
-                            stream,
-                            self.server_config.cert_path.clone().unwrap(),
-                            self.server_config.key_path.clone().unwrap(),
-                        )
-                        .unwrap();
-                        stream
-                    }
-                };
-
-                let token = Token(self.next_token);
-                self.next_token += 1;
+            if !self.acceptor.listeners.is_empty() && !self.acceptor.accepting.load(Ordering::Relaxed) {
+                debug!("Worker {}: closing listeners", self.id);
+                self.acceptor.listeners.clear();
+            }
 
-                // Регистрируем новое соединение
-                if let Err(e) = stream.register(&self.poll, token, Interest::READABLE | Interest::WRITABLE) {
-                    info!("Worker {}: Failed to register connection: {}", self.id, e);
-                    continue;
-                }
+            self.take_queued_connections();
+            self.process_all_connections()?;
+        }
+    }
 
+    fn active_tests(&self) -> usize {
+        self.connections.len() + self.handshakes.len()
+    }
 
-                match self.handle_greeting_receive_connection_type(stream, token) {
-                    Ok(stream) => {
-                        self.connections.insert(
-                            token,
-                            TestState {
-                                token,
-                                last_active: Instant::now(),
-                                // stream: Stream::new_rustls_server(stream, None, None).unwrap(),
-                                stream: stream,
-                                measurement_state: ServerTestPhase::GreetingSendVersion,
-                                read_buffer: [0; 1024 * 8],
-                                write_buffer: [0; 1024 * 8],
-                                read_bytes: BytesMut::new(),
-                                read_pos: 0,
-                                write_pos: 0,
-                                num_chunks: 0,
-                                chunk_size: 0,
-                                processed_chunks: 0,
-                                clock: None,
-                                time_ns: None,
-                                duration: 0,
-                                chunk_buffer: vec![0; MIN_CHUNK_SIZE as usize],
-                                total_bytes: 0,
-                                chunk: None,
-                                terminal_chunk: None,
-                                put_duration: None,
-                                bytes_received: VecDeque::new(),
-                            },
-                        );
-                    },
-                    Err(e) => {
-                        info!("Worker {}: Error handling greeting: {}", self.id, e);
-                        continue;
-                    }
-                };
+    /// Забирает соединения, которые acceptor отдал этому воркеру, и
+    /// при наличии свободных слотов — из общей очереди.
+    fn take_queued_connections(&mut self) {
+        let queued: Vec<_> = self.acceptor.queue.connections.lock().unwrap().drain(..).collect();
+        for entry in queued {
+            METRICS.observe_queue_wait(entry.queued_at.elapsed());
+            self.start_connection(entry);
+        }
 
-                debug!(
-                    "Worker {} registered new connection with token {:?} (total connections: {})",
+        while self.active_tests() < self.tests_per_worker {
+            let entry = {
+                let mut global_queue = self.global_queue.lock().unwrap();
+                let entry = global_queue.pop_front();
+                if entry.is_some() {
+                    trace!("Worker {}: taking connection from global queue (queue size after: {})",
+                        self.id, global_queue.len());
+                }
+                entry
+            };
+            let Some(entry) = entry else {
+                break;
+            };
+            METRICS.observe_queue_wait(entry.queued_at.elapsed());
+            {
+                let mut counts = self.worker_connection_counts.lock().unwrap();
+                counts[self.id] += 1;
+                trace!(
+                    "Worker {}: connection count increased to {} (from global queue)",
                     self.id,
-                    token,
-                    self.connections.len()
+                    counts[self.id]
                 );
             }
+            self.start_connection(entry);
+        }
+    }
 
-            if !self.connections.is_empty() {
-                self.process_all_connections()?;
-            } else {
-                thread::sleep(Duration::from_millis(100));
+    /// Registers a connection that is already counted in `worker_connection_counts`
+    /// and waits for its HTTP upgrade request.
+    fn start_connection(&mut self, entry: AcceptedConnection) {
+        let AcceptedConnection {
+            connection,
+            permit,
+            proxy_protocol,
+            ..
+        } = entry;
+        let client_addr = match &connection {
+            ConnectionType::Tcp(stream) | ConnectionType::Tls(stream) => stream.peer_addr().ok(),
+            #[cfg(feature = "quic")]
+            ConnectionType::Quic(stream) => Some(stream.peer_addr()),
+        };
+        let transport = match &connection {
+            ConnectionType::Tcp(_) => Transport::Tcp,
+            ConnectionType::Tls(_) => Transport::Tls,
+            #[cfg(feature = "quic")]
+            ConnectionType::Quic(_) => Transport::Quic,
+        };
+        let mut stream = match connection {
+            ConnectionType::Tcp(stream) => Stream::Tcp(stream),
+            // TLS начнётся после PROXY заголовка
+            ConnectionType::Tls(stream) if proxy_protocol => Stream::Tcp(stream),
+            ConnectionType::Tls(stream) => match self.start_tls(stream) {
+                Some(stream) => stream,
+                None => {
+                    self.handshake_failed(transport);
+                    return;
+                }
+            },
+            #[cfg(feature = "quic")]
+            ConnectionType::Quic(stream) => Stream::Quic(stream),
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        // Регистрируем новое соединение
+        if let Err(e) = stream.register(&self.poll, token, Interest::READABLE | Interest::WRITABLE) {
+            info!("Worker {}: Failed to register connection: {}", self.id, e);
+            self.handshake_failed(transport);
+            return;
+        }
+
+        self.handshakes.insert(
+            token,
+            PendingHandshake {
+                stream,
+                request: BytesMut::new(),
+                started: Instant::now(),
+                transport,
+                client_addr,
+                permit,
+                proxy_header: proxy_protocol,
+            },
+        );
+    }
+
+    fn start_tls(&self, stream: mio::net::TcpStream) -> Option<Stream> {
+        let tls_stream = match &self.acceptor.tls_config {
+            Some(tls_config) => Stream::new_rustls_server(stream, tls_config.current()).map_err(|e| e.to_string()),
+            None => Err("TLS is not configured".to_string()),
+        };
+        match tls_stream {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                info!("Worker {}: Failed to start TLS session: {}", self.id, e);
+                None
             }
         }
     }
 
+    fn handshake_failed(&mut self, transport: Transport) {
+        METRICS.handshake_failed(transport);
+        self.release_slot();
+    }
+
+    fn release_slot(&mut self) {
+        let mut counts = self.worker_connection_counts.lock().unwrap();
+        counts[self.id] -= 1;
+    }
+
     fn process_all_connections(&mut self) -> io::Result<()> {
-        if let Err(e) = self
-            .poll
-            .poll(&mut self.events, Some(std::time::Duration::from_millis(10)))
-        {
+        // Без активных тестов таймауты проверять не нужно, будит Waker или listener
+        let mut timeout = if self.active_tests() == 0 {
+            Duration::from_millis(100)
+        } else {
+            Duration::from_millis(10)
+        };
+        let now = Instant::now();
+        for state in self.connections.values() {
+            if let Some(resume_at) = state.pacer.as_ref().and_then(|pacer| pacer.resume_at()) {
+                timeout = timeout.min(resume_at.saturating_duration_since(now));
+            }
+        }
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
             info!("Worker {}: Poll error: {}", self.id, e);
             return Err(e);
         }
 
         let mut connections_to_remove = Vec::new();
+        let mut handshakes_ready = Vec::new();
 
         for event in self.events.iter() {
             debug!("Worker {}: event {:?} token {:?}", self.id, event, event.token());
             let event_token = event.token();
+            if event_token == TCP_LISTENER || event_token == TLS_LISTENER {
+                self.listener_ready = true;
+                continue;
+            }
+            if event_token == WORKER_WAKER {
+                // Очередь разбирается в начале следующей итерации
+                continue;
+            }
+            if self.handshakes.contains_key(&event_token) {
+                handshakes_ready.push(event_token);
+                continue;
+            }
             if let Some(state) = self.connections.get_mut(&event_token) {
+                let _context = logger::enter_connection(event_token.0, Some(&state.session.session_id));
                 let mut should_remove: Result<usize, io::Error> = Ok(0);
                 state.last_active = Instant::now();
                 if event.is_readable() {
@@ -214,133 +336,472 @@ impl Worker {
                     should_remove = handle_client_writable_data(state, &self.poll);
                 }
 
-                match should_remove {
-                    Ok(n) => {
-                        if n == 0 {
-                            debug!("Worker {}: should_remove: {} token {:?}", self.id, n, event_token);
-                            connections_to_remove.push(event_token);
-                        }
-                        continue;
-                        // If n > 0, continue processing
-                    }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        debug!("Worker {}: would block for token {:?}", self.id, event_token);
-                        continue;
-                    }
-                    Err(e) => {
-                        info!(
-                            "Worker {}: Error handling client data for token {:?} with error {:?} and measurement state {:?}",
-                            self.id, event_token, e, state.measurement_state
-                        );
-                        connections_to_remove.push(event_token);
-                    }
+                if let Some(reason) = close_reason(self.id, event_token, state, should_remove) {
+                    connections_to_remove.push((event_token, reason));
                 }
             }
         }
 
+        // Передачи, которые ждали ограничения скорости; сокет о них уже не напомнит
+        let now = Instant::now();
         for (token, state) in self.connections.iter_mut() {
-            if state.last_active.elapsed() > Duration::from_secs(15) {
+            let Some(interest) = state.pacer.as_mut().and_then(|pacer| pacer.take_due(now)) else {
+                continue;
+            };
+            if connections_to_remove.iter().any(|(removed, _)| removed == token) {
+                continue;
+            }
+            let _context = logger::enter_connection(token.0, Some(&state.session.session_id));
+            state.last_active = now;
+            let result = if interest.is_readable() {
+                handle_client_readable_data(state, &self.poll)
+            } else {
+                handle_client_writable_data(state, &self.poll)
+            };
+            if let Some(reason) = close_reason(self.id, *token, state, result) {
+                connections_to_remove.push((*token, reason));
+            }
+        }
+
+        for token in handshakes_ready {
+            self.continue_handshake(token);
+        }
+
+        let limits = self.runtime.current().server_config.limits;
+        let expired: Vec<Token> = self
+            .handshakes
+            .iter()
+            .filter(|(_, handshake)| handshake.started.elapsed() > limits.handshake_timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            let mut handshake = self.handshakes.remove(&token).unwrap();
+            debug!("Worker {}: handshake timeout after {:?}", self.id, limits.handshake_timeout);
+            let _ = handshake.stream.close();
+            METRICS.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+            self.handshake_failed(handshake.transport);
+        }
+
+        for (token, state) in self.connections.iter_mut() {
+            // Уже закрывается после события или паузы ограничения скорости
+            if connections_to_remove.iter().any(|(removed, _)| removed == token) {
+                continue;
+            }
+            if state.last_active.elapsed() > limits.idle_timeout {
                 debug!("Worker {}: connection {:?} timed out", self.id, token);
-                connections_to_remove.push(token.clone());
+                METRICS.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+                connections_to_remove.push((*token, "idle_timeout".to_string()));
+            } else if !limits.max_session.is_zero() && state.started.elapsed() > limits.max_session + limits.idle_timeout {
+                // Клиент, который так и не прислал следующую команду, получил бы ERR;
+                // застрявшую передачу закрываем без ответа
+                debug!("Worker {}: connection {:?} exceeded the maximum session length", self.id, token);
+                connections_to_remove.push((*token, "session_timeout".to_string()));
             }
         }
 
+        self.close_connections(connections_to_remove);
+
+        let mut phases = HashMap::new();
+        for state in self.connections.values() {
+            *phases.entry(state.measurement_state).or_insert(0) += 1;
+        }
+        if !self.handshakes.is_empty() {
+            phases.insert(ServerTestPhase::GreetingReceiveConnectionType, self.handshakes.len());
+        }
+        METRICS.set_worker_phases(self.id, phases);
+
+        if self.listener_ready {
+            self.accept_connections();
+        }
+
+        debug!("Worker {}: finished processing events", self.id);
 
-        for token in connections_to_remove {
-            self.connections.remove(&token);
+        Ok(())
+    }
+
+    /// Drops the connections and frees their slots. A token may be listed more
+    /// than once; only the first entry closes it.
+    fn close_connections(&mut self, connections_to_remove: Vec<(Token, String)>) {
+        for (token, reason) in connections_to_remove {
+            let Some(mut state) = self.connections.remove(&token) else {
+                continue;
+            };
+            {
+                let _context = logger::enter_connection(token.0, Some(&state.session.session_id));
+                write_session(&mut state, &reason);
+            }
             {
-                println!("Worker {}: removing connection {:?}", self.id, token);
                 let mut counts = self.worker_connection_counts.lock().unwrap();
                 counts[self.id] -= 1;
-                println!("Worker {}: connection count decreased to {}", self.id, counts[self.id]);
+                trace!("Worker {}: connection count decreased to {}", self.id, counts[self.id]);
             }
 
-            println!(
+            debug!(
                 "Worker {}: connection {:?} closed, remaining connections: {}",
                 self.id,
                 token,
                 self.connections.len()
             );
         }
+    }
 
-        debug!("Worker {}: finished processing events", self.id);
+    /// Accepts everything pending on this worker's own listeners. Connections
+    /// beyond `tests_per_worker` go to the global queue so a busy worker does
+    /// not hold clients back.
+    fn accept_connections(&mut self) {
+        self.listener_ready = false;
+        let runtime = self.runtime.current();
+        let mut accepted = Vec::new();
+        for listener in self.acceptor.listeners.iter() {
+            loop {
+                match listener.listener.accept() {
+                    Ok((stream, addr)) => {
+                        if let Some(entry) =
+                            admit_connection(&self.acceptor.connection_limiter, &runtime.ip_filter, stream, addr, listener)
+                        {
+                            accepted.push(entry);
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        debug!("Worker {}: error accepting connection: {}", self.id, e);
+                        break;
+                    }
+                }
+            }
+        }
 
-        Ok(())
+        for entry in accepted {
+            if self.active_tests() < self.tests_per_worker {
+                self.worker_connection_counts.lock().unwrap()[self.id] += 1;
+                self.start_connection(entry);
+            } else {
+                self.global_queue.lock().unwrap().push_back(entry);
+            }
+        }
     }
 
-    fn handle_greeting_receive_connection_type(
-        &mut self,
-        mut stream: Stream,
-        token: Token,
-    ) -> io::Result<Stream> {
-        debug!("Worker {}: handle_greeting_receive_connection_type", self.id);
-        let mut buffer = vec![0; 1024];
-        let mut result = BytesMut::new();
-        let mut loop_flag = false;
-        let timeout = std::time::Duration::from_secs(3);
-        let start_time = std::time::Instant::now();
-
-        while !loop_flag {
-            // Проверяем таймаут
-            if start_time.elapsed() > timeout {
-                debug!("Worker {}: handshake timeout after {:?}", self.id, timeout);
-                stream.close().unwrap();
-                self.connections.remove(&token);
-                return Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout"));
+    /// Reads what has arrived of the HTTP upgrade request and, once it is
+    /// complete, answers it and hands the connection to the test state machine.
+    fn continue_handshake(&mut self, token: Token) {
+        let _context = logger::enter_connection(token.0, None);
+        if self.handshakes[&token].proxy_header {
+            match self.read_proxy_header(token) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    info!("Worker {}: Rejecting connection without valid PROXY header: {}", self.id, e);
+                    let handshake = self.handshakes.remove(&token).unwrap();
+                    self.handshake_failed(handshake.transport);
+                    return;
+                }
             }
+            if !self.handshakes.contains_key(&token) {
+                return;
+            }
+        }
 
-            // Используем таймаут для poll
-            let poll_timeout = timeout - start_time.elapsed();
-            self.poll.poll(&mut self.events, Some(poll_timeout))?;
-            for event in self.events.iter() {
-                if event.is_readable() {
-                    match stream.read(&mut buffer) {
-                        Ok(n) => {
-                            result.extend_from_slice(&buffer[..n]);
-                            debug!("Worker {}: read {} bytes {}", self.id, n, String::from_utf8_lossy(&buffer[..n]));
-                            if result.len() >= 4
-                                && result[result.len() - 4..result.len()]
-                                    == [b'\r', b'\n', b'\r', b'\n'] || String::from_utf8_lossy(&result).contains("\r\n\r\n")
-                            {
-                                let request = String::from_utf8_lossy(&result);
-                                let ws_regex = Regex::new(r"(?i)upgrade:\s*websocket").unwrap();
-
-                                let is_websocket = ws_regex.is_match(&request);
-                                debug!("Worker {}: is_websocket: {}", self.id, is_websocket);
-                                if is_websocket {
-                                    stream = stream.upgrade_to_websocket().unwrap();
-                                    let handshake = Handshake::parse(&request).unwrap();
-                                    stream.finish_server_handshake(handshake).unwrap();
-                                } else {
-                                    //TODO maybe loop
-                                    debug!("Worker {}: writing upgrade response", self.id);
-                                    match stream.write(RMBT_UPGRADE.as_bytes()) {
-                                        Ok(n) => {
-                                            debug!("Worker {}: wrote {} bytes {}", self.id, n, RMBT_UPGRADE);
-                                        }
-                                        Err(e) => {
-                                            debug!("Worker {}: error writing upgrade response: {}", self.id, e);
-                                        }
-                                    }
-                                }
-
-                                debug!("Worker {}: reregistering stream", self.id);
-                                stream.reregister(&self.poll, token, Interest::WRITABLE)?;
-
-                                loop_flag = true;
-                            }
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            continue;
-                        }
-                        Err(e) => {
-                            return Err(e);
-                        }
+        debug!("Worker {}: handle_greeting_receive_connection_type", self.id);
+        let mut buffer = [0; 1024];
+        let handshake = self.handshakes.get_mut(&token).unwrap();
+        let complete = loop {
+            match handshake.stream.read(&mut buffer) {
+                // rustls отдаёт 0, пока идёт TLS handshake и данных ещё нет
+                Ok(0) if !handshake.stream.peer_closed() => break Ok(None),
+                Ok(0) => {
+                    debug!("Worker {}: EOF during handshake", self.id);
+                    break Err(None);
+                }
+                Ok(n) => {
+                    handshake.request.extend_from_slice(&buffer[..n]);
+                    debug!("Worker {}: read {} bytes {}", self.id, n, String::from_utf8_lossy(&buffer[..n]));
+                    match parse_upgrade_request(&handshake.request) {
+                        Ok(Some(request)) => break Ok(Some(request)),
+                        Ok(None) => {}
+                        Err(e) => break Err(Some(e)),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(None),
+                Err(e) => {
+                    debug!("Worker {}: error reading handshake: {}", self.id, e);
+                    break Err(None);
+                }
+            }
+        };
+
+        match complete {
+            Ok(None) => {}
+            Ok(Some(request)) => {
+                let handshake = self.handshakes.remove(&token).unwrap();
+                match self.finish_handshake(handshake, token, request) {
+                    Ok(state) => {
+                        self.connections.insert(token, state);
+                        debug!(
+                            "Worker {} registered new connection with token {:?} (total connections: {})",
+                            self.id,
+                            token,
+                            self.connections.len()
+                        );
+                    }
+                    Err((e, transport)) => {
+                        info!("Worker {}: Error handling greeting: {}", self.id, e);
+                        self.handshake_failed(transport);
                     }
                 }
             }
+            Err(error) => {
+                let mut handshake = self.handshakes.remove(&token).unwrap();
+                if let Some(error) = error {
+                    info!(
+                        "Worker {}: Rejecting upgrade request from {:?}: {}",
+                        self.id, handshake.client_addr, error
+                    );
+                    // Ответ без повторов: сканерам хватит и части
+                    let _ = handshake.stream.write(error.response().as_bytes());
+                    let _ = handshake.stream.flush();
+                }
+                self.handshake_failed(handshake.transport);
+            }
+        }
+    }
+
+    /// Consumes the PROXY header in front of the connection and replaces the
+    /// balancer's address with the client's. Only the header is read from the
+    /// socket, the TLS ClientHello or upgrade request after it stays queued.
+    /// Returns Ok(false) while the header is incomplete.
+    fn read_proxy_header(&mut self, token: Token) -> io::Result<bool> {
+        let handshake = self.handshakes.get_mut(&token).unwrap();
+        let Stream::Tcp(tcp) = &mut handshake.stream else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "PROXY header expected on a raw TCP stream"));
+        };
+        let mut buffer = [0; MAX_HEADER_LEN];
+        let n = match tcp.peek(&mut buffer) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let Some((header, len)) = parse_header(&buffer[..n])? else {
+            return Ok(false);
+        };
+        tcp.read_exact(&mut buffer[..len])?;
+
+        let mut handshake = self.handshakes.remove(&token).unwrap();
+        handshake.proxy_header = false;
+        if let ProxyHeader::Proxied { source, destination } = header {
+            debug!(
+                "Worker {}: PROXY header from {:?}: client {} -> {}",
+                self.id, handshake.client_addr, source, destination
+            );
+            handshake.client_addr = Some(source);
+        }
+
+        let Some(client_addr) = handshake.client_addr else {
+            self.release_slot();
+            return Ok(true);
+        };
+        if !ip_permitted(&self.runtime.current().ip_filter, client_addr, handshake.transport) {
+            self.release_slot();
+            return Ok(true);
+        }
+        let Some(permit) = acquire_permit(&self.acceptor.connection_limiter, client_addr) else {
+            self.release_slot();
+            return Ok(true);
+        };
+        handshake.permit = Some(permit);
+
+        if handshake.transport == Transport::Tls {
+            let Stream::Tcp(tcp) = handshake.stream else {
+                unreachable!("checked above");
+            };
+            handshake.stream = match self.start_tls(tcp) {
+                Some(stream) => stream,
+                None => {
+                    self.handshake_failed(handshake.transport);
+                    return Ok(true);
+                }
+            };
+            if let Err(e) = handshake.stream.reregister(&self.poll, token, Interest::READABLE | Interest::WRITABLE) {
+                info!("Worker {}: Failed to register connection: {}", self.id, e);
+                self.handshake_failed(handshake.transport);
+                return Ok(true);
+            }
+        }
+        self.handshakes.insert(token, handshake);
+        Ok(true)
+    }
+
+    fn finish_handshake(
+        &mut self,
+        handshake: PendingHandshake,
+        token: Token,
+        request: UpgradeRequest,
+    ) -> Result<TestState, (io::Error, Transport)> {
+        let PendingHandshake {
+            mut stream,
+            mut transport,
+            client_addr,
+            permit,
+            ..
+        } = handshake;
+
+        if let UpgradeRequest::WebSocket(handshake) = request {
+            debug!("Worker {}: websocket upgrade", self.id);
+            transport = transport.websocket();
+            let websocket_error = |e: anyhow::Error| (io::Error::new(io::ErrorKind::InvalidData, e.to_string()), transport);
+            stream = stream.upgrade_to_websocket().map_err(websocket_error)?;
+            stream.finish_server_handshake(handshake).map_err(websocket_error)?;
+        } else {
+            //TODO maybe loop
+            debug!("Worker {}: writing upgrade response", self.id);
+            match stream.write(RMBT_UPGRADE.as_bytes()) {
+                Ok(n) => {
+                    debug!("Worker {}: wrote {} bytes {}", self.id, n, RMBT_UPGRADE);
+                }
+                Err(e) => {
+                    debug!("Worker {}: error writing upgrade response: {}", self.id, e);
+                }
+            }
         }
+
+        debug!("Worker {}: reregistering stream", self.id);
+        stream
+            .reregister(&self.poll, token, Interest::WRITABLE)
+            .map_err(|e| (e, transport))?;
         debug!("Worker {}: handshake done", self.id);
-        Ok(stream)
+
+        let runtime = self.runtime.current();
+        Ok(TestState {
+            token,
+            started: Instant::now(),
+            last_active: Instant::now(),
+            stream,
+            measurement_state: ServerTestPhase::GreetingSendVersion,
+            read_buffer: [0; 1024 * 8],
+            write_buffer: [0; 1024 * 8],
+            read_bytes: BytesMut::new(),
+            read_pos: 0,
+            write_pos: 0,
+            num_chunks: 0,
+            chunk_size: 0,
+            processed_chunks: 0,
+            clock: None,
+            time_ns: None,
+            duration: 0,
+            chunk_buffer: vec![0; MIN_CHUNK_SIZE as usize],
+            total_bytes: 0,
+            chunk: None,
+            terminal_chunk: None,
+            put_duration: None,
+            bytes_received: VecDeque::new(),
+            token_validator: runtime.token_validator.clone(),
+            token_uuid: None,
+            client_addr,
+            sig_key: runtime.server_config.secret_key.clone(),
+            total_bytes_sent: 0,
+            sent_time_ns: None,
+            total_bytes_received: 0,
+            received_time_ns: None,
+            signed_result: None,
+            _permit: permit,
+            session: SessionRecord::new(client_addr, transport),
+            limits: runtime.server_config.limits,
+            bandwidth_caps: runtime.bandwidth_caps.clone(),
+            token_label: None,
+            pacer: None,
+        })
+    }
+}
+
+/// Why a connection has to be closed after its handler returned `result`;
+/// `None` keeps it open.
+fn close_reason(worker_id: usize, token: Token, state: &TestState, result: io::Result<usize>) -> Option<String> {
+    match result {
+        Ok(0) => {
+            debug!("Worker {}: should_remove token {:?}", worker_id, token);
+            let reason = match state.measurement_state {
+                ServerTestPhase::GreetingSendErr => "token_rejected",
+                ServerTestPhase::LimitSendErr => "limit_exceeded",
+                _ => "client_closed",
+            };
+            Some(reason.to_string())
+        }
+        Ok(_) => None,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            debug!("Worker {}: would block for token {:?}", worker_id, token);
+            None
+        }
+        Err(e) => {
+            info!(
+                "Worker {}: Error handling client data for token {:?} with error {:?} and measurement state {:?}",
+                worker_id, token, e, state.measurement_state
+            );
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                Some("client_closed".to_string())
+            } else {
+                Some(format!("error: {}", e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileConfig;
+    use crate::mioserver::parser::parse_args;
+    use crate::mioserver::reload::RuntimeConfig;
+    use mio::Waker;
+
+    fn test_worker() -> Worker {
+        let poll = Poll::new().unwrap();
+        let queue = Arc::new(WorkerQueue {
+            connections: Mutex::new(VecDeque::new()),
+            waker: Waker::new(poll.registry(), WORKER_WAKER).unwrap(),
+        });
+        let config = parse_args(vec!["-s".to_string()], FileConfig::default()).unwrap();
+        let runtime = RuntimeConfigStore::new(RuntimeConfig::new(config).unwrap());
+        let acceptor = WorkerAcceptor {
+            queue,
+            listeners: Vec::new(),
+            connection_limiter: Arc::new(ConnectionLimiter::new(0, 0, Vec::new())),
+            tls_config: None,
+            accepting: Arc::new(AtomicBool::new(true)),
+        };
+        let counts = Arc::new(Mutex::new(vec![0]));
+        Worker::new(0, poll, counts, Arc::new(Mutex::new(VecDeque::new())), 4, runtime, acceptor).unwrap()
+    }
+
+    #[test]
+    fn test_connection_closed_twice_frees_one_slot() {
+        let mut worker = test_worker();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let token = Token(WORKER_WAKER.0 + 1);
+        let mut stream = Stream::Tcp(mio::net::TcpStream::from_std(stream));
+        stream.register(&worker.poll, token, Interest::READABLE).unwrap();
+        let handshake = PendingHandshake {
+            stream,
+            request: BytesMut::new(),
+            started: Instant::now(),
+            transport: Transport::Tcp,
+            client_addr: Some(addr),
+            permit: None,
+            proxy_header: false,
+        };
+        let state = worker.finish_handshake(handshake, token, UpgradeRequest::Rmbt).ok().unwrap();
+        worker.connections.insert(token, state);
+        worker.worker_connection_counts.lock().unwrap()[0] = 1;
+
+        // Закрыто событием и тут же попало под idle_timeout
+        worker.close_connections(vec![
+            (token, "client_closed".to_string()),
+            (token, "idle_timeout".to_string()),
+        ]);
+        assert!(worker.connections.is_empty());
+        assert_eq!(worker.worker_connection_counts.lock().unwrap()[0], 0);
     }
 }
@@ -1,7 +1,11 @@
 pub mod stream;
 pub mod websocket;
+#[cfg(feature = "openssl")]
 pub mod websocket_tls_openssl;
 pub mod rustls_server;
 pub mod rustls;
+#[cfg(feature = "openssl")]
 pub mod openssl;
 pub mod websocket_rustls_server;
+#[cfg(feature = "quic")]
+pub mod quic;
@@ -0,0 +1,695 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use log::{debug, info, trace};
+use mio::net::{UdpSocket, UnixStream};
+use mio::{Events, Interest, Poll, Token};
+use quinn_proto::rustls;
+use quinn_proto::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn_proto::{
+    ClientConfig, ConnectionHandle, DatagramEvent, Dir, Endpoint, EndpointConfig, Event, IdleTimeout,
+    ReadError, ServerConfig, StreamEvent, StreamId, TransportConfig, VarInt, WriteError,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::stream::rustls_server::{load_certs, load_private_key};
+
+// ALPN, без него rustls не начнёт QUIC handshake
+const ALPN: &[u8] = b"rmbt";
+const UDP_SOCKET: Token = Token(0);
+const UDP_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const BRIDGE_CHUNK: usize = 64 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// Клиент с -t открывает столько потоков на одном соединении
+const MAX_STREAMS: u32 = 256;
+
+/// One bidirectional QUIC stream seen through a local socket pair. The
+/// driver thread owns the QUIC connection and copies bytes between the stream
+/// and the other end of the pair, so the RMBT code reads and writes it like
+/// a TCP socket.
+#[derive(Debug)]
+pub struct QuicStream {
+    socket: UnixStream,
+    peer_addr: SocketAddr,
+}
+
+impl QuicStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+
+    pub fn register(&mut self, poll: &Poll, token: Token, interest: Interest) -> io::Result<()> {
+        poll.registry().register(&mut self.socket, token, interest)
+    }
+
+    pub fn reregister(&mut self, poll: &Poll, token: Token, interest: Interest) -> io::Result<()> {
+        poll.registry().reregister(&mut self.socket, token, interest)
+    }
+}
+
+/// Decides on new connections and takes the streams the server driver accepts.
+pub trait QuicAcceptor: Send {
+    /// None refuses the connection; otherwise the TLS config to answer it with.
+    fn accept_connection(&mut self, remote: SocketAddr) -> Option<Arc<ServerConfig>>;
+    fn accept_stream(&mut self, stream: QuicStream);
+}
+
+/// Opens a QUIC connection to `addr` with `streams` bidirectional streams on it.
+/// The server certificate is not verified, as with `-tls`.
+pub fn connect(addr: SocketAddr, streams: usize) -> Result<Vec<QuicStream>> {
+    let bind_addr: SocketAddr = if addr.is_ipv6() { "[::]:0".parse()? } else { "0.0.0.0:0".parse()? };
+    let mut driver = Driver::new(bind_udp_socket(bind_addr, false)?, None, None)?;
+
+    // Тот же выбор имени, что у rustls клиента
+    let server_name = if addr.ip().is_loopback() { "localhost" } else { "dev.measurementservers.net" };
+    let (handle, connection) = driver
+        .endpoint
+        .connect(Instant::now(), client_config()?, addr, server_name)
+        .map_err(|e| anyhow::anyhow!("QUIC connect to {} failed: {}", addr, e))?;
+    driver.connections.insert(handle, QuicConnection::new(connection));
+
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        let connection = driver
+            .connections
+            .get_mut(&handle)
+            .filter(|connection| !connection.conn.is_closed())
+            .ok_or_else(|| anyhow::anyhow!("QUIC connection to {} closed during handshake", addr))?;
+        if connection.connected {
+            break;
+        }
+        if Instant::now() >= deadline {
+            return Err(anyhow::anyhow!("QUIC handshake with {} timed out", addr));
+        }
+        // Таймеры QUIC (повтор Initial) важнее общего срока
+        let wake_at = connection.conn.poll_timeout().map_or(deadline, |at| at.min(deadline));
+        driver.turn(Some(wake_at.saturating_duration_since(Instant::now())))?;
+    }
+    debug!("QUIC connection to {} established", addr);
+
+    let mut quic_streams = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        let connection = driver.connections.get_mut(&handle).unwrap();
+        let id = connection
+            .conn
+            .streams()
+            .open(Dir::Bi)
+            .ok_or_else(|| anyhow::anyhow!("Server allows fewer than {} QUIC streams", streams))?;
+        quic_streams.push(driver.bridge(handle, id, addr)?);
+    }
+
+    thread::Builder::new()
+        .name("quic-client".to_string())
+        .spawn(move || {
+            if let Err(e) = driver.run() {
+                info!("QUIC client driver error: {}", e);
+            }
+        })?;
+    Ok(quic_streams)
+}
+
+/// Serves QUIC on a socket from `bind_udp_socket` in its own thread; every
+/// stream a client opens is handed to `acceptor`. `config` is only the
+/// starting point, each connection gets the one `acceptor` returns.
+pub fn listen(socket: UdpSocket, config: Arc<ServerConfig>, acceptor: Box<dyn QuicAcceptor>) -> io::Result<()> {
+    let local_addr = socket.local_addr()?;
+    let mut driver = Driver::new(socket, Some(config), Some(acceptor))?;
+    thread::Builder::new()
+        .name("quic-server".to_string())
+        .spawn(move || {
+            if let Err(e) = driver.run() {
+                info!("QUIC server on {} stopped: {}", local_addr, e);
+            }
+        })?;
+    Ok(())
+}
+
+/// QUIC server config for a PEM certificate chain and key.
+pub fn server_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(Path::new(cert_path))?;
+    let key = load_private_key(Path::new(key_path))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(Arc::new(server_transport_config()));
+    Ok(Arc::new(config))
+}
+
+fn client_config() -> Result<ClientConfig> {
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(danger::NoCertificateVerification))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport_config()));
+    Ok(config)
+}
+
+fn transport_config() -> TransportConfig {
+    let mut config = TransportConfig::default();
+    // Окна по умолчанию рассчитаны на ~100 Мбит/с при 100 мс RTT
+    config
+        .stream_receive_window(VarInt::from_u32(16 * 1024 * 1024))
+        .send_window(64 * 1024 * 1024)
+        .max_idle_timeout(Some(IdleTimeout::try_from(IDLE_TIMEOUT).unwrap()))
+        .max_concurrent_uni_streams(VarInt::from_u32(0));
+    config
+}
+
+fn server_transport_config() -> TransportConfig {
+    let mut config = transport_config();
+    config.max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS));
+    config
+}
+
+/// Non-blocking UDP socket with large buffers; `[::]` is dual-stack unless `only_v6`.
+pub fn bind_udp_socket(addr: SocketAddr, only_v6: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // Ядро может урезать до net.core.rmem_max, это не ошибка
+    let _ = socket.set_recv_buffer_size(UDP_BUFFER_SIZE);
+    let _ = socket.set_send_buffer_size(UDP_BUFFER_SIZE);
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind UDP {}: {}", addr, e)))?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+struct QuicConnection {
+    conn: quinn_proto::Connection,
+    connected: bool,
+    // Клиент закрывает соединение, когда открытые им потоки закончились
+    streams_opened: bool,
+    bridges: HashMap<StreamId, Bridge>,
+}
+
+impl QuicConnection {
+    fn new(conn: quinn_proto::Connection) -> Self {
+        QuicConnection { conn, connected: false, streams_opened: false, bridges: HashMap::new() }
+    }
+}
+
+/// Driver side of a `QuicStream`.
+struct Bridge {
+    socket: UnixStream,
+    // Прочитано из сокета, но ещё не принято QUIC (flow control)
+    outgoing: BytesMut,
+    // Получено из QUIC, но сокет пока не принял
+    incoming: Bytes,
+    local_closed: bool,
+    remote_finished: bool,
+    failed: bool,
+}
+
+impl Bridge {
+    fn done(&self) -> bool {
+        self.failed || (self.local_closed && self.outgoing.is_empty() && self.remote_finished && self.incoming.is_empty())
+    }
+}
+
+/// UDP socket, quinn endpoint and the connections on it. The client runs one
+/// per connection, the server one per listen address.
+struct Driver {
+    poll: Poll,
+    events: Events,
+    socket: UdpSocket,
+    endpoint: Endpoint,
+    connections: HashMap<ConnectionHandle, QuicConnection>,
+    acceptor: Option<Box<dyn QuicAcceptor>>,
+    next_token: usize,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+    // Датаграмма, которую не принял переполненный сокет
+    blocked_transmit: Option<(Vec<u8>, SocketAddr)>,
+}
+
+impl Driver {
+    /// `acceptor` makes it a server; without `server_config` the endpoint
+    /// drops Initial packets before the acceptor sees them.
+    fn new(
+        mut socket: UdpSocket,
+        server_config: Option<Arc<ServerConfig>>,
+        acceptor: Option<Box<dyn QuicAcceptor>>,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        poll.registry().register(&mut socket, UDP_SOCKET, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(Driver {
+            poll,
+            events: Events::with_capacity(1024),
+            socket,
+            endpoint: Endpoint::new(Arc::new(EndpointConfig::default()), server_config, false, None),
+            connections: HashMap::new(),
+            acceptor,
+            next_token: UDP_SOCKET.0 + 1,
+            recv_buf: vec![0; MAX_DATAGRAM_SIZE],
+            send_buf: Vec::with_capacity(MAX_DATAGRAM_SIZE),
+            blocked_transmit: None,
+        })
+    }
+
+    /// Runs until the last connection is gone; a server driver never stops.
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            if self.acceptor.is_none() && self.connections.is_empty() {
+                debug!("QUIC driver: all connections closed");
+                return Ok(());
+            }
+            let timeout = self
+                .connections
+                .values_mut()
+                .filter_map(|c| c.conn.poll_timeout())
+                .min()
+                .map(|at| at.saturating_duration_since(Instant::now()));
+            self.turn(timeout)?;
+        }
+    }
+
+    /// Waits for socket readiness or the next QUIC timer and moves everything
+    /// that can move.
+    fn turn(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        self.receive_datagrams()?;
+
+        let now = Instant::now();
+        let handles: Vec<ConnectionHandle> = self.connections.keys().copied().collect();
+        for handle in handles {
+            self.drive_connection(handle, now)?;
+        }
+        self.transmit()
+    }
+
+    fn receive_datagrams(&mut self) -> io::Result<()> {
+        loop {
+            let (n, remote) = match self.socket.recv_from(&mut self.recv_buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // ICMP от прошлых отправок, датаграммы не потеряны
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e),
+            };
+            let now = Instant::now();
+            let data = BytesMut::from(&self.recv_buf[..n]);
+            self.send_buf.clear();
+            match self.endpoint.handle(now, remote, None, None, data, &mut self.send_buf) {
+                Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                    if let Some(connection) = self.connections.get_mut(&handle) {
+                        connection.conn.handle_event(event);
+                    }
+                }
+                Some(DatagramEvent::NewConnection(incoming)) => {
+                    let config = self.acceptor.as_mut().and_then(|a| a.accept_connection(remote));
+                    self.send_buf.clear();
+                    match config {
+                        Some(config) => {
+                            // После перезагрузки сертификата новый конфиг нужен и самому endpoint
+                            self.endpoint.set_server_config(Some(config.clone()));
+                            match self.endpoint.accept(incoming, now, &mut self.send_buf, Some(config)) {
+                                Ok((handle, conn)) => {
+                                    debug!("QUIC connection from {}", remote);
+                                    self.connections.insert(handle, QuicConnection::new(conn));
+                                }
+                                Err(e) => {
+                                    debug!("Failed to accept QUIC connection from {}: {}", remote, e.cause);
+                                    if let Some(transmit) = e.response {
+                                        self.send_now(&transmit, remote);
+                                    }
+                                }
+                            }
+                        }
+                        None => {
+                            let transmit = self.endpoint.refuse(incoming, &mut self.send_buf);
+                            self.send_now(&transmit, remote);
+                        }
+                    }
+                }
+                Some(DatagramEvent::Response(transmit)) => self.send_now(&transmit, remote),
+                None => {}
+            }
+        }
+    }
+
+    /// Best effort send of an endpoint answer; QUIC retransmits if it is lost.
+    fn send_now(&mut self, transmit: &quinn_proto::Transmit, destination: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&self.send_buf[..transmit.size], destination) {
+            trace!("QUIC send to {} failed: {}", destination, e);
+        }
+    }
+
+    fn drive_connection(&mut self, handle: ConnectionHandle, now: Instant) -> io::Result<()> {
+        let connection = self.connections.get_mut(&handle).unwrap();
+        if connection.conn.poll_timeout().is_some_and(|at| at <= now) {
+            connection.conn.handle_timeout(now);
+        }
+        while let Some(event) = connection.conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(handle, event) {
+                connection.conn.handle_event(event);
+            }
+        }
+
+        let mut opened = false;
+        while let Some(event) = connection.conn.poll() {
+            match event {
+                Event::Connected => connection.connected = true,
+                Event::ConnectionLost { reason } => {
+                    debug!("QUIC connection to {} lost: {}", connection.conn.remote_address(), reason);
+                    // Сокеты закрываются, тесты увидят EOF
+                    connection.bridges.clear();
+                }
+                Event::Stream(StreamEvent::Opened { dir: Dir::Bi }) => opened = true,
+                _ => {}
+            }
+        }
+        if opened {
+            let remote = connection.conn.remote_address();
+            while let Some(id) = self.connections.get_mut(&handle).unwrap().conn.streams().accept(Dir::Bi) {
+                let stream = self.bridge(handle, id, remote)?;
+                if let Some(acceptor) = self.acceptor.as_mut() {
+                    acceptor.accept_stream(stream);
+                }
+            }
+        }
+
+        let connection = self.connections.get_mut(&handle).unwrap();
+        for (id, bridge) in connection.bridges.iter_mut() {
+            pump(&mut connection.conn, *id, bridge);
+        }
+        connection.bridges.retain(|id, bridge| {
+            if bridge.done() {
+                trace!("QUIC stream {} done", id);
+            }
+            !bridge.done()
+        });
+
+        if self.acceptor.is_none() && connection.streams_opened && connection.bridges.is_empty() && !connection.conn.is_closed() {
+            connection.conn.close(now, VarInt::from_u32(0), Bytes::from_static(b"done"));
+        }
+        if connection.conn.is_drained() {
+            self.connections.remove(&handle);
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self) -> io::Result<()> {
+        if let Some((datagram, destination)) = self.blocked_transmit.take() {
+            match self.socket.send_to(&datagram, destination) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.blocked_transmit = Some((datagram, destination));
+                    return Ok(());
+                }
+                Err(e) => trace!("QUIC send to {} failed: {}", destination, e),
+                Ok(_) => {}
+            }
+        }
+
+        let now = Instant::now();
+        for connection in self.connections.values_mut() {
+            loop {
+                self.send_buf.clear();
+                let Some(transmit) = connection.conn.poll_transmit(now, 1, &mut self.send_buf) else {
+                    break;
+                };
+                match self.socket.send_to(&self.send_buf[..transmit.size], transmit.destination) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // Ждём WRITABLE на UDP сокете
+                        self.blocked_transmit = Some((self.send_buf[..transmit.size].to_vec(), transmit.destination));
+                        return Ok(());
+                    }
+                    Err(e) => trace!("QUIC send to {} failed: {}", transmit.destination, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the socket pair for stream `id`; the driver keeps one end.
+    fn bridge(&mut self, handle: ConnectionHandle, id: StreamId, peer_addr: SocketAddr) -> io::Result<QuicStream> {
+        let (mut local, remote) = UnixStream::pair()?;
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.registry().register(&mut local, token, Interest::READABLE | Interest::WRITABLE)?;
+        let connection = self.connections.get_mut(&handle).unwrap();
+        connection.streams_opened = true;
+        connection.bridges.insert(
+            id,
+            Bridge {
+                socket: local,
+                outgoing: BytesMut::new(),
+                incoming: Bytes::new(),
+                local_closed: false,
+                remote_finished: false,
+                failed: false,
+            },
+        );
+        Ok(QuicStream { socket: remote, peer_addr })
+    }
+}
+
+/// Copies what it can in both directions between stream `id` and its socket.
+fn pump(conn: &mut quinn_proto::Connection, id: StreamId, bridge: &mut Bridge) {
+    let mut buf = [0u8; BRIDGE_CHUNK];
+    // Сокет -> QUIC
+    while !bridge.failed {
+        if bridge.outgoing.is_empty() {
+            if bridge.local_closed {
+                break;
+            }
+            match bridge.socket.read(&mut buf) {
+                Ok(0) => {
+                    bridge.local_closed = true;
+                    let _ = conn.send_stream(id).finish();
+                    break;
+                }
+                Ok(n) => bridge.outgoing.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => bridge.failed = true,
+            }
+            continue;
+        }
+        match conn.send_stream(id).write(&bridge.outgoing) {
+            Ok(n) => {
+                let _ = bridge.outgoing.split_to(n);
+            }
+            Err(WriteError::Blocked) => break,
+            Err(_) => bridge.failed = true,
+        }
+    }
+
+    // QUIC -> сокет
+    while !bridge.failed {
+        if bridge.incoming.is_empty() {
+            if bridge.remote_finished {
+                break;
+            }
+            let mut stream = conn.recv_stream(id);
+            let mut chunks = match stream.read(true) {
+                Ok(chunks) => chunks,
+                Err(_) => {
+                    bridge.failed = true;
+                    break;
+                }
+            };
+            let next = chunks.next(BRIDGE_CHUNK);
+            // Возвращает кредит flow control отправителю
+            let _ = chunks.finalize();
+            match next {
+                Ok(Some(chunk)) => bridge.incoming = chunk.bytes,
+                Ok(None) => {
+                    bridge.remote_finished = true;
+                    let _ = bridge.socket.shutdown(Shutdown::Write);
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(_)) => bridge.failed = true,
+            }
+            continue;
+        }
+        match bridge.socket.write(&bridge.incoming) {
+            Ok(n) => {
+                let _ = bridge.incoming.split_to(n);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(_) => {
+                // Тест закрыл сокет, дальше слать некуда
+                let _ = conn.recv_stream(id).stop(VarInt::from_u32(0));
+                bridge.failed = true;
+            }
+        }
+    }
+
+    if bridge.failed {
+        let _ = conn.send_stream(id).reset(VarInt::from_u32(0));
+    }
+}
+
+mod danger {
+    use quinn_proto::rustls;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::DigitallySignedStruct;
+
+    #[derive(Debug)]
+    pub struct NoCertificateVerification;
+
+    impl ServerCertVerifier for NoCertificateVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer,
+            _intermediates: &[CertificateDer],
+            _server_name: &ServerName,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    pub(crate) const TEST_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/stream/testdata/localhost.pem");
+    pub(crate) const TEST_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/stream/testdata/localhost.key");
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct ChannelAcceptor {
+        config: Option<Arc<ServerConfig>>,
+        streams: mpsc::Sender<QuicStream>,
+    }
+
+    impl QuicAcceptor for ChannelAcceptor {
+        fn accept_connection(&mut self, _remote: SocketAddr) -> Option<Arc<ServerConfig>> {
+            self.config.clone()
+        }
+
+        fn accept_stream(&mut self, stream: QuicStream) {
+            let _ = self.streams.send(stream);
+        }
+    }
+
+    /// Starts a server on a free loopback port; `refuse` turns every connection away.
+    fn start_server(refuse: bool) -> (SocketAddr, mpsc::Receiver<QuicStream>) {
+        let config = server_config(TEST_CERT, TEST_KEY).unwrap();
+        let socket = bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let acceptor = ChannelAcceptor { config: (!refuse).then(|| config.clone()), streams: sender };
+        listen(socket, config, Box::new(acceptor)).unwrap();
+        (addr, receiver)
+    }
+
+    /// Reads one line from a non-blocking stream; None on EOF or a reset.
+    pub(crate) fn read_line(stream: &mut QuicStream) -> Option<String> {
+        let deadline = Instant::now() + TEST_TIMEOUT;
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            match stream.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) if byte[0] == b'\n' => return Some(String::from_utf8(line).unwrap()),
+                Ok(_) => line.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "timed out waiting for a line");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    pub(crate) fn write_line(stream: &mut QuicStream, line: &str) {
+        let data = format!("{}\n", line);
+        let mut written = 0;
+        while written < data.len() {
+            match stream.write(&data.as_bytes()[written..]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(e) => panic!("write failed: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_listen_and_connect() {
+        let (addr, accepted) = start_server(false);
+        let mut streams = connect(addr, 3).unwrap();
+        assert_eq!(streams.len(), 3);
+
+        // Сервер видит поток только после первых байт клиента
+        for (i, client) in streams.iter_mut().enumerate() {
+            write_line(client, &format!("GETTIME {}", i + 1));
+            let mut server = accepted.recv_timeout(TEST_TIMEOUT).unwrap();
+            assert!(server.peer_addr().ip().is_loopback());
+            assert_eq!(read_line(&mut server).unwrap(), format!("GETTIME {}", i + 1));
+            write_line(&mut server, "TIME 1000");
+            assert_eq!(read_line(client).unwrap(), "TIME 1000");
+        }
+    }
+
+    #[test]
+    fn test_refused_connection() {
+        let (addr, accepted) = start_server(true);
+        let started = Instant::now();
+        let err = connect(addr, 1).unwrap_err();
+        assert!(err.to_string().contains("closed during handshake"), "{}", err);
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+        assert!(accepted.try_recv().is_err());
+    }
+}
@@ -14,24 +14,15 @@ pub struct RustlsServerStream {
     pub stream: TcpStream,
     pub finished: bool,
     pub temp_buf: Vec<u8>,
+    // read() отдаёт 0 и без EOF, пока идёт TLS handshake
+    pub peer_closed: bool,
 }
 
 impl RustlsServerStream {
-    pub fn new(stream: TcpStream, cert_path: String, key_path: String) -> Result<Self> {
+    pub fn new(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Self> {
         stream.set_nodelay(true)?;
 
-        let certs = load_certs(Path::new(&cert_path))?;
-        let key = load_private_key(Path::new(&key_path))?;
-
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| Error::msg(format!("Failed to create server config: {}", e)))?;
-
-            config.alpn_protocols = vec![b"http/1.1".to_vec()];
-
-
-        let conn = ServerConnection::new(Arc::new(config))?;
+        let conn = ServerConnection::new(config)?;
 
         // conn.set_buffer_limit(Some(1024 * 1024 * 10));
 
@@ -40,6 +31,7 @@ impl RustlsServerStream {
             stream,
             finished: true,
             temp_buf: vec![],
+            peer_closed: false,
         })
     }
 
@@ -81,6 +73,7 @@ impl RustlsServerStream {
             }
             Ok(0) => {
                 // trace!("TLS connection closed");
+                self.peer_closed = true;
                 return Ok(0);
             }
             Ok(n) => {
@@ -159,6 +152,7 @@ impl RustlsServerStream {
         // Check if peer has closed
         if io_state.peer_has_closed() {
             trace!("Peer has closed the connection");
+            self.peer_closed = true;
             return Ok(0);
         }
 
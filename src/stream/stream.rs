@@ -3,28 +3,34 @@ use mio::{net::TcpStream, Interest, Poll, Token};
 use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
 
-use crate::client::constants::RMBT_UPGRADE_REQUEST;
+use crate::config::constants::RMBT_UPGRADE_REQUEST;
 use crate::stream::{
     websocket::WebSocketClient,
-    websocket_tls_openssl::WebSocketTlsClient,
-    openssl::OpenSslStream,
     rustls::RustlsStream,
     rustls_server::RustlsServerStream,
     websocket_rustls_server::WebSocketRustlsServerStream,
-    
 };
+#[cfg(feature = "openssl")]
+use crate::stream::{openssl::OpenSslStream, websocket_tls_openssl::WebSocketTlsClient};
+#[cfg(feature = "quic")]
+use crate::stream::quic::QuicStream;
 use crate::tokio_server::utils::websocket::Handshake;
 
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
     WebSocket(WebSocketClient),
+    #[cfg(feature = "openssl")]
     OpenSsl(OpenSslStream),
     Rustls(RustlsStream),
     RustlsServer(RustlsServerStream),
+    #[cfg(feature = "openssl")]
     WebSocketTls(WebSocketTlsClient),
     WebSocketRustlsServer(WebSocketRustlsServerStream),
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
 }
 
 impl Stream {
@@ -37,12 +43,25 @@ impl Stream {
     pub fn return_type(&self) -> &str {
         match self {
             Stream::Tcp(_) => "Tcp",
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(_) => "OpenSsl",
             Stream::WebSocket(_) => "WebSocket",
             Stream::Rustls(_) => "Rustls",
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(_) => "WebSocketTls",
             Stream::RustlsServer(_) => "RustlsServer",
             Stream::WebSocketRustlsServer(_) => "WebSocketRustlsServer",
+            #[cfg(feature = "quic")]
+            Stream::Quic(_) => "Quic",
+        }
+    }
+
+    /// Whether a read that returned 0 saw the peer close the connection; the
+    /// rustls server stream also returns 0 after reading only handshake records.
+    pub fn peer_closed(&self) -> bool {
+        match self {
+            Stream::RustlsServer(stream) => stream.peer_closed,
+            _ => true,
         }
     }
 
@@ -56,6 +75,8 @@ impl Stream {
                 let stream = WebSocketRustlsServerStream::from_rustls_server_stream(stream)?;
                 Ok(Stream::WebSocketRustlsServer(stream))
             }
+            #[cfg(feature = "quic")]
+            Stream::Quic(_) => Err(anyhow::anyhow!("WebSocket is not supported over QUIC")),
             _ => Ok(self),
         }
     }
@@ -81,35 +102,67 @@ impl Stream {
         Ok(Self::Rustls(stream))
     }
 
-    pub fn new_rustls_server(stream: TcpStream, cert_path: String, key_path: String) -> Result<Self> {
-        let stream = RustlsServerStream::new(stream, cert_path, key_path)?;
+    pub fn new_rustls_server(stream: TcpStream, config: Arc<rustls::ServerConfig>) -> Result<Self> {
+        let stream = RustlsServerStream::new(stream, config)?;
         Ok(Self::RustlsServer(stream))
     }
 
+    /// `count` streams on one QUIC connection; the client's `-quic-streams` mode.
+    #[cfg(feature = "quic")]
+    pub fn new_quic_streams(addr: SocketAddr, count: usize) -> Result<Vec<Self>> {
+        let streams = crate::stream::quic::connect(addr, count)?;
+        Ok(streams.into_iter().map(Self::Quic).collect())
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn new_quic(addr: SocketAddr) -> Result<Self> {
+        let mut streams = Self::new_quic_streams(addr, 1)?;
+        Ok(streams.remove(0))
+    }
+
+    #[cfg(not(feature = "quic"))]
+    pub fn new_quic(_addr: SocketAddr) -> Result<Self> {
+        Err(anyhow::anyhow!("QUIC is not available: nettest was built without the quic feature"))
+    }
+
+    #[cfg(not(feature = "quic"))]
+    pub fn new_quic_streams(addr: SocketAddr, _count: usize) -> Result<Vec<Self>> {
+        Ok(vec![Self::new_quic(addr)?])
+    }
+
     pub fn close(&mut self) -> Result<()> {
         match self {
             Stream::Tcp(_) => Ok(()),
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => stream.close(),
             Stream::WebSocket(stream) => stream.close(),
             Stream::Rustls(_) => Ok(()),
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => stream.close(),
             Stream::RustlsServer(_) => Ok(()),
             Stream::WebSocketRustlsServer(_) => Ok(()),
+            #[cfg(feature = "quic")]
+            Stream::Quic(_) => Ok(()),
         }
     }
 
     pub fn get_greeting(&mut self) -> Vec<u8> {
         match self {
             Stream::Tcp(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
             Stream::WebSocket(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
             Stream::Rustls(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
             Stream::RustlsServer(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
             Stream::WebSocketRustlsServer(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(_) => RMBT_UPGRADE_REQUEST.as_bytes().to_vec(),
         }
     }
 
+    #[cfg(feature = "openssl")]
     pub fn new_openssl(addr: SocketAddr) -> Result<Self> {
         let stream1 = TcpStream::connect(addr)?;
         stream1.set_nodelay(true)?;
@@ -118,6 +171,7 @@ impl Stream {
     }
     
 
+    #[cfg(feature = "openssl")]
     pub fn new_websocket_tls(addr: SocketAddr) -> Result<Self> {
         let stream1 = TcpStream::connect(addr)?;
         stream1.set_nodelay(true)?;
@@ -125,27 +179,41 @@ impl Stream {
         Ok(Self::WebSocketTls(stream))
     }
 
+    // WebSocket поверх TLS у клиента есть только на OpenSSL
+    #[cfg(not(feature = "openssl"))]
+    pub fn new_websocket_tls(_addr: SocketAddr) -> Result<Self> {
+        Err(anyhow::anyhow!("WebSocket over TLS is not available: nettest was built without the openssl feature"))
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => stream.read(buf),
             Stream::WebSocket(stream) => stream.read(buf),
             Stream::Rustls(stream) => stream.read(buf),
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => stream.read(buf),
             Stream::RustlsServer(stream) => stream.read(buf),
             Stream::WebSocketRustlsServer(stream) => stream.read(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(stream) => stream.read(buf),
         }
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
             Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => stream.write(buf),
             Stream::WebSocket(stream) => stream.write(buf),
             Stream::Rustls(stream) => stream.write(buf),
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => stream.write(buf),
             Stream::RustlsServer(stream) => stream.write(buf),
             Stream::WebSocketRustlsServer(stream) => stream.write(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(stream) => stream.write(buf),
         }
     }
 
@@ -154,6 +222,7 @@ impl Stream {
             Stream::Tcp(stream) => {
                 poll.registry().register(stream, token, interest)?;
             }
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => {
                 stream.register(poll, token, interest)?;
             }
@@ -163,6 +232,7 @@ impl Stream {
             Stream::Rustls(stream) => {
                 stream.register(poll, token, interest)?;
             }
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => {
                 stream.register(poll, token, interest)?;
             }
@@ -172,6 +242,10 @@ impl Stream {
             Stream::WebSocketRustlsServer(stream) => {
                 stream.register(poll, token, interest)?;
             }
+            #[cfg(feature = "quic")]
+            Stream::Quic(stream) => {
+                stream.register(poll, token, interest)?;
+            }
         }
         Ok(())
     }
@@ -179,12 +253,16 @@ impl Stream {
     pub fn flush(&mut self) -> io::Result<()> {
         match self {
             Stream::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => stream.flush(),
             Stream::WebSocket(stream) => stream.flush(),
             Stream::Rustls(stream) => stream.flush(),
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => stream.flush(),
             Stream::RustlsServer(stream) => stream.flush(),
             Stream::WebSocketRustlsServer(stream) => stream.flush(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(stream) => stream.flush(),
         }
     }
 
@@ -194,6 +272,7 @@ impl Stream {
                 poll.registry().reregister(stream, token, interest)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
             }
+            #[cfg(feature = "openssl")]
             Stream::OpenSsl(stream) => {
                 stream.reregister(poll, token, interest)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
@@ -206,6 +285,7 @@ impl Stream {
                 stream.reregister(poll, token, interest)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
             }
+            #[cfg(feature = "openssl")]
             Stream::WebSocketTls(stream) => {
                 stream.reregister(poll, token, interest)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
@@ -218,6 +298,8 @@ impl Stream {
                 stream.reregister(poll, token, interest)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
             }
+            #[cfg(feature = "quic")]
+            Stream::Quic(stream) => stream.reregister(poll, token, interest),
         }
     }
 }
@@ -27,8 +27,8 @@ impl WebSocketClient {
     }
 
     pub fn finish_server_handshake(&mut self, handshake: Handshake) -> Result<()> {
-        let response = generate_handshake_response(&handshake).unwrap();
-        self.write_all(response.as_bytes()).unwrap();
+        let response = generate_handshake_response(&handshake).map_err(|e| anyhow::anyhow!(e))?;
+        self.write_all(response.as_bytes())?;
         Ok(())
     }
 
@@ -277,9 +277,9 @@ impl Write for WebSocketClient {
         if self.flushed {
             let message = if buf.len() < 2 || buf.len() > (CHUNK_SIZE - 3) {
                 debug!("Writing binary {} bytes", buf.len());
-                tokio_tungstenite::tungstenite::Message::Binary(buf.to_vec())
+                tungstenite::Message::Binary(buf.to_vec())
             } else {
-                tokio_tungstenite::tungstenite::Message::Text(
+                tungstenite::Message::Text(
                     String::from_utf8_lossy(buf).to_string(),
                 )
             };
@@ -34,7 +34,7 @@ impl WebSocketRustlsServerStream {
     }
 
     pub fn finish_server_handshake(&mut self, handshake: Handshake) -> Result<()> {
-        let response = generate_handshake_response(&handshake).unwrap();
+        let response = generate_handshake_response(&handshake).map_err(|e| anyhow::anyhow!(e))?;
         // Write handshake response directly to the underlying stream
         let stream = self.ws.get_mut();
         stream.write_all(response.as_bytes())?;
@@ -109,9 +109,9 @@ impl Write for WebSocketRustlsServerStream {
         if self.flushed {
             let message = if buf.len() < 2 || buf.len() > (CHUNK_SIZE - 3) {
                 debug!("Writing binary {} bytes", buf.len());
-                tokio_tungstenite::tungstenite::Message::Binary(buf.to_vec())
+                tungstenite::Message::Binary(buf.to_vec())
             } else {
-                tokio_tungstenite::tungstenite::Message::Text(
+                tungstenite::Message::Text(
                     String::from_utf8_lossy(buf).to_string(),
                 )
             };
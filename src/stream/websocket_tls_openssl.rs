@@ -374,9 +374,9 @@ impl Write for WebSocketTlsClient {
         if self.flushed {
             let message = if buf.len() < 2 || buf.len() > (CHUNK_SIZE - 3) {
                 debug!("Writing binary {} bytes", buf.len());
-                tokio_tungstenite::tungstenite::Message::Binary(buf.to_vec())
+                tungstenite::Message::Binary(buf.to_vec())
             } else {
-                tokio_tungstenite::tungstenite::Message::Text(
+                tungstenite::Message::Text(
                     String::from_utf8_lossy(buf).to_string(),
                 )
             };
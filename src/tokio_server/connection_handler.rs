@@ -1,4 +1,4 @@
-use crate::config::constants::{ACCEPT_COMMANDS, CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, RESP_ERR};
+use crate::config::constants::{ACCEPT_COMMANDS_UNSIGNED, CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, RESP_ERR};
 use crate::tokio_server::handlers::{
     handle_get_chunks, handle_get_time, handle_ping, handle_put, handle_put_no_result, handle_quit,
 };
@@ -49,7 +49,7 @@ impl ConnectionHandler {
 
         // Main command loop
         loop {
-            self.stream.write_all(ACCEPT_COMMANDS.as_bytes()).await?;
+            self.stream.write_all(ACCEPT_COMMANDS_UNSIGNED.as_bytes()).await?;
             debug!("Accept commands message sent");
 
             let mut buffer = [0u8; 1024];
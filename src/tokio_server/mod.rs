@@ -1,6 +1,11 @@
+#[cfg(feature = "legacy-tokio-server")]
 pub mod server;
+#[cfg(feature = "legacy-tokio-server")]
 pub mod server_config;
-pub mod connection_handler; 
+#[cfg(feature = "legacy-tokio-server")]
+pub mod connection_handler;
+#[cfg(feature = "legacy-tokio-server")]
 pub mod handlers;
+#[cfg(feature = "legacy-tokio-server")]
 pub mod stream;
 pub mod utils;
\ No newline at end of file
@@ -1,5 +1,6 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::process;
 use nix::unistd::{fork, ForkResult, setsid, dup2};
 use std::env;
@@ -39,9 +40,8 @@ pub fn daemonize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             dup2(null_fd, 1)?;
             dup2(null_fd, 2)?;
 
-            // Закрываем лишние дескрипторы
+            // Закрываем лишние дескрипторы; null_fd закроет drop(devnull)
             unsafe {
-                libc::close(null_fd);
                 libc::close(stdin_fd);
                 libc::close(stdout_fd);
                 libc::close(stderr_fd);
@@ -51,4 +51,16 @@ pub fn daemonize() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
         Err(e) => Err(format!("Failed to fork: {}", e).into()),
     }
-} 
\ No newline at end of file
+}
+
+pub const DEFAULT_PID_FILE: &str = "/run/nettest.pid";
+
+/// Записывает PID текущего процесса; вызывается уже после fork.
+pub fn write_pid_file(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", process::id()))?;
+    info!("PID file location: {}", path.display());
+    Ok(())
+}
@@ -1,7 +1,14 @@
+// Общие с mio сервером; клиент подписывает им свой токен
+#[cfg(any(feature = "client", feature = "server", feature = "legacy-tokio-server"))]
 pub mod token_validator;
+#[cfg(any(feature = "server", feature = "legacy-tokio-server"))]
 pub mod daemon;
+#[cfg(any(feature = "server", feature = "legacy-tokio-server"))]
 pub mod secret_keys;
-pub mod use_http;
+#[cfg(any(feature = "server", feature = "legacy-tokio-server"))]
+pub mod user;
 pub mod websocket;
+#[cfg(feature = "legacy-tokio-server")]
+pub mod use_http;
+#[cfg(feature = "legacy-tokio-server")]
 pub mod random_buffer;
-pub mod user;
@@ -1,16 +1,31 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::time::sleep;
-use log::{debug};
+use log::{debug, info};
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use uuid::Uuid;
+
+use crate::config::constants::{MAX_ACCEPT_EARLY, MAX_ACCEPT_LATE, MAX_TOKEN_REUSE};
 
 type HmacSha1 = Hmac<Sha1>;
 
+/// Key removed from the keys file, still accepted until `until`.
+#[derive(Clone)]
+struct RetiredKey {
+    key: String,
+    label: String,
+    until: Instant,
+}
+
 pub struct TokenValidator {
     secret_keys: Vec<String>,
     secret_keys_labels: Vec<String>,
+    retired_keys: Vec<RetiredKey>,
+    used_tokens: Mutex<HashMap<String, Instant>>,
 }
 
 impl TokenValidator {
@@ -19,6 +34,40 @@ impl TokenValidator {
         Self {
             secret_keys,
             secret_keys_labels,
+            retired_keys: Vec::new(),
+            used_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Validator with the keys of `next` that replaces this one on reload.
+    /// Keys missing from `next` stay valid for `grace`, so tokens already
+    /// handed out with them still work; used tokens are carried over too.
+    pub fn rotate(&self, next: &TokenValidator, grace: Duration) -> Self {
+        let now = Instant::now();
+        let mut retired_keys: Vec<RetiredKey> = self
+            .retired_keys
+            .iter()
+            .filter(|retired| retired.until > now && !next.secret_keys.contains(&retired.key))
+            .cloned()
+            .collect();
+        if !grace.is_zero() {
+            for (key, label) in self.secret_keys.iter().zip(&self.secret_keys_labels) {
+                if !next.secret_keys.contains(key) {
+                    info!("Key {} was removed, accepting its tokens for {} more seconds", label, grace.as_secs());
+                    retired_keys.push(RetiredKey {
+                        key: key.clone(),
+                        label: label.clone(),
+                        until: now + grace,
+                    });
+                }
+            }
+        }
+
+        Self {
+            secret_keys: next.secret_keys.clone(),
+            secret_keys_labels: next.secret_keys_labels.clone(),
+            retired_keys,
+            used_tokens: Mutex::new(self.used_tokens.lock().unwrap().clone()),
         }
     }
 
@@ -35,41 +84,87 @@ impl TokenValidator {
         Ok(false)
     }
 
-    async fn validate_with_key(&self, token_uuid: &str, start_time_str: &str, _hmac: &str, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        // if Uuid::parse_str(token_uuid).is_err() {
-        //     // error!("Invalid UUID format: \"{}\"", token_uuid);
-        //     return Ok(false);
-        // }
+    /// Synchronous check for the mio workers: returns the label of the key that
+    /// accepted the token. Early tokens are not delayed here.
+    pub fn validate_now(&self, token_uuid: &str, start_time_str: &str, hmac: &str) -> Result<Option<&str>, Box<dyn Error + Send + Sync>> {
+        for (i, key) in self.secret_keys.iter().enumerate() {
+            if self.check_with_key(token_uuid, start_time_str, hmac, key)? {
+                debug!("Token was accepted by key {}", self.secret_keys_labels[i]);
+                return Ok(Some(&self.secret_keys_labels[i]));
+            }
+        }
+        for retired in &self.retired_keys {
+            if retired.until > Instant::now() && self.check_with_key(token_uuid, start_time_str, hmac, &retired.key)? {
+                debug!("Token was accepted by retired key {}", retired.label);
+                return Ok(Some(&retired.label));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Records a use of the token uuid and returns false for a replay.
+    /// All connections of one test share the token, so it stays usable for
+    /// MAX_TOKEN_REUSE seconds after its first use.
+    pub fn register_use(&self, token_uuid: &str) -> bool {
+        let mut used_tokens = self.used_tokens.lock().unwrap();
+        let expiry = Duration::from_secs((MAX_ACCEPT_EARLY + MAX_ACCEPT_LATE) as u64);
+        used_tokens.retain(|_, first_use| first_use.elapsed() < expiry);
+
+        let first_use = used_tokens.entry(token_uuid.to_string()).or_insert_with(Instant::now);
+        first_use.elapsed() <= Duration::from_secs(MAX_TOKEN_REUSE as u64)
+    }
+
+    async fn validate_with_key(&self, token_uuid: &str, start_time_str: &str, hmac: &str, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if !self.check_with_key(token_uuid, start_time_str, hmac, key)? {
+            return Ok(false);
+        }
 
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)?
             .as_secs() as i64;
-        
         let start_time = start_time_str.parse::<i64>()?;
 
-        // if start_time - (MAX_ACCEPT_EARLY as i64) > now {
-        //     // error!("Client is not allowed yet. {} seconds too early", start_time - now);
-        //     return Ok(false);
-        // }
-        // if start_time + (MAX_ACCEPT_LATE as i64) < now {
-        //     // error!("Client is {} seconds too late", now - start_time);
-        //     return Ok(false);
-        // }
-
         if start_time > now {
             let wait_time = start_time - now;
             debug!("Client is {} seconds too early. Let him wait", wait_time);
             sleep(std::time::Duration::from_secs(wait_time as u64)).await;
         }
 
+        Ok(true)
+    }
+
+    fn check_with_key(&self, token_uuid: &str, start_time_str: &str, hmac: &str, key: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        if Uuid::parse_str(token_uuid).is_err() {
+            debug!("Invalid UUID format: \"{}\"", token_uuid);
+            return Ok(false);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs() as i64;
+        
+        let start_time = start_time_str.parse::<i64>()?;
+
+        if start_time - (MAX_ACCEPT_EARLY as i64) > now {
+            debug!("Client is not allowed yet. {} seconds too early", start_time - now);
+            return Ok(false);
+        }
+        if start_time + (MAX_ACCEPT_LATE as i64) < now {
+            debug!("Client is {} seconds too late", now - start_time);
+            return Ok(false);
+        }
+
+        let expected = match BASE64.decode(hmac) {
+            Ok(bytes) => bytes,
+            Err(_) => return Ok(false),
+        };
+
         let message = format!("{}_{}", token_uuid, start_time_str);
         let mut mac = HmacSha1::new_from_slice(key.as_bytes())?;
         mac.update(message.as_bytes());
-        let result = mac.finalize();
-        let code_bytes = result.into_bytes();
-        let _computed_hmac = BASE64.encode(code_bytes);
 
-        Ok(true)
+        Ok(mac.verify_slice(&expected).is_ok())
     }
 
     pub fn generate_hmac(token_uuid: &str, start_time_str: &str, key: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
@@ -217,4 +312,70 @@ mod tests {
         assert!(result.is_ok());
         assert!(!result.unwrap());
     }
+
+    #[test]
+    fn test_validate_now_returns_label() {
+        let validator = create_test_validator_with_two_keys();
+        let uuid = Uuid::new_v4().to_string();
+        let start_time = (SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64)
+            .to_string();
+
+        let hmac = TokenValidator::generate_hmac(&uuid, &start_time, TEST_KEY_2)
+            .expect("Failed to generate HMAC");
+        assert_eq!(validator.validate_now(&uuid, &start_time, &hmac).unwrap(), Some(TEST_LABEL_2));
+
+        let hmac = TokenValidator::generate_hmac(&uuid, &start_time, "unknown_key")
+            .expect("Failed to generate HMAC");
+        assert_eq!(validator.validate_now(&uuid, &start_time, &hmac).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rotate_keeps_removed_key_for_grace_period() {
+        let validator = create_test_validator_with_two_keys();
+        let next = TokenValidator::new(vec![TEST_KEY_2.to_string()], vec![TEST_LABEL_2.to_string()]);
+        let uuid = Uuid::new_v4().to_string();
+        let start_time = (SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64)
+            .to_string();
+        let hmac = TokenValidator::generate_hmac(&uuid, &start_time, TEST_KEY_1)
+            .expect("Failed to generate HMAC");
+        assert!(validator.register_use(&uuid));
+
+        let rotated = validator.rotate(&next, Duration::from_secs(60));
+        assert_eq!(rotated.validate_now(&uuid, &start_time, &hmac).unwrap(), Some(TEST_LABEL_1));
+        assert!(rotated.used_tokens.lock().unwrap().contains_key(&uuid));
+
+        // Повторная перезагрузка не продлевает и не теряет старый ключ
+        let rotated_again = rotated.rotate(&next, Duration::from_secs(60));
+        assert_eq!(rotated_again.retired_keys.len(), 1);
+
+        let without_grace = validator.rotate(&next, Duration::ZERO);
+        assert_eq!(without_grace.validate_now(&uuid, &start_time, &hmac).unwrap(), None);
+    }
+
+    #[test]
+    fn test_register_use_allows_parallel_connections() {
+        let validator = create_test_validator();
+        let uuid = Uuid::new_v4().to_string();
+
+        assert!(validator.register_use(&uuid));
+        assert!(validator.register_use(&uuid));
+    }
+
+    #[test]
+    fn test_register_use_rejects_replay() {
+        let validator = create_test_validator();
+        let uuid = Uuid::new_v4().to_string();
+        validator.used_tokens.lock().unwrap().insert(
+            uuid.clone(),
+            Instant::now() - Duration::from_secs(MAX_TOKEN_REUSE as u64 + 1),
+        );
+
+        assert!(!validator.register_use(&uuid));
+    }
 }
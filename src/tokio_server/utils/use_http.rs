@@ -3,13 +3,13 @@ use crate::tokio_server::stream::Stream::{Plain, Tls};
 use crate::tokio_server::utils::websocket::{generate_handshake_response, Handshake};
 use log::{debug, info};
 use regex::Regex;
+use crate::config::constants::RMBT_UPGRADE;
 use std::error::Error;
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsAcceptor;
 
 pub const MAX_LINE_LENGTH: usize = 1024;
-pub const RMBT_UPGRADE: &str = "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: RMBT\r\n\r\n";
 
 
 pub async fn define_stream(
@@ -1,5 +1,7 @@
 use std::ffi::CString;
 use std::io;
+use std::os::unix::fs::chown;
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct UserPrivileges {
@@ -28,8 +30,21 @@ impl UserPrivileges {
         }
     }
 
+    /// Gives the user a file it has to reopen after the drop, e.g. the log file.
+    pub fn chown(&self, path: &Path) -> io::Result<()> {
+        chown(path, Some(self.uid), Some(self.gid))
+    }
+
     pub fn drop_privileges(&self) -> io::Result<()> {
         unsafe {
+            // Дополнительные группы root иначе остаются у процесса
+            if libc::setgroups(1, &self.gid) != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Error: failed to set supplementary groups"
+                ));
+            }
+
             if libc::setgid(self.gid) != 0 {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,